@@ -0,0 +1,427 @@
+//! Local HTTP and WebSocket API, for stream overlays and other tools on the same machine.
+//!
+//! * `GET /state`: the [`ControllerState`] as JSON
+//! * `POST /leds`: sets LEDs, e.g.
+//!   `{"buttons": {"Button1": "blink"}, "knobs": {"Knob1": {"value": 6, "style": "fan"}}}`
+//! * `GET /events`: WebSocket that sends each [`Event`] as JSON
+//! * `GET /metrics`: latency and throughput [`metrics`] in the Prometheus text format
+//!
+//! With a token, requests need an `Authorization: Bearer <token>` header, or a `token` query
+//! parameter, since browsers can't set headers on WebSockets.
+
+use crate::metrics;
+use crate::model::{Button, ButtonLedState, ControllerState, Event, Knob, KnobLedStyle};
+use crate::model::{KnobLedValue, KnobState};
+use crate::output::Command;
+use crate::router::{HasController, Middleware, Next};
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{Future, SinkExt, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, warn};
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+// Events are dropped for WebSocket clients that fall this far behind
+const EVENT_BUFFER: usize = 256;
+
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Body of `POST /leds`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct LedUpdate {
+    #[serde(default)]
+    pub buttons: HashMap<Button, ButtonLedState>,
+    #[serde(default)]
+    pub knobs: HashMap<Knob, KnobLed>,
+}
+
+/// Knob values go from 0 to 12.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct KnobLed {
+    pub value: u8,
+    #[serde(default = "default_style")]
+    pub style: KnobLedStyle,
+}
+
+fn default_style() -> KnobLedStyle {
+    KnobLedStyle::Fan
+}
+
+impl LedUpdate {
+    pub fn to_commands(&self) -> impl Iterator<Item = Command> + '_ {
+        let buttons = self
+            .buttons
+            .iter()
+            .map(|(&button, &state)| Command::SetButtonLedState { button, state });
+
+        let knobs = self
+            .knobs
+            .iter()
+            .map(|(&knob, led)| Command::SetKnobLedState {
+                knob,
+                state: KnobState {
+                    style: led.style,
+                    led_value: KnobLedValue::new(led.value),
+                    ..Default::default()
+                },
+            });
+
+        buttons.chain(knobs)
+    }
+}
+
+/// Serves the API on localhost.
+#[derive(Clone, Debug)]
+pub struct Server {
+    port: u16,
+    token: Option<String>,
+}
+
+impl Server {
+    /// Port 0 picks any free port.
+    pub fn new(port: u16) -> Self {
+        Self { port, token: None }
+    }
+
+    /// Requires clients to send this token.
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Binds the server, returning a handle to keep it updated, LED commands from
+    /// `POST /leds` (to be applied with [`Controller::apply`](crate::Controller::apply)), and
+    /// the server itself, which needs to be spawned.
+    pub fn bind(
+        self,
+        state: ControllerState,
+    ) -> Result<(Api, BoxStream<'static, Command>, impl Future<Output = ()>)> {
+        let (events_tx, _) = broadcast::channel(EVENT_BUFFER);
+        let (state_tx, state_rx) = watch::channel(state);
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+
+        let routes = routes(
+            self.token.as_deref().map(Arc::from),
+            state_rx.clone(),
+            events_tx.clone(),
+            commands_tx,
+        );
+
+        let (addr, server) = warp::serve(routes)
+            .try_bind_ephemeral((Ipv4Addr::LOCALHOST, self.port))
+            .with_context(|| format!("failed to bind API server to port {}", self.port))?;
+
+        let api = Api {
+            addr,
+            events: events_tx,
+            state: Arc::new(state_tx),
+            state_rx,
+        };
+
+        Ok((api, commands_rx.boxed(), server))
+    }
+}
+
+/// Keeps the server's state and event stream up to date. As [`Middleware`], it publishes
+/// every handled event.
+#[derive(Clone, Debug)]
+pub struct Api {
+    addr: SocketAddr,
+    events: broadcast::Sender<Event>,
+    state: Arc<watch::Sender<ControllerState>>,
+    state_rx: watch::Receiver<ControllerState>,
+}
+
+impl Api {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn publish(&self, event: &Event) {
+        // Fails if no WebSockets are connected, which is fine
+        let _ = self.events.send(event.clone());
+    }
+
+    /// Updates the state served by `GET /state`, if it has changed.
+    pub fn sync(&self, state: &ControllerState) {
+        if *self.state_rx.borrow() != *state {
+            let _ = self.state.send(state.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Api {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let result = next.run(context, event).await;
+
+        self.publish(event);
+        self.sync(context.controller().state());
+        result
+    }
+}
+
+/// Every endpoint, behind the token check when there is one.
+fn routes(
+    token: Option<Arc<str>>,
+    state_rx: watch::Receiver<ControllerState>,
+    events_tx: broadcast::Sender<Event>,
+    commands_tx: mpsc::UnboundedSender<Command>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let get_state = warp::path!("state")
+        .and(warp::get())
+        .map(move || warp::reply::json(&*state_rx.borrow()));
+
+    let post_leds = warp::path!("leds")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .map(move |update: LedUpdate| {
+            for command in update.to_commands() {
+                if commands_tx.unbounded_send(command).is_err() {
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+            }
+
+            StatusCode::NO_CONTENT
+        });
+
+    let get_metrics = warp::path!("metrics").and(warp::get()).map(|| {
+        warp::reply::with_header(
+            metrics::global().render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    });
+
+    let events = warp::path!("events").and(warp::ws()).map(move |ws: Ws| {
+        let events = events_tx.subscribe();
+        ws.on_upgrade(move |socket| send_events(socket, events))
+    });
+
+    authorize(token)
+        .and(get_state.or(post_leds).or(get_metrics).or(events))
+        .recover(recover)
+}
+
+async fn send_events(socket: WebSocket, mut events: broadcast::Receiver<Event>) {
+    let (mut sink, mut incoming) = socket.split();
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // Incoming messages are ignored, except to notice when the client goes away
+            message = incoming.next() => match message {
+                Some(Ok(_)) => continue,
+                _ => break,
+            },
+        };
+
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(skipped, "WebSocket client fell behind, skipping events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let json = match serde_json::to_string(&event) {
+            Ok(json) => json,
+            Err(error) => {
+                warn!(?error, "Failed to serialize event");
+                continue;
+            }
+        };
+
+        if let Err(error) = sink.send(Message::text(json)).await {
+            debug!(?error, "WebSocket client disconnected");
+            break;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+fn authorize(token: Option<Arc<str>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let query = warp::query::<HashMap<String, String>>()
+        .or(warp::any().map(HashMap::new))
+        .unify();
+
+    warp::header::optional::<String>("authorization")
+        .and(query)
+        .and_then(
+            move |header: Option<String>, query: HashMap<String, String>| {
+                let token = token.clone();
+
+                async move {
+                    let expected = match token {
+                        Some(token) => token,
+                        None => return Ok(()),
+                    };
+
+                    let provided = header
+                        .as_deref()
+                        .and_then(|header| header.strip_prefix("Bearer "))
+                        .or_else(|| query.get("token").map(String::as_str));
+
+                    if provided == Some(&*expected) {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+async fn recover(rejection: Rejection) -> std::result::Result<impl Reply, Rejection> {
+    let (status, message) = if rejection.find::<Unauthorized>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid token".to_owned(),
+        )
+    } else if let Some(error) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, error.to_string())
+    } else {
+        return Err(rejection);
+    };
+
+    let body = warp::reply::json(&serde_json::json!({ "error": message }));
+    Ok(warp::reply::with_status(body, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::UnboundedReceiver;
+
+    fn test_routes(
+        token: Option<&str>,
+    ) -> (
+        impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone,
+        UnboundedReceiver<Command>,
+    ) {
+        let (events_tx, _) = broadcast::channel(EVENT_BUFFER);
+        let (_, state_rx) = watch::channel(ControllerState::default());
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+
+        let routes = routes(token.map(Arc::from), state_rx, events_tx, commands_tx);
+        (routes, commands_rx)
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let (routes, _) = test_routes(Some("secret"));
+
+        let missing = warp::test::request().path("/state").reply(&routes).await;
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong_header = warp::test::request()
+            .path("/state")
+            .header("authorization", "Bearer wrong")
+            .reply(&routes)
+            .await;
+        assert_eq!(wrong_header.status(), StatusCode::UNAUTHORIZED);
+
+        let unprefixed_header = warp::test::request()
+            .path("/state")
+            .header("authorization", "secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(unprefixed_header.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong_query = warp::test::request()
+            .path("/state?token=wrong")
+            .reply(&routes)
+            .await;
+        assert_eq!(wrong_query.status(), StatusCode::UNAUTHORIZED);
+
+        let header = warp::test::request()
+            .path("/state")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(header.status(), StatusCode::OK);
+
+        let query = warp::test::request()
+            .path("/state?token=secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(query.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn allows_any_request_without_a_token() {
+        let (routes, _) = test_routes(None);
+
+        let response = warp::test::request().path("/state").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_led_updates() {
+        let (routes, mut commands) = test_routes(None);
+
+        let bodies = [
+            "not json",
+            r#"{"buttons": {"Button99": "on"}}"#,
+            r#"{"buttons": {"Button1": "sparkle"}}"#,
+            r#"{"knobs": {"Knob1": {"style": "fan"}}}"#,
+        ];
+
+        for body in bodies.iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/leds")
+                .body(*body)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        assert!(commands.try_recv().is_err(), "no commands should be sent");
+    }
+
+    #[tokio::test]
+    async fn sends_led_commands() {
+        let (routes, mut commands) = test_routes(Some("secret"));
+
+        let body = r#"{"buttons": {"Button1": "blink"}, "knobs": {"Knob1": {"value": 6}}}"#;
+        let response = warp::test::request()
+            .method("POST")
+            .path("/leds?token=secret")
+            .body(body)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let button = Command::SetButtonLedState {
+            button: Button::Button1,
+            state: ButtonLedState::Blink,
+        };
+        let knob = Command::SetKnobLedState {
+            knob: Knob::Knob1,
+            state: KnobState {
+                style: KnobLedStyle::Fan,
+                led_value: KnobLedValue::new(6),
+                ..Default::default()
+            },
+        };
+
+        assert_eq!(commands.next().await, Some(button));
+        assert_eq!(commands.next().await, Some(knob));
+    }
+}
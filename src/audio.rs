@@ -0,0 +1,652 @@
+//! Audio mixer on the knobs and fader, for PulseAudio and PipeWire (through `pipewire-pulse`).
+//!
+//! Talks to the sound server with `pactl`, which needs to support `--format=json` (PulseAudio
+//! 16 or later, or any PipeWire release that ships it). Knob rings show volume and button
+//! LEDs light up while muted, updated from [`Mixer::changes`] so changes made elsewhere are
+//! shown too.
+
+use crate::model::{Button, ButtonLedState, Event, Knob, KnobLedStyle, KnobLedValue};
+use crate::output::Controller;
+use crate::status::{Source, WithSource};
+use anyhow::{anyhow, bail, Context as _, Result};
+use futures::channel::mpsc;
+use futures::stream::{BoxStream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::{debug, error};
+
+const DEFAULT_STEP: f64 = 2.0;
+const DEFAULT_MAX_VOLUME: f64 = 100.0;
+
+/// Whose volume a channel controls.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// A sink (output device) by name, e.g. `alsa_output.pci-0000_00_1f.3.analog-stereo`
+    Sink(String),
+    /// Whichever sink is currently the default
+    DefaultSink,
+    /// Every stream from an application, matched case-insensitively against its
+    /// `application.name` or `application.process.binary`
+    Application(String),
+}
+
+impl Target {
+    pub fn sink<S: Into<String>>(name: S) -> Self {
+        Self::Sink(name.into())
+    }
+
+    pub fn application<S: Into<String>>(name: S) -> Self {
+        Self::Application(name.into())
+    }
+}
+
+/// A target and the controls bound to it. Pressing the knob or the button toggles mute.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channel {
+    target: Target,
+    knob: Option<Knob>,
+    button: Option<Button>,
+    fader: bool,
+}
+
+impl Channel {
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            knob: None,
+            button: None,
+            fader: false,
+        }
+    }
+
+    /// Turning the knob changes the volume, which is shown on its ring.
+    pub fn knob(mut self, knob: Knob) -> Self {
+        self.knob = Some(knob);
+        self
+    }
+
+    /// The button's LED is lit while muted.
+    pub fn button(mut self, button: Button) -> Self {
+        self.button = Some(button);
+        self
+    }
+
+    /// The fader sets the volume directly.
+    pub fn fader(mut self) -> Self {
+        self.fader = true;
+        self
+    }
+
+    fn binds(&self, event: &Event) -> bool {
+        match *event {
+            Event::KnobTurned { knob, .. } | Event::KnobPressed { knob, .. } => {
+                self.knob == Some(knob)
+            }
+            Event::ButtonPressed { button, .. } => self.button == Some(button),
+            Event::FaderMoved { .. } => self.fader,
+        }
+    }
+
+    fn controls_volume(&self, event: &Event) -> bool {
+        match *event {
+            Event::KnobTurned { knob, .. } => self.knob == Some(knob),
+            Event::FaderMoved { .. } => self.fader,
+            _ => false,
+        }
+    }
+
+    fn toggles_mute(&self, event: &Event) -> bool {
+        match *event {
+            Event::KnobPressed { knob, is_down } => is_down && self.knob == Some(knob),
+            Event::ButtonPressed { button, is_down } => is_down && self.button == Some(button),
+            _ => false,
+        }
+    }
+}
+
+/// The state of a channel's target, as last seen.
+#[derive(Clone, Debug, PartialEq)]
+struct Level {
+    /// Sink names or sink input indexes, for `pactl`
+    ids: Vec<String>,
+    /// Percent, averaged over the audio channels
+    volume: f64,
+    muted: bool,
+}
+
+/// Volume and mute controls for a set of channels.
+#[derive(Debug)]
+pub struct Mixer {
+    channels: Vec<Channel>,
+    step: f64,
+    max_volume: f64,
+    levels: Mutex<HashMap<usize, Level>>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self {
+            channels: Vec::new(),
+            step: DEFAULT_STEP,
+            max_volume: DEFAULT_MAX_VOLUME,
+            levels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channels.push(channel);
+        self
+    }
+
+    /// Volume change per knob step, in percent (2 by default).
+    pub fn step(mut self, percent: f64) -> Self {
+        self.step = percent;
+        self
+    }
+
+    /// Highest volume the controls go up to, in percent (100 by default). A full knob ring
+    /// means this volume.
+    pub fn max_volume(mut self, percent: f64) -> Self {
+        self.max_volume = percent;
+        self
+    }
+
+    /// Whether the event comes from a control bound to a channel, so callers can leave other
+    /// controls to their usual actions.
+    pub fn handles(&self, event: &Event) -> bool {
+        self.channels.iter().any(|channel| channel.binds(event))
+    }
+
+    /// Changes volume or toggles mute, for events from the channels' controls. Other events
+    /// are ignored.
+    pub async fn handle(&self, controller: &mut Controller, event: &Event) -> Result<()> {
+        for (i, channel) in self.channels.iter().enumerate() {
+            let result = if channel.controls_volume(event) {
+                self.set_volume(controller, i, event).await
+            } else if channel.toggles_mute(event) {
+                self.toggle_mute(controller, i).await
+            } else {
+                continue;
+            };
+
+            result.with_source(Source::Audio)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queries every channel's volume and mute state, and updates the LEDs. Call this once
+    /// at startup, and whenever [`Mixer::changes`] yields.
+    pub async fn sync(&self, controller: &mut Controller) -> Result<()> {
+        let server = ServerState::query().await?;
+
+        for (i, channel) in self.channels.iter().enumerate() {
+            match server.level(&channel.target) {
+                Some(level) => {
+                    self.show(controller, channel, &level)?;
+                    self.lock_levels()?.insert(i, level);
+                }
+                // e.g. the application isn't playing anything right now
+                None => {
+                    self.lock_levels()?.remove(&i);
+                    self.show_missing(controller, channel)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Yields whenever sinks or streams change on the server, including volume changes made
+    /// elsewhere. Bursts of changes are merged into one item.
+    pub fn changes() -> Result<BoxStream<'static, ()>> {
+        let mut child = Command::new("pactl")
+            .arg("subscribe")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to run pactl subscribe")?;
+        let stdout = child.stdout.take().context("pactl has no stdout")?;
+        let (tx, rx) = mpsc::unbounded();
+
+        tokio::spawn(async move {
+            // Killed on drop, once the stream is gone and the next change comes in
+            let _child = child;
+            let mut lines = BufReader::new(stdout).lines();
+
+            loop {
+                match lines.next_line().await {
+                    // e.g. `Event 'change' on sink-input #42`
+                    Ok(Some(line)) if line.contains(" sink") || line.contains(" server") => {
+                        if tx.unbounded_send(()).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(error) => {
+                        error!(?error, "Failed to read pactl subscribe output");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(rx.ready_chunks(64).map(|_| ()).boxed())
+    }
+
+    async fn set_volume(
+        &self,
+        controller: &mut Controller,
+        index: usize,
+        event: &Event,
+    ) -> Result<()> {
+        let (step, max_volume) = (self.step, self.max_volume);
+
+        self.update(controller, index, "volume", |level| {
+            level.volume = match *event {
+                Event::KnobTurned { delta, .. } => level.volume + delta as f64 * step,
+                Event::FaderMoved { value } => value.as_percent() * max_volume,
+                _ => level.volume,
+            }
+            .clamp(0.0, max_volume);
+
+            format!("{}%", level.volume.round())
+        })
+        .await
+    }
+
+    async fn toggle_mute(&self, controller: &mut Controller, index: usize) -> Result<()> {
+        // Set explicitly rather than toggled, so every stream of an application ends up the same
+        self.update(controller, index, "mute", |level| {
+            level.muted = !level.muted;
+            if level.muted { "1" } else { "0" }.to_owned()
+        })
+        .await
+    }
+
+    /// Changes the channel's level with `change`, which returns the new value of `property`,
+    /// and applies it to the channel's sinks or streams. Cached sink input indexes go stale
+    /// as streams come and go, so if `pactl` fails, the level is queried again and the
+    /// change retried once.
+    async fn update<F>(
+        &self,
+        controller: &mut Controller,
+        index: usize,
+        property: &str,
+        change: F,
+    ) -> Result<()>
+    where
+        F: Fn(&mut Level) -> String,
+    {
+        let channel = &self.channels[index];
+        let cached = self.lock_levels()?.get(&index).cloned();
+        let is_cached = cached.is_some();
+
+        let mut level = match cached {
+            Some(level) => level,
+            None => match self.query_level(index).await? {
+                Some(level) => level,
+                None => return Ok(()),
+            },
+        };
+
+        let value = change(&mut level);
+        if let Err(error) = self.apply(channel, property, &level, &value).await {
+            if !is_cached {
+                return Err(error);
+            }
+
+            debug!(?error, "Audio level was out of date, querying it again");
+            self.lock_levels()?.remove(&index);
+            level = match self.query_level(index).await? {
+                Some(level) => level,
+                None => return self.show_missing(controller, channel),
+            };
+
+            let value = change(&mut level);
+            self.apply(channel, property, &level, &value).await?;
+        }
+
+        self.show(controller, channel, &level)?;
+        self.lock_levels()?.insert(index, level);
+        Ok(())
+    }
+
+    async fn apply(
+        &self,
+        channel: &Channel,
+        property: &str,
+        level: &Level,
+        value: &str,
+    ) -> Result<()> {
+        for id in &level.ids {
+            pactl(&[set_command(&channel.target, property), id, value]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The channel's level, queried from the server.
+    async fn query_level(&self, index: usize) -> Result<Option<Level>> {
+        let server = ServerState::query().await?;
+        Ok(server.level(&self.channels[index].target))
+    }
+
+    fn show(&self, controller: &mut Controller, channel: &Channel, level: &Level) -> Result<()> {
+        if let Some(knob) = channel.knob {
+            let percent = (level.volume / self.max_volume).min(1.0);
+            controller.set_knob(knob, KnobLedStyle::Fan, KnobLedValue::from_percent(percent))?;
+        }
+
+        if let Some(button) = channel.button {
+            let state = if level.muted {
+                ButtonLedState::On
+            } else {
+                ButtonLedState::Off
+            };
+            controller.set_button(button, state)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_missing(&self, controller: &mut Controller, channel: &Channel) -> Result<()> {
+        if let Some(knob) = channel.knob {
+            controller.set_knob(knob, KnobLedStyle::Single, KnobLedValue::MIN)?;
+        }
+
+        if let Some(button) = channel.button {
+            controller.set_button(button, ButtonLedState::Off)?;
+        }
+
+        Ok(())
+    }
+
+    fn lock_levels(&self) -> Result<std::sync::MutexGuard<'_, HashMap<usize, Level>>> {
+        self.levels
+            .lock()
+            .map_err(|_| anyhow!("mixer levels were poisoned"))
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn set_command(target: &Target, property: &str) -> &'static str {
+    match (target, property) {
+        (Target::Application(_), "volume") => "set-sink-input-volume",
+        (Target::Application(_), _) => "set-sink-input-mute",
+        (_, "volume") => "set-sink-volume",
+        (_, _) => "set-sink-mute",
+    }
+}
+
+async fn pactl(args: &[&str]) -> Result<String> {
+    let output = Command::new("pactl")
+        .arg("--format=json")
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("failed to run pactl")?;
+
+    if !output.status.success() {
+        bail!(
+            "pactl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn query<T: serde::de::DeserializeOwned>(args: &[&str]) -> Result<T> {
+    let output = pactl(args).await?;
+    serde_json::from_str(&output)
+        .with_context(|| format!("unexpected output from pactl {}", args.join(" ")))
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    default_sink_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sink {
+    name: String,
+    mute: bool,
+    volume: HashMap<String, ChannelVolume>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SinkInput {
+    index: u32,
+    mute: bool,
+    volume: HashMap<String, ChannelVolume>,
+    #[serde(default)]
+    properties: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelVolume {
+    /// e.g. `"65%"`
+    value_percent: String,
+}
+
+/// Sinks and streams, as returned by `pactl list`.
+struct ServerState {
+    default_sink: String,
+    sinks: Vec<Sink>,
+    sink_inputs: Vec<SinkInput>,
+}
+
+impl ServerState {
+    async fn query() -> Result<Self> {
+        let info: Info = query(&["info"]).await?;
+
+        Ok(Self {
+            default_sink: info.default_sink_name,
+            sinks: query(&["list", "sinks"]).await?,
+            sink_inputs: query(&["list", "sink-inputs"]).await?,
+        })
+    }
+
+    fn level(&self, target: &Target) -> Option<Level> {
+        match target {
+            Target::Sink(name) => self.sink_level(name),
+            Target::DefaultSink => self.sink_level(&self.default_sink),
+            Target::Application(name) => {
+                let inputs = self
+                    .sink_inputs
+                    .iter()
+                    .filter(|input| is_application(input, name))
+                    .collect::<Vec<_>>();
+                let first = inputs.first()?;
+
+                Some(Level {
+                    ids: inputs.iter().map(|input| input.index.to_string()).collect(),
+                    volume: average_volume(&first.volume),
+                    muted: first.mute,
+                })
+            }
+        }
+    }
+
+    fn sink_level(&self, name: &str) -> Option<Level> {
+        let sink = self.sinks.iter().find(|sink| sink.name == name)?;
+
+        Some(Level {
+            ids: vec![sink.name.clone()],
+            volume: average_volume(&sink.volume),
+            muted: sink.mute,
+        })
+    }
+}
+
+fn is_application(input: &SinkInput, name: &str) -> bool {
+    ["application.name", "application.process.binary"]
+        .iter()
+        .filter_map(|key| input.properties.get(*key)?.as_str())
+        .any(|value| value.eq_ignore_ascii_case(name))
+}
+
+fn average_volume(volume: &HashMap<String, ChannelVolume>) -> f64 {
+    let percents = volume
+        .values()
+        .filter_map(|channel| {
+            channel
+                .value_percent
+                .trim_end_matches('%')
+                .trim()
+                .parse::<f64>()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+
+    if percents.is_empty() {
+        0.0
+    } else {
+        percents.iter().sum::<f64>() / percents.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEAKERS: &str = "alsa_output.pci-0000_00_1f.3.analog-stereo";
+    const HEADPHONES: &str = "bluez_output.00_1B_66_AA_BB_CC.1";
+
+    // Captured from `pactl --format=json list sinks` and `list sink-inputs`, with Firefox
+    // playing two streams and Spotify one
+    fn server() -> ServerState {
+        ServerState {
+            default_sink: SPEAKERS.to_owned(),
+            sinks: serde_json::from_str(include_str!("../tests/fixtures/pactl-sinks.json"))
+                .unwrap(),
+            sink_inputs: serde_json::from_str(include_str!(
+                "../tests/fixtures/pactl-sink-inputs.json"
+            ))
+            .unwrap(),
+        }
+    }
+
+    fn level(ids: &[&str], volume: f64, muted: bool) -> Option<Level> {
+        Some(Level {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            volume,
+            muted,
+        })
+    }
+
+    #[test]
+    fn finds_sink_levels() {
+        let server = server();
+
+        // Averaged over the left (60%) and right (70%) channels
+        assert_eq!(
+            server.level(&Target::sink(SPEAKERS)),
+            level(&[SPEAKERS], 65.0, false)
+        );
+        assert_eq!(
+            server.level(&Target::sink(HEADPHONES)),
+            level(&[HEADPHONES], 40.0, true)
+        );
+        assert_eq!(server.level(&Target::sink("missing")), None);
+    }
+
+    #[test]
+    fn finds_the_default_sink() {
+        let mut server = server();
+        assert_eq!(
+            server.level(&Target::DefaultSink),
+            level(&[SPEAKERS], 65.0, false)
+        );
+
+        server.default_sink = HEADPHONES.to_owned();
+        assert_eq!(
+            server.level(&Target::DefaultSink),
+            level(&[HEADPHONES], 40.0, true)
+        );
+
+        server.default_sink = "unplugged".to_owned();
+        assert_eq!(server.level(&Target::DefaultSink), None);
+    }
+
+    #[test]
+    fn finds_application_streams() {
+        let server = server();
+
+        // All of an application's streams change together, showing the first one's level
+        assert_eq!(
+            server.level(&Target::application("firefox")),
+            level(&["83", "97"], 80.0, false)
+        );
+        assert_eq!(
+            server.level(&Target::application("Spotify")),
+            level(&["102"], 100.0, true)
+        );
+        assert_eq!(server.level(&Target::application("vlc")), None);
+    }
+
+    #[test]
+    fn matches_application_names_and_binaries() {
+        let server = server();
+        let firefox = &server.sink_inputs[0];
+
+        assert!(is_application(firefox, "Firefox"));
+        assert!(is_application(firefox, "FIREFOX"));
+        assert!(!is_application(firefox, "fire"));
+
+        let unnamed: SinkInput =
+            serde_json::from_str(r#"{"index": 1, "mute": false, "volume": {}}"#).unwrap();
+        assert!(!is_application(&unnamed, "firefox"));
+    }
+
+    #[test]
+    fn averages_volumes() {
+        let volume = |percents: &[&str]| {
+            percents
+                .iter()
+                .enumerate()
+                .map(|(i, percent)| {
+                    let channel = ChannelVolume {
+                        value_percent: percent.to_string(),
+                    };
+                    (i.to_string(), channel)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        assert_eq!(average_volume(&volume(&["30%", "50%"])), 40.0);
+        assert_eq!(average_volume(&volume(&["120%"])), 120.0);
+        assert_eq!(average_volume(&volume(&["25 %", "n/a"])), 25.0);
+        assert_eq!(average_volume(&volume(&[])), 0.0);
+    }
+
+    #[test]
+    fn picks_set_commands() {
+        let sink = Target::sink(SPEAKERS);
+        let application = Target::application("firefox");
+
+        assert_eq!(set_command(&sink, "volume"), "set-sink-volume");
+        assert_eq!(set_command(&sink, "mute"), "set-sink-mute");
+        assert_eq!(
+            set_command(&Target::DefaultSink, "volume"),
+            "set-sink-volume"
+        );
+        assert_eq!(set_command(&application, "volume"), "set-sink-input-volume");
+        assert_eq!(set_command(&application, "mute"), "set-sink-input-mute");
+    }
+}
@@ -0,0 +1,276 @@
+//! Backends for emulating input devices.
+
+use crate::keyboard::{Flag, Key, KeyboardBackend};
+use crate::mouse::{Axis, MouseBackend, MouseButton};
+use anyhow::{bail, Result};
+use autopilot::geometry::Point;
+use autopilot::key::{Character, Code};
+use autopilot::mouse::ScrollDirection;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex, RwLock};
+
+#[cfg(target_os = "linux")]
+mod uinput;
+
+#[cfg(target_os = "linux")]
+pub use self::uinput::Uinput;
+
+const INPUT_DELAY: u64 = 0;
+
+static BACKEND: Lazy<RwLock<Arc<dyn Backend>>> = Lazy::new(|| RwLock::new(Arc::new(Autopilot)));
+
+/// A backend for both keyboard and mouse input.
+pub trait Backend: KeyboardBackend + MouseBackend {}
+
+impl<T: KeyboardBackend + MouseBackend> Backend for T {}
+
+/// Replaces the backend used by the [`crate::keyboard`] and [`crate::mouse`] functions
+/// (autopilot by default).
+pub fn set<B: Backend + 'static>(backend: B) {
+    *BACKEND.write().unwrap() = Arc::new(backend);
+}
+
+pub fn get() -> Arc<dyn Backend> {
+    BACKEND.read().unwrap().clone()
+}
+
+/// Switches to a backend by name: `autopilot`, or `uinput` on Linux.
+pub fn select(name: &str) -> Result<()> {
+    match name {
+        "autopilot" => set(Autopilot),
+        #[cfg(target_os = "linux")]
+        "uinput" => set(Uinput::new("xtouchmini")?),
+        _ => bail!("unknown input backend {:?}", name),
+    }
+
+    Ok(())
+}
+
+/// Emulates input through autopilot (which uses X11 on Linux).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Autopilot;
+
+impl KeyboardBackend for Autopilot {
+    fn toggle(&self, key: Key, down: bool, flags: &[Flag]) -> Result<()> {
+        match key {
+            Key::Code(code) => autopilot::key::toggle(&Code(code), down, flags, INPUT_DELAY),
+            Key::Char(c) => autopilot::key::toggle(&Character(c), down, flags, INPUT_DELAY),
+        }
+
+        Ok(())
+    }
+
+    fn tap(&self, key: Key, flags: &[Flag]) -> Result<()> {
+        match key {
+            Key::Code(code) => autopilot::key::tap(&Code(code), flags, INPUT_DELAY, 0),
+            Key::Char(c) => autopilot::key::tap(&Character(c), flags, INPUT_DELAY, 0),
+        }
+
+        Ok(())
+    }
+
+    fn type_text(&self, text: &str) -> Result<()> {
+        autopilot::key::type_string(text, &[], 0., 0.);
+        Ok(())
+    }
+}
+
+impl MouseBackend for Autopilot {
+    fn scroll(&self, axis: Axis, clicks: i32) -> Result<()> {
+        if axis == Axis::Horizontal {
+            bail!("autopilot doesn't support horizontal scrolling");
+        }
+
+        let direction = if clicks > 0 {
+            ScrollDirection::Down
+        } else {
+            ScrollDirection::Up
+        };
+
+        autopilot::mouse::scroll(direction, clicks.unsigned_abs());
+        Ok(())
+    }
+
+    fn move_by(&self, dx: i32, dy: i32) -> Result<()> {
+        let location = autopilot::mouse::location();
+        let (width, height) = self.screen_size()?;
+
+        // Clamp to the screen, since autopilot refuses to move out of bounds
+        let x = (location.x + dx as f64).clamp(0.0, width - 1.0);
+        let y = (location.y + dy as f64).clamp(0.0, height - 1.0);
+        self.move_to(x, y)
+    }
+
+    fn move_to(&self, x: f64, y: f64) -> Result<()> {
+        Ok(autopilot::mouse::move_to(Point::new(x, y))?)
+    }
+
+    fn screen_size(&self) -> Result<(f64, f64)> {
+        let size = autopilot::screen::size();
+        Ok((size.width, size.height))
+    }
+
+    fn toggle_button(&self, button: MouseButton, down: bool) -> Result<()> {
+        autopilot::mouse::toggle(button, down);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Key {
+        key: Key,
+        down: bool,
+        flags: Vec<Flag>,
+    },
+    Text(String),
+    Scroll {
+        axis: Axis,
+        clicks: i32,
+    },
+    MoveBy {
+        dx: i32,
+        dy: i32,
+    },
+    MoveTo {
+        x: f64,
+        y: f64,
+    },
+    MouseButton {
+        button: MouseButton,
+        down: bool,
+    },
+}
+
+// Pretend screen size reported by the recorder
+const RECORDER_SCREEN_SIZE: (f64, f64) = (1920.0, 1080.0);
+
+/// Records input actions in memory instead of performing them, for tests.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    actions: Arc<Mutex<Vec<Action>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All actions recorded so far. Clones of a recorder share the same actions.
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.actions.lock().unwrap().clear();
+    }
+
+    fn push(&self, action: Action) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
+
+impl KeyboardBackend for Recorder {
+    fn toggle(&self, key: Key, down: bool, flags: &[Flag]) -> Result<()> {
+        self.push(Action::Key {
+            key,
+            down,
+            flags: flags.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn type_text(&self, text: &str) -> Result<()> {
+        self.push(Action::Text(text.to_owned()));
+        Ok(())
+    }
+}
+
+impl MouseBackend for Recorder {
+    fn scroll(&self, axis: Axis, clicks: i32) -> Result<()> {
+        self.push(Action::Scroll { axis, clicks });
+        Ok(())
+    }
+
+    fn move_by(&self, dx: i32, dy: i32) -> Result<()> {
+        self.push(Action::MoveBy { dx, dy });
+        Ok(())
+    }
+
+    fn move_to(&self, x: f64, y: f64) -> Result<()> {
+        self.push(Action::MoveTo { x, y });
+        Ok(())
+    }
+
+    fn screen_size(&self) -> Result<(f64, f64)> {
+        Ok(RECORDER_SCREEN_SIZE)
+    }
+
+    fn toggle_button(&self, button: MouseButton, down: bool) -> Result<()> {
+        self.push(Action::MouseButton { button, down });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{self, KeyCode, KeyCombo};
+    use crate::mouse;
+
+    // The backend is global, so everything using it is checked in one test
+    #[test]
+    fn recorder_captures_keyboard_and_mouse() {
+        let recorder = Recorder::new();
+        set(recorder.clone());
+
+        keyboard::tap_key(KeyCode::Return).unwrap();
+        KeyCombo::new('c', &[Flag::Control]).press().unwrap();
+        mouse::scroll(Axis::Horizontal, -2).unwrap();
+        mouse::move_to_fraction(0.5, 0.25).unwrap();
+        mouse::click(MouseButton::Left).unwrap();
+
+        assert_eq!(
+            recorder.actions(),
+            vec![
+                Action::Key {
+                    key: Key::Code(KeyCode::Return),
+                    down: true,
+                    flags: vec![],
+                },
+                Action::Key {
+                    key: Key::Code(KeyCode::Return),
+                    down: false,
+                    flags: vec![],
+                },
+                Action::Key {
+                    key: Key::Char('c'),
+                    down: true,
+                    flags: vec![Flag::Control],
+                },
+                Action::Scroll {
+                    axis: Axis::Horizontal,
+                    clicks: -2,
+                },
+                Action::MoveTo {
+                    x: 959.5,
+                    y: 269.75
+                },
+                Action::MouseButton {
+                    button: MouseButton::Left,
+                    down: true,
+                },
+                Action::MouseButton {
+                    button: MouseButton::Left,
+                    down: false,
+                },
+            ]
+        );
+
+        recorder.clear();
+        assert!(recorder.actions().is_empty());
+
+        assert!(select("bogus").is_err());
+        select("autopilot").unwrap();
+    }
+}
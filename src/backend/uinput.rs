@@ -0,0 +1,355 @@
+//! Virtual keyboard and mouse through the Linux uinput module, which works regardless of the
+//! display server (including Wayland). The user needs write access to `/dev/uinput`.
+
+use crate::keyboard::{Flag, Key, KeyCode, KeyboardBackend};
+use crate::mouse::{Axis, MouseBackend, MouseButton};
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+// From linux/uinput.h and linux/input-event-codes.h
+const UI_SET_EVBIT: u64 = 0x4004_5564;
+const UI_SET_KEYBIT: u64 = 0x4004_5565;
+const UI_SET_RELBIT: u64 = 0x4004_5566;
+const UI_DEV_CREATE: u64 = 0x5501;
+const UI_DEV_DESTROY: u64 = 0x5502;
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const SYN_REPORT: u16 = 0;
+const BUS_USB: u16 = 0x03;
+const KEY_MAX: u16 = 0x2ff;
+const ABS_CNT: usize = 0x40;
+
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTALT: u16 = 56;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_HELP: u16 = 138;
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; 80],
+    id: InputId,
+    ff_effects_max: u32,
+    absmax: [i32; ABS_CNT],
+    absmin: [i32; ABS_CNT],
+    absfuzz: [i32; ABS_CNT],
+    absflat: [i32; ABS_CNT],
+}
+
+#[derive(Debug)]
+pub struct Uinput {
+    file: Mutex<File>,
+}
+
+impl Uinput {
+    /// Creates a virtual keyboard and mouse with the given name. Some desktops take a moment to pick
+    /// up new devices, so input sent immediately after creation may be dropped.
+    pub fn new(name: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(UINPUT_PATH)
+            .with_context(|| format!("failed to open {}", UINPUT_PATH))?;
+
+        ioctl(&file, UI_SET_EVBIT, EV_KEY as libc::c_ulong)?;
+        for code in 1..KEY_MAX {
+            ioctl(&file, UI_SET_KEYBIT, code as libc::c_ulong)?;
+        }
+
+        ioctl(&file, UI_SET_EVBIT, EV_REL as libc::c_ulong)?;
+        for code in &[REL_X, REL_Y, REL_HWHEEL, REL_WHEEL] {
+            ioctl(&file, UI_SET_RELBIT, *code as libc::c_ulong)?;
+        }
+
+        let mut device = UinputUserDev {
+            name: [0; 80],
+            id: InputId {
+                bustype: BUS_USB,
+                vendor: 0x1,
+                product: 0x1,
+                version: 1,
+            },
+            ff_effects_max: 0,
+            absmax: [0; ABS_CNT],
+            absmin: [0; ABS_CNT],
+            absfuzz: [0; ABS_CNT],
+            absflat: [0; ABS_CNT],
+        };
+        let name_len = name.len().min(device.name.len() - 1);
+        device.name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+
+        (&file).write_all(as_bytes(&device))?;
+        ioctl(&file, UI_DEV_CREATE, 0)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn emit(&self, file: &mut File, type_: u16, code: u16, value: i32) -> Result<()> {
+        let event = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+
+        file.write_all(as_bytes(&event))
+            .context("failed to write uinput event")
+    }
+
+    fn emit_keys(&self, codes: &[u16], down: bool) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let value = if down { 1 } else { 0 };
+
+        for code in codes {
+            self.emit(&mut file, EV_KEY, *code, value)?;
+        }
+
+        self.emit(&mut file, EV_SYN, SYN_REPORT, 0)
+    }
+
+    fn emit_relative(&self, motions: &[(u16, i32)]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        for (code, value) in motions {
+            if *value != 0 {
+                self.emit(&mut file, EV_REL, *code, *value)?;
+            }
+        }
+
+        self.emit(&mut file, EV_SYN, SYN_REPORT, 0)
+    }
+}
+
+impl KeyboardBackend for Uinput {
+    fn toggle(&self, key: Key, down: bool, flags: &[Flag]) -> Result<()> {
+        let (code, needs_shift) = match key {
+            Key::Code(code) => (
+                key_code(code).with_context(|| format!("unsupported key {:?} for uinput", code))?,
+                false,
+            ),
+            Key::Char(c) => char_code(c)
+                .with_context(|| format!("character {:?} can't be typed with uinput", c))?,
+        };
+
+        let mut modifiers = flags
+            .iter()
+            .map(|flag| flag_code(*flag))
+            .collect::<Vec<_>>();
+        if needs_shift && !modifiers.contains(&KEY_LEFTSHIFT) {
+            modifiers.push(KEY_LEFTSHIFT);
+        }
+
+        // Modifiers go down before the key, and come up after it
+        if down {
+            self.emit_keys(&modifiers, true)?;
+            self.emit_keys(&[code], true)
+        } else {
+            self.emit_keys(&[code], false)?;
+            modifiers.reverse();
+            self.emit_keys(&modifiers, false)
+        }
+    }
+}
+
+// uinput devices here only report relative motion, so absolute positioning isn't possible
+impl MouseBackend for Uinput {
+    fn scroll(&self, axis: Axis, clicks: i32) -> Result<()> {
+        // Positive wheel values scroll up, while positive horizontal values scroll right
+        match axis {
+            Axis::Vertical => self.emit_relative(&[(REL_WHEEL, -clicks)]),
+            Axis::Horizontal => self.emit_relative(&[(REL_HWHEEL, clicks)]),
+        }
+    }
+
+    fn move_by(&self, dx: i32, dy: i32) -> Result<()> {
+        self.emit_relative(&[(REL_X, dx), (REL_Y, dy)])
+    }
+
+    fn move_to(&self, _x: f64, _y: f64) -> Result<()> {
+        bail!("uinput doesn't support absolute pointer positioning")
+    }
+
+    fn screen_size(&self) -> Result<(f64, f64)> {
+        bail!("uinput doesn't know the screen size")
+    }
+
+    fn toggle_button(&self, button: MouseButton, down: bool) -> Result<()> {
+        let code = match button {
+            MouseButton::Left => BTN_LEFT,
+            MouseButton::Middle => BTN_MIDDLE,
+            MouseButton::Right => BTN_RIGHT,
+        };
+
+        self.emit_keys(&[code], down)
+    }
+}
+
+impl Drop for Uinput {
+    fn drop(&mut self) {
+        if let Ok(file) = self.file.lock() {
+            let _ = ioctl(&file, UI_DEV_DESTROY, 0);
+        }
+    }
+}
+
+fn ioctl(file: &File, request: u64, arg: libc::c_ulong) -> Result<()> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg) };
+    if result < 0 {
+        bail!(
+            "uinput ioctl {:#x} failed: {}",
+            request,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    // Only used with the plain `repr(C)` structs above
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn flag_code(flag: Flag) -> u16 {
+    match flag {
+        Flag::Shift => KEY_LEFTSHIFT,
+        Flag::Control => KEY_LEFTCTRL,
+        Flag::Alt => KEY_LEFTALT,
+        Flag::Meta => KEY_LEFTMETA,
+        Flag::Help => KEY_HELP,
+    }
+}
+
+fn key_code(code: KeyCode) -> Option<u16> {
+    use KeyCode::*;
+
+    let code = match code {
+        F1 => 59,
+        F2 => 60,
+        F3 => 61,
+        F4 => 62,
+        F5 => 63,
+        F6 => 64,
+        F7 => 65,
+        F8 => 66,
+        F9 => 67,
+        F10 => 68,
+        F11 => 87,
+        F12 => 88,
+        F13 => 183,
+        F14 => 184,
+        F15 => 185,
+        F16 => 186,
+        F17 => 187,
+        F18 => 188,
+        F19 => 189,
+        F20 => 190,
+        F21 => 191,
+        F22 => 192,
+        F23 => 193,
+        F24 => 194,
+        LeftArrow => 105,
+        Control => KEY_LEFTCTRL,
+        RightArrow => 106,
+        DownArrow => 108,
+        End => 107,
+        UpArrow => 103,
+        PageUp => 104,
+        Alt => KEY_LEFTALT,
+        Return => 28,
+        PageDown => 109,
+        Delete => 111,
+        Home => 102,
+        Escape => 1,
+        Backspace => 14,
+        Meta => KEY_LEFTMETA,
+        CapsLock => 58,
+        Shift => KEY_LEFTSHIFT,
+        Tab => 15,
+        Space => 57,
+        // Newer autopilot releases add keys (e.g. `Insert`, `PrintScreen`) that aren't mapped here
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+/// Key code for a character on a US layout, and whether shift needs to be held.
+fn char_code(c: char) -> Option<(u16, bool)> {
+    const LETTERS: [u16; 26] = [
+        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
+        45, 21, 44,
+    ];
+
+    let code = match c {
+        'a'..='z' => (LETTERS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTERS[c as usize - 'A' as usize], true),
+        '1'..='9' => (c as u16 - '1' as u16 + 2, false),
+        '0' => (11, false),
+        '!' => (2, true),
+        '@' => (3, true),
+        '#' => (4, true),
+        '$' => (5, true),
+        '%' => (6, true),
+        '^' => (7, true),
+        '&' => (8, true),
+        '*' => (9, true),
+        '(' => (10, true),
+        ')' => (11, true),
+        '-' => (12, false),
+        '_' => (12, true),
+        '=' => (13, false),
+        '+' => (13, true),
+        '[' => (26, false),
+        '{' => (26, true),
+        ']' => (27, false),
+        '}' => (27, true),
+        ';' => (39, false),
+        ':' => (39, true),
+        '\'' => (40, false),
+        '"' => (40, true),
+        '`' => (41, false),
+        '~' => (41, true),
+        '\\' => (43, false),
+        '|' => (43, true),
+        ',' => (51, false),
+        '<' => (51, true),
+        '.' => (52, false),
+        '>' => (52, true),
+        '/' => (53, false),
+        '?' => (53, true),
+        ' ' => (57, false),
+        '\n' => (28, false),
+        '\t' => (15, false),
+        _ => return None,
+    };
+
+    Some(code)
+}
@@ -0,0 +1,70 @@
+//! Poses as an X-Touch Mini through virtual MIDI ports, so apps connect to it like the real
+//! device. It's controlled from the terminal, or replays a recording.
+
+use anyhow::Result;
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() -> Result<()> {
+    use anyhow::Context as _;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+    use tracing::error;
+    use xtouchmini::record::Replay;
+    use xtouchmini::simulator::Simulator;
+    use xtouchmini::virtual_ports::{VirtualPortNames, VirtualPorts};
+
+    #[derive(StructOpt)]
+    struct Opt {
+        /// Name of the virtual device
+        #[structopt(long, default_value = "X-TOUCH MINI")]
+        name: String,
+        /// Replay a recording instead of showing the simulator
+        #[structopt(long)]
+        replay: Option<PathBuf>,
+        /// Playback speed for --replay
+        #[structopt(long, default_value = "1.0")]
+        speed: f64,
+    }
+
+    let opt = Opt::from_args();
+
+    xtouchmini::simulator::init_logging()?;
+
+    let mut ports = VirtualPorts::create(&VirtualPortNames::new(opt.name))?;
+
+    if let Some(path) = opt.replay {
+        let mut messages = Replay::open(path)?.speed(opt.speed).into_midi_stream();
+        while let Some(bytes) = messages.next().await {
+            ports.send(&bytes)?;
+        }
+
+        return Ok(());
+    }
+
+    let received = ports
+        .take_received()
+        .context("virtual port messages already taken")?;
+    let (input_tx, mut input_rx) = mpsc::unbounded::<Vec<u8>>();
+    let simulator = Simulator::with_channels(input_tx, received);
+
+    let forward = async move {
+        while let Some(bytes) = input_rx.next().await {
+            if let Err(error) = ports.send(&bytes) {
+                error!(?error, "Failed to send to virtual port");
+            }
+        }
+    };
+
+    tokio::select! {
+        result = simulator.run() => result,
+        _ = forward => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn main() -> Result<()> {
+    anyhow::bail!("virtual MIDI ports aren't supported on this platform")
+}
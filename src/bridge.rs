@@ -0,0 +1,866 @@
+//! Forwarding events to another MIDI port as arbitrary MIDI messages, e.g. to drive a DAW or
+//! lighting software, with feedback from that port shown on the LEDs.
+//!
+//! Mappings are read from a JSON config:
+//!
+//! ```json
+//! {
+//!   "port": "Ardour",
+//!   "mappings": [
+//!     { "control": { "knob": "Knob1" }, "gesture": "turn", "send": { "type": "cc", "channel": 1, "number": 20 } },
+//!     { "control": { "knob": "Knob2" }, "layer": "a", "send": { "type": "nrpn", "channel": 1, "parameter": 1000, "relative": true } },
+//!     { "control": { "button": "Button1" }, "send": { "type": "note", "channel": 1, "note": 60 } },
+//!     { "control": "fader", "send": { "type": "pitch_bend", "channel": 1 } }
+//!   ],
+//!   "feedback": [
+//!     { "receive": { "type": "note", "channel": 1, "note": 60 }, "led": { "button": "Button1" } },
+//!     { "receive": { "type": "cc", "channel": 1, "number": 20 }, "led": { "knob": "Knob1", "style": "fan" } }
+//!   ]
+//! }
+//! ```
+
+use crate::model::{Button, ButtonLedState, Event, Knob, KnobLedStyle, KnobLedValue, KnobState};
+use crate::output::Command;
+use crate::router::{Control, Gesture, HasController, Layer, Middleware, Next};
+use crate::status::{Source, WithSource};
+use crate::{is_device_port, MIDI_CLIENT_NAME};
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use futures::task::{Context, Poll};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tracing::error;
+
+/// Largest value of 14-bit messages. Values are kept at this resolution, and reduced for 7-bit
+/// messages.
+const MAX_VALUE: u16 = 0x3fff;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BridgeConfig {
+    /// MIDI port to send to, and to receive feedback from
+    pub port: String,
+    /// Creates virtual ports named `port` for other apps to connect to, instead of connecting
+    /// to an existing port
+    #[serde(default, rename = "virtual")]
+    pub virtual_port: bool,
+    #[serde(default)]
+    pub mappings: Vec<Mapping>,
+    #[serde(default)]
+    pub feedback: Vec<FeedbackRule>,
+}
+
+impl BridgeConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read bridge config {:?}", path))?;
+        let config: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid bridge config {:?}", path))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let messages = self
+            .mappings
+            .iter()
+            .map(|mapping| &mapping.send)
+            .chain(self.feedback.iter().map(|rule| &rule.receive));
+
+        for message in messages {
+            message.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends a MIDI message when a matching event is handled. Unset parts match anything.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    pub control: Control,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<Layer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gesture: Option<Gesture>,
+    pub send: Message,
+}
+
+impl Mapping {
+    fn matches(&self, event: &Event, layer: Layer) -> bool {
+        self.control == Control::from(event)
+            && self.layer.iter().all(|expected| *expected == layer)
+            && self
+                .gesture
+                .iter()
+                .all(|expected| *expected == Gesture::from(event))
+    }
+}
+
+/// Channels are numbered from 1 to 16.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Note on while pressed (or with the control's value), note off otherwise
+    Note {
+        channel: u8,
+        note: u8,
+    },
+    /// Relative values are sent as 64 plus the knob delta
+    Cc {
+        channel: u8,
+        number: u8,
+        #[serde(default)]
+        relative: bool,
+    },
+    /// Relative values are sent with data increment and decrement messages
+    Nrpn {
+        channel: u8,
+        parameter: u16,
+        #[serde(default)]
+        relative: bool,
+    },
+    PitchBend {
+        channel: u8,
+    },
+}
+
+impl Message {
+    fn validate(&self) -> Result<()> {
+        let channel = self.channel();
+        if !(1..=16).contains(&channel) {
+            bail!("MIDI channel {} is out of range 1-16", channel);
+        }
+
+        match *self {
+            Self::Note { note: number, .. } | Self::Cc { number, .. } if number > 0x7f => {
+                bail!("note or controller number {} is out of range 0-127", number)
+            }
+            Self::Nrpn { parameter, .. } if parameter > MAX_VALUE => {
+                bail!("NRPN parameter {} is out of range 0-16383", parameter)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn channel(&self) -> u8 {
+        match *self {
+            Self::Note { channel, .. }
+            | Self::Cc { channel, .. }
+            | Self::Nrpn { channel, .. }
+            | Self::PitchBend { channel } => channel,
+        }
+    }
+
+    fn is_relative(&self) -> bool {
+        matches!(
+            self,
+            Self::Cc { relative: true, .. } | Self::Nrpn { relative: true, .. }
+        )
+    }
+
+    fn address(&self) -> Address {
+        let channel = self.channel().saturating_sub(1) & 0x0f;
+
+        match *self {
+            Self::Note { note, .. } => Address::Note(channel, note),
+            Self::Cc { number, .. } => Address::Cc(channel, number),
+            Self::Nrpn { parameter, .. } => Address::Nrpn(channel, parameter),
+            Self::PitchBend { .. } => Address::PitchBend(channel),
+        }
+    }
+
+    fn encode(&self, value: Value) -> Vec<[u8; 3]> {
+        let status = |kind: u8| kind | (self.channel().saturating_sub(1) & 0x0f);
+
+        match (self, value) {
+            (Self::Note { note, .. }, value) => {
+                let velocity = (value.absolute() >> 7) as u8;
+                if velocity > 0 {
+                    vec![[status(0x90), *note, velocity]]
+                } else {
+                    vec![[status(0x80), *note, 0]]
+                }
+            }
+            (Self::Cc { number, .. }, Value::Relative(delta)) => {
+                vec![[status(0xb0), *number, (64 + delta.clamp(-63, 63)) as u8]]
+            }
+            (Self::Cc { number, .. }, value) => {
+                vec![[status(0xb0), *number, (value.absolute() >> 7) as u8]]
+            }
+            (Self::Nrpn { parameter, .. }, value) => {
+                let mut messages = vec![
+                    [status(0xb0), 99, (parameter >> 7) as u8],
+                    [status(0xb0), 98, (parameter & 0x7f) as u8],
+                ];
+
+                match value {
+                    Value::Relative(delta) => {
+                        let number = if delta < 0 { 97 } else { 96 };
+                        messages.push([status(0xb0), number, delta.abs().min(0x7f) as u8]);
+                    }
+                    Value::Absolute(value) => {
+                        messages.push([status(0xb0), 6, (value >> 7) as u8]);
+                        messages.push([status(0xb0), 38, (value & 0x7f) as u8]);
+                    }
+                }
+
+                messages
+            }
+            (Self::PitchBend { .. }, value) => {
+                let value = value.absolute();
+                vec![[status(0xe0), (value & 0x7f) as u8, (value >> 7) as u8]]
+            }
+        }
+    }
+}
+
+/// A control's value for a mapping, as a 14-bit value or a knob delta.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Absolute(u16),
+    Relative(i32),
+}
+
+impl Value {
+    fn absolute(self) -> u16 {
+        match self {
+            Self::Absolute(value) => value,
+            // Only possible for notes, which treat any turn as a press
+            Self::Relative(delta) if delta != 0 => MAX_VALUE,
+            Self::Relative(_) => 0,
+        }
+    }
+}
+
+/// Channels are 0-based here, as in the MIDI messages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Address {
+    Note(u8, u8),
+    Cc(u8, u8),
+    Nrpn(u8, u16),
+    PitchBend(u8),
+}
+
+/// Shows a MIDI message from the bridged port on an LED.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeedbackRule {
+    pub receive: Message,
+    pub led: Led,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Led {
+    /// Lit with the `on` state for values in the upper half, off otherwise
+    Button {
+        button: Button,
+        #[serde(default = "default_on")]
+        on: ButtonLedState,
+    },
+    /// The value is shown on the ring
+    Knob {
+        knob: Knob,
+        #[serde(default = "default_style")]
+        style: KnobLedStyle,
+    },
+}
+
+fn default_on() -> ButtonLedState {
+    ButtonLedState::On
+}
+
+fn default_style() -> KnobLedStyle {
+    KnobLedStyle::Fan
+}
+
+impl Led {
+    fn command(&self, value: u16) -> Command {
+        match *self {
+            Self::Button { button, on } => Command::SetButtonLedState {
+                button,
+                state: if value > MAX_VALUE / 2 {
+                    on
+                } else {
+                    ButtonLedState::Off
+                },
+            },
+            Self::Knob { knob, style } => Command::SetKnobLedState {
+                knob,
+                state: KnobState {
+                    style,
+                    led_value: KnobLedValue::from_percent(value as f64 / MAX_VALUE as f64),
+                    ..Default::default()
+                },
+            },
+        }
+    }
+}
+
+type Output = Box<dyn FnMut(&[u8]) -> Result<()> + Send>;
+
+struct State {
+    send: Output,
+    /// Last value sent for each mapping, for knobs that aren't relative
+    values: Vec<u16>,
+}
+
+/// Forwards handled events to the bridged port. Added to a [`Router`](crate::Router) as
+/// middleware, so it sees events with the layer they happened in.
+pub struct Bridge {
+    mappings: Vec<Mapping>,
+    state: Mutex<State>,
+}
+
+impl Bridge {
+    /// Connects to the port in the config, returning the bridge along with its feedback.
+    pub fn connect(config: BridgeConfig) -> Result<(Self, Feedback)> {
+        config.validate()?;
+
+        if config.virtual_port {
+            return Self::connect_virtual(config);
+        }
+
+        let mut output = connect_output(&config.port)?;
+        let feedback = if config.feedback.is_empty() {
+            Feedback::new(config.feedback, stream::empty())
+        } else {
+            let (tx, rx) = mpsc::unbounded();
+            let connection = connect_input(&config.port, move |bytes| {
+                if let Err(error) = tx.unbounded_send(bytes.to_vec()) {
+                    error!(?error, "Failed to forward bridge feedback");
+                }
+            })?;
+
+            Feedback {
+                _connection: Some(connection),
+                ..Feedback::new(config.feedback, rx)
+            }
+        };
+
+        let bridge = Self::with_output(config.mappings, move |bytes| Ok(output.send(bytes)?));
+        Ok((bridge, feedback))
+    }
+
+    #[cfg(unix)]
+    fn connect_virtual(config: BridgeConfig) -> Result<(Self, Feedback)> {
+        use crate::virtual_ports::{VirtualPortNames, VirtualPorts};
+
+        let mut ports = VirtualPorts::create(&VirtualPortNames::new(config.port))?;
+        let received = ports
+            .take_received()
+            .context("virtual port messages already taken")?;
+
+        let feedback = Feedback::new(config.feedback, received);
+        let bridge = Self::with_output(config.mappings, move |bytes| ports.send(bytes));
+        Ok((bridge, feedback))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_virtual(_config: BridgeConfig) -> Result<(Self, Feedback)> {
+        bail!("virtual MIDI ports aren't supported on this platform")
+    }
+
+    /// Sends MIDI messages through `send` instead of to a port.
+    pub fn with_output<F>(mappings: Vec<Mapping>, send: F) -> Self
+    where
+        F: FnMut(&[u8]) -> Result<()> + Send + 'static,
+    {
+        let values = vec![0; mappings.len()];
+
+        Self {
+            mappings,
+            state: Mutex::new(State {
+                send: Box::new(send),
+                values,
+            }),
+        }
+    }
+
+    /// Sends the messages for every mapping that matches the event.
+    pub fn forward(&self, event: &Event, layer: Layer) -> Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("bridge state was poisoned"))?;
+        let State { send, values } = &mut *state;
+
+        for (mapping, last) in self.mappings.iter().zip(values.iter_mut()) {
+            if !mapping.matches(event, layer) {
+                continue;
+            }
+
+            let value = match *event {
+                Event::ButtonPressed { is_down, .. } | Event::KnobPressed { is_down, .. } => {
+                    Value::Absolute(if is_down { MAX_VALUE } else { 0 })
+                }
+                Event::FaderMoved { value } => {
+                    Value::Absolute(((value.0 as u16) << 7) | value.0 as u16)
+                }
+                Event::KnobTurned { delta, .. } if mapping.send.is_relative() => {
+                    Value::Relative(delta)
+                }
+                Event::KnobTurned { delta, .. } => {
+                    // One step per detent for 7-bit messages
+                    let value = *last as i32 + delta * 0x80;
+                    Value::Absolute(value.clamp(0, MAX_VALUE as i32) as u16)
+                }
+            };
+
+            if let Value::Absolute(value) = value {
+                *last = value;
+            }
+
+            for message in mapping.send.encode(value) {
+                send(&message)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Bridge {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        // Handlers may switch layers, so the layer is taken from before they run
+        let layer = Layer::current(context.controller().state());
+        let result = next.run(context, event).await;
+        let forwarded = self.forward(event, layer).with_source(Source::Bridge);
+
+        result.and(forwarded)
+    }
+}
+
+/// LED commands from MIDI messages that the bridged port sends back, to be applied with
+/// [`Controller::apply`](crate::Controller::apply).
+pub struct Feedback {
+    rules: Vec<FeedbackRule>,
+    messages: BoxStream<'static, Vec<u8>>,
+    parser: Parser,
+    pending: VecDeque<Command>,
+    _connection: Option<MidiInputConnection<()>>,
+}
+
+impl Feedback {
+    /// Feedback from MIDI messages coming from somewhere other than a port.
+    pub fn new<S>(rules: Vec<FeedbackRule>, messages: S) -> Self
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        Self {
+            rules,
+            messages: messages.boxed(),
+            parser: Parser::default(),
+            pending: VecDeque::new(),
+            _connection: None,
+        }
+    }
+}
+
+impl Stream for Feedback {
+    type Item = Command;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Command>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(command) = this.pending.pop_front() {
+                return Poll::Ready(Some(command));
+            }
+
+            let bytes = match this.messages.poll_next_unpin(context) {
+                Poll::Ready(Some(bytes)) => bytes,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if let Some((address, value)) = this.parser.parse(&bytes) {
+                let commands = this
+                    .rules
+                    .iter()
+                    .filter(|rule| rule.receive.address() == address)
+                    .map(|rule| rule.led.command(value));
+
+                this.pending.extend(commands);
+            }
+        }
+    }
+}
+
+/// Parses incoming messages into 14-bit values, keeping track of NRPN parameters per channel.
+#[derive(Debug, Default)]
+struct Parser {
+    nrpn: [Nrpn; 16],
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Nrpn {
+    parameter_msb: u8,
+    parameter_lsb: u8,
+    data_msb: u8,
+}
+
+impl Nrpn {
+    fn parameter(&self) -> u16 {
+        ((self.parameter_msb as u16) << 7) | self.parameter_lsb as u16
+    }
+}
+
+impl Parser {
+    fn parse(&mut self, bytes: &[u8]) -> Option<(Address, u16)> {
+        let (status, data1, data2) = match *bytes {
+            [status, data1, data2] => (status, data1, data2),
+            _ => return None,
+        };
+        let channel = status & 0x0f;
+        let nrpn = &mut self.nrpn[channel as usize];
+
+        match status & 0xf0 {
+            0x80 => Some((Address::Note(channel, data1), 0)),
+            0x90 => Some((Address::Note(channel, data1), to_14_bit(data2))),
+            0xe0 => Some((
+                Address::PitchBend(channel),
+                ((data2 as u16) << 7) | data1 as u16,
+            )),
+            0xb0 => match data1 {
+                99 => {
+                    nrpn.parameter_msb = data2;
+                    None
+                }
+                98 => {
+                    nrpn.parameter_lsb = data2;
+                    None
+                }
+                6 => {
+                    nrpn.data_msb = data2;
+                    Some((
+                        Address::Nrpn(channel, nrpn.parameter()),
+                        (data2 as u16) << 7,
+                    ))
+                }
+                38 => Some((
+                    Address::Nrpn(channel, nrpn.parameter()),
+                    ((nrpn.data_msb as u16) << 7) | data2 as u16,
+                )),
+                number => Some((Address::Cc(channel, number), to_14_bit(data2))),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Scales a 7-bit value so that 127 becomes the maximum.
+fn to_14_bit(value: u8) -> u16 {
+    ((value as u16) << 7) | value as u16
+}
+
+fn connect_output(port_name: &str) -> Result<midir::MidiOutputConnection> {
+    let midi_out = MidiOutput::new(MIDI_CLIENT_NAME)?;
+
+    let port = midi_out
+        .ports()
+        .into_iter()
+        .find(|port| is_device_port(midi_out.port_name(port), port_name))
+        .with_context(|| format!("could not find MIDI output port {}", port_name))?;
+
+    midi_out
+        .connect(&port, MIDI_CLIENT_NAME)
+        .map_err(|e| anyhow!("failed to connect to MIDI output port {}: {}", port_name, e))
+}
+
+fn connect_input<F>(port_name: &str, mut handler: F) -> Result<MidiInputConnection<()>>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    let mut midi_in = MidiInput::new(MIDI_CLIENT_NAME)?;
+    midi_in.ignore(Ignore::All);
+
+    let port = midi_in
+        .ports()
+        .into_iter()
+        .find(|port| is_device_port(midi_in.port_name(port), port_name))
+        .with_context(|| format!("could not find MIDI input port {}", port_name))?;
+
+    midi_in
+        .connect(
+            &port,
+            MIDI_CLIENT_NAME,
+            move |_timestamp, bytes, ()| handler(bytes),
+            (),
+        )
+        .map_err(|e| anyhow!("failed to connect to MIDI input port {}: {}", port_name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::FaderValue;
+    use std::sync::Arc;
+
+    // The example from the module docs
+    const CONFIG: &str = r#"{
+      "port": "Ardour",
+      "mappings": [
+        { "control": { "knob": "Knob1" }, "gesture": "turn", "send": { "type": "cc", "channel": 1, "number": 20 } },
+        { "control": { "knob": "Knob2" }, "layer": "a", "send": { "type": "nrpn", "channel": 1, "parameter": 1000, "relative": true } },
+        { "control": { "button": "Button1" }, "send": { "type": "note", "channel": 1, "note": 60 } },
+        { "control": "fader", "send": { "type": "pitch_bend", "channel": 1 } }
+      ],
+      "feedback": [
+        { "receive": { "type": "note", "channel": 1, "note": 60 }, "led": { "button": "Button1" } },
+        { "receive": { "type": "cc", "channel": 1, "number": 20 }, "led": { "knob": "Knob1", "style": "fan" } }
+      ]
+    }"#;
+
+    fn config() -> BridgeConfig {
+        let config: BridgeConfig = serde_json::from_str(CONFIG).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    /// A bridge that collects the messages it sends.
+    fn bridge(mappings: Vec<Mapping>) -> (Bridge, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let output = sent.clone();
+        let bridge = Bridge::with_output(mappings, move |bytes| {
+            output.lock().unwrap().push(bytes.to_vec());
+            Ok(())
+        });
+
+        (bridge, sent)
+    }
+
+    fn turn(knob: Knob, delta: i32) -> Event {
+        Event::KnobTurned { knob, delta }
+    }
+
+    #[test]
+    fn parses_config() {
+        let config = config();
+        assert_eq!(config.port, "Ardour");
+        assert!(!config.virtual_port);
+        assert_eq!(
+            config.mappings[1],
+            Mapping {
+                control: Control::Knob(Knob::Knob2),
+                layer: Some(Layer::A),
+                gesture: None,
+                send: Message::Nrpn {
+                    channel: 1,
+                    parameter: 1000,
+                    relative: true,
+                },
+            }
+        );
+        assert_eq!(config.mappings[3].control, Control::Fader);
+        assert_eq!(
+            config.feedback[0].led,
+            Led::Button {
+                button: Button::Button1,
+                on: ButtonLedState::On,
+            }
+        );
+        assert_eq!(
+            config.feedback[1].led,
+            Led::Knob {
+                knob: Knob::Knob1,
+                style: KnobLedStyle::Fan,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_messages() {
+        let invalid = [
+            Message::Cc {
+                channel: 0,
+                number: 1,
+                relative: false,
+            },
+            Message::Note {
+                channel: 17,
+                note: 60,
+            },
+            Message::Note {
+                channel: 1,
+                note: 128,
+            },
+            Message::Nrpn {
+                channel: 1,
+                parameter: 0x4000,
+                relative: false,
+            },
+        ];
+
+        for message in invalid.iter() {
+            assert!(message.validate().is_err(), "{:?} was valid", message);
+        }
+    }
+
+    #[test]
+    fn encodes_messages() {
+        let (bridge, sent) = bridge(config().mappings);
+
+        bridge
+            .forward(&turn(Knob::Knob1, 2), Layer::Default)
+            .unwrap();
+        bridge
+            .forward(&turn(Knob::Knob1, -1), Layer::Default)
+            .unwrap();
+        // Only mapped on layer A
+        bridge.forward(&turn(Knob::Knob2, 3), Layer::B).unwrap();
+        bridge.forward(&turn(Knob::Knob2, 3), Layer::A).unwrap();
+        bridge.forward(&turn(Knob::Knob2, -2), Layer::A).unwrap();
+        bridge
+            .forward(
+                &Event::ButtonPressed {
+                    button: Button::Button1,
+                    is_down: true,
+                },
+                Layer::Default,
+            )
+            .unwrap();
+        bridge
+            .forward(
+                &Event::ButtonPressed {
+                    button: Button::Button1,
+                    is_down: false,
+                },
+                Layer::Default,
+            )
+            .unwrap();
+        bridge
+            .forward(
+                &Event::FaderMoved {
+                    value: FaderValue(127),
+                },
+                Layer::Default,
+            )
+            .unwrap();
+        bridge
+            .forward(
+                &Event::FaderMoved {
+                    value: FaderValue(64),
+                },
+                Layer::Default,
+            )
+            .unwrap();
+
+        // Parameter 1000 is 7 << 7 | 104
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                vec![0xb0, 20, 2],
+                vec![0xb0, 20, 1],
+                vec![0xb0, 99, 7],
+                vec![0xb0, 98, 104],
+                vec![0xb0, 96, 3],
+                vec![0xb0, 99, 7],
+                vec![0xb0, 98, 104],
+                vec![0xb0, 97, 2],
+                vec![0x90, 60, 127],
+                vec![0x80, 60, 0],
+                vec![0xe0, 0x7f, 0x7f],
+                vec![0xe0, 64, 64],
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_absolute_nrpn_and_relative_cc() {
+        let nrpn = Message::Nrpn {
+            channel: 2,
+            parameter: 0x3fff,
+            relative: false,
+        };
+        assert_eq!(
+            nrpn.encode(Value::Absolute(0x2001)),
+            vec![
+                [0xb1, 99, 0x7f],
+                [0xb1, 98, 0x7f],
+                [0xb1, 6, 0x40],
+                [0xb1, 38, 0x01],
+            ]
+        );
+
+        let cc = Message::Cc {
+            channel: 16,
+            number: 7,
+            relative: true,
+        };
+        assert_eq!(cc.encode(Value::Relative(-3)), vec![[0xbf, 7, 61]]);
+        assert_eq!(cc.encode(Value::Relative(100)), vec![[0xbf, 7, 127]]);
+    }
+
+    #[tokio::test]
+    async fn turns_feedback_into_led_commands() {
+        let mut rules = config().feedback;
+        rules.push(FeedbackRule {
+            receive: Message::Nrpn {
+                channel: 1,
+                parameter: 1000,
+                relative: false,
+            },
+            led: Led::Knob {
+                knob: Knob::Knob2,
+                style: KnobLedStyle::Single,
+            },
+        });
+
+        let messages = vec![
+            vec![0x90, 60, 127],
+            vec![0x80, 60, 0],
+            vec![0xb0, 20, 127],
+            // Unmapped note and channel
+            vec![0x90, 61, 127],
+            vec![0x91, 60, 127],
+            vec![0xb0, 99, 7],
+            vec![0xb0, 98, 104],
+            vec![0xb0, 6, 0],
+        ];
+        let commands = Feedback::new(rules, stream::iter(messages))
+            .collect::<Vec<_>>()
+            .await;
+
+        let knob = |knob, style, led_value| Command::SetKnobLedState {
+            knob,
+            state: KnobState {
+                style,
+                led_value,
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            commands,
+            vec![
+                Command::SetButtonLedState {
+                    button: Button::Button1,
+                    state: ButtonLedState::On,
+                },
+                Command::SetButtonLedState {
+                    button: Button::Button1,
+                    state: ButtonLedState::Off,
+                },
+                knob(
+                    Knob::Knob1,
+                    KnobLedStyle::Fan,
+                    KnobLedValue::from_percent(1.0)
+                ),
+                knob(
+                    Knob::Knob2,
+                    KnobLedStyle::Single,
+                    KnobLedValue::from_percent(0.0)
+                ),
+            ]
+        );
+    }
+}
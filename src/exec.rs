@@ -0,0 +1,396 @@
+//! Running external programs as actions, with arguments templated from controller events.
+
+use crate::model::{Button, ButtonLedState, ControllerState, Event};
+use crate::output::Command;
+use anyhow::{bail, Context as _, Result};
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command as Process;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A program to run, with arguments that may contain placeholders such as `{delta}`.
+///
+/// Available placeholders:
+/// * `{button}`, `{knob}`: name of the control, e.g. `Button5` or `Knob3`
+/// * `{is_down}`: `true` or `false`, for presses
+/// * `{delta}`: how far a knob was turned
+/// * `{value}`: the knob's accumulated value
+/// * `{fader}`: the fader value (0 to 127)
+/// * `{percent}`: the fader value as a percentage (0 to 100)
+///
+/// Placeholders that don't apply to the event are left empty. Use `{{` and `}}` for literal
+/// braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecAction {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+    led: Option<(Button, LedMapping)>,
+}
+
+/// How the result of a command is shown on a button LED.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LedMapping {
+    ExitStatus {
+        success: ButtonLedState,
+        failure: ButtonLedState,
+    },
+    /// LED state for the trimmed stdout, falling back to `default` for other output
+    Stdout {
+        states: Vec<(String, ButtonLedState)>,
+        default: ButtonLedState,
+    },
+}
+
+impl LedMapping {
+    /// On if the command succeeds, off otherwise (e.g. for `systemctl is-active`).
+    pub fn exit_status() -> Self {
+        Self::ExitStatus {
+            success: ButtonLedState::On,
+            failure: ButtonLedState::Off,
+        }
+    }
+
+    pub fn stdout<S: Into<String>>(states: Vec<(S, ButtonLedState)>) -> Self {
+        Self::Stdout {
+            states: states
+                .into_iter()
+                .map(|(output, state)| (output.into(), state))
+                .collect(),
+            default: ButtonLedState::Off,
+        }
+    }
+
+    pub fn state(&self, output: &Output) -> ButtonLedState {
+        match self {
+            Self::ExitStatus { success, failure } => {
+                if output.status.success() {
+                    *success
+                } else {
+                    *failure
+                }
+            }
+            Self::Stdout { states, default } => {
+                let stdout = output.stdout.trim();
+                states
+                    .iter()
+                    .find(|(expected, _)| expected == stdout)
+                    .map(|(_, state)| *state)
+                    .unwrap_or(*default)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: String,
+}
+
+impl ExecAction {
+    pub fn new<S: Into<String>>(program: S) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            led: None,
+        }
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// The process is killed if it runs longer than this (10 seconds by default).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets a button LED from the result of the command.
+    pub fn led(mut self, button: Button, mapping: LedMapping) -> Self {
+        self.led = Some((button, mapping));
+        self
+    }
+
+    /// Arguments with placeholders filled in for the event.
+    pub fn render_args(&self, event: &Event, state: &ControllerState) -> Result<Vec<String>> {
+        let vars = vars(event, state);
+        self.args.iter().map(|arg| render(arg, &vars)).collect()
+    }
+}
+
+/// Runs [`ExecAction`]s, with a limit on how many processes run at the same time.
+#[derive(Clone, Debug)]
+pub struct Runner {
+    permits: Arc<Semaphore>,
+}
+
+impl Runner {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        }
+    }
+
+    /// Runs the action, waiting for a free slot if too many processes are already running.
+    pub async fn run(
+        &self,
+        action: &ExecAction,
+        event: &Event,
+        state: &ControllerState,
+    ) -> Result<Output> {
+        let args = action.render_args(event, state)?;
+        self.run_args(action, &args).await
+    }
+
+    /// Starts the action in the background and returns right away, so handlers aren't held up
+    /// while it waits for a slot or runs. Once it's done, its LED (if it has one) is set by
+    /// sending a command to `leds`, to be applied with [`crate::Controller::apply`]. Failures
+    /// are logged.
+    pub fn spawn_with_led(
+        &self,
+        action: &ExecAction,
+        event: &Event,
+        state: &ControllerState,
+        leds: &mpsc::UnboundedSender<Command>,
+    ) -> Result<JoinHandle<()>> {
+        let args = action.render_args(event, state)?;
+        let runner = self.clone();
+        let action = action.clone();
+        let leds = leds.clone();
+
+        Ok(tokio::spawn(async move {
+            let output = match runner.run_args(&action, &args).await {
+                Ok(output) => output,
+                Err(error) => {
+                    error!(?error, program = %action.program, "Failed to run command");
+                    return;
+                }
+            };
+
+            if let Some((button, mapping)) = &action.led {
+                // Only fails once the receiver is gone, when nobody cares about LEDs anymore
+                let _ = leds.unbounded_send(Command::SetButtonLedState {
+                    button: *button,
+                    state: mapping.state(&output),
+                });
+            }
+        }))
+    }
+
+    async fn run_args(&self, action: &ExecAction, args: &[String]) -> Result<Output> {
+        let _permit = self.permits.acquire().await?;
+
+        let child = Process::new(&action.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to run {}", action.program))?;
+
+        let output = tokio::time::timeout(action.timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("{} timed out", action.program))??;
+
+        Ok(Output {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        })
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+fn vars(event: &Event, state: &ControllerState) -> HashMap<&'static str, String> {
+    let mut vars = HashMap::new();
+
+    match event {
+        Event::ButtonPressed { button, is_down } => {
+            vars.insert("button", format!("{:?}", button));
+            vars.insert("is_down", is_down.to_string());
+        }
+        Event::KnobPressed { knob, is_down } => {
+            vars.insert("knob", format!("{:?}", knob));
+            vars.insert("is_down", is_down.to_string());
+            vars.insert("value", state.knob(*knob).value.to_string());
+        }
+        Event::KnobTurned { knob, delta } => {
+            vars.insert("knob", format!("{:?}", knob));
+            vars.insert("delta", delta.to_string());
+            vars.insert("value", state.knob(*knob).value.to_string());
+        }
+        Event::FaderMoved { .. } => {}
+    }
+
+    let fader = match event {
+        Event::FaderMoved { value } => *value,
+        _ => *state.fader(),
+    };
+    vars.insert("fader", fader.0.to_string());
+    vars.insert(
+        "percent",
+        format!("{}", (fader.as_percent() * 100.0).round()),
+    );
+
+    vars
+}
+
+fn render(template: &str, vars: &HashMap<&'static str, String>) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => bail!("unterminated placeholder {{{} in {:?}", name, template),
+                    }
+                }
+
+                match name.as_str() {
+                    "button" | "knob" | "is_down" | "delta" | "value" | "fader" | "percent" => {
+                        if let Some(value) = vars.get(name.as_str()) {
+                            output.push_str(value);
+                        }
+                    }
+                    _ => bail!("unknown placeholder {{{}}} in {:?}", name, template),
+                }
+            }
+            c => output.push(c),
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn spawned_actions_share_the_limit_and_report_leds() {
+        let runner = Runner::new(1);
+        let (leds_tx, leds_rx) = mpsc::unbounded();
+        let state = ControllerState::default();
+        let event = Event::ButtonPressed {
+            button: Button::Button1,
+            is_down: true,
+        };
+
+        let start = Instant::now();
+        let handles = [Button::Button1, Button::Button2]
+            .iter()
+            .map(|button| {
+                let action = ExecAction::new("sleep")
+                    .arg("0.2")
+                    .led(*button, LedMapping::exit_status());
+                runner
+                    .spawn_with_led(&action, &event, &state, &leds_tx)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // Spawning doesn't wait for the processes
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // Only one runs at a time
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        drop(leds_tx);
+        let mut commands = leds_rx.collect::<Vec<_>>().await;
+        commands.sort_by_key(|command| format!("{:?}", command));
+        assert_eq!(
+            commands,
+            vec![
+                Command::SetButtonLedState {
+                    button: Button::Button1,
+                    state: ButtonLedState::On,
+                },
+                Command::SetButtonLedState {
+                    button: Button::Button2,
+                    state: ButtonLedState::On,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let action = ExecAction::new("echo").args(vec!["{knob}", "{delta}", "{{literal}}"]);
+        let event = Event::KnobTurned {
+            knob: crate::model::Knob::Knob2,
+            delta: -3,
+        };
+
+        assert_eq!(
+            action
+                .render_args(&event, &ControllerState::default())
+                .unwrap(),
+            vec!["Knob2", "-3", "{literal}"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_placeholders() {
+        let event = Event::ButtonPressed {
+            button: crate::model::Button::Button1,
+            is_down: true,
+        };
+        let render_arg = |arg: &str| {
+            ExecAction::new("echo")
+                .args(vec![arg])
+                .render_args(&event, &ControllerState::default())
+        };
+
+        let error = render_arg("{button").unwrap_err();
+        assert!(error.to_string().contains("unterminated placeholder"));
+        assert!(render_arg("prefix {is_down").is_err());
+
+        let error = render_arg("{nope}").unwrap_err();
+        assert!(error.to_string().contains("unknown placeholder"));
+
+        assert_eq!(render_arg("{button}").unwrap(), vec!["Button1"]);
+    }
+}
@@ -0,0 +1,23 @@
+// Focuses the chat input of a YouTube or Twitch stream. This runs inside the browser tab.
+(() => {
+  try {
+    // YouTube
+    const el = document
+      .querySelector('#chatframe')
+      .contentWindow
+      .document
+      .querySelector('#input')
+      .querySelector('#input');
+
+    const selection = window.getSelection();
+    const range = document.createRange();
+    selection.removeAllRanges();
+    range.selectNodeContents(el);
+    range.collapse(false);
+    selection.addRange(range);
+    el.focus();
+  } catch (e) {
+    // Twitch
+    document.querySelector('[data-a-target="chat-input"]').focus();
+  }
+})()
@@ -0,0 +1,28 @@
+// Runs through osascript, with `$params.urlPatterns` (tab URL substrings to look for) and
+// `$params.javascript` (code to run in the tab, or null)
+const app = Application("Google Chrome");
+app.activate();
+
+const matches = tab => $params.urlPatterns.some(pattern => tab.url().includes(pattern));
+
+// Find matching tab
+const activeTab = app.windows[0].activeTab;
+if (!matches(activeTab)) {
+  for (const [winIndex, win] of app.windows().entries()) {
+    const tabIndex = win.tabs().findIndex(matches);
+
+    if (tabIndex != -1) {
+      win.activeTabIndex = tabIndex + 1;
+      win.index = 1;
+      if (winIndex != 0) {
+        win.visible = false;
+        win.visible = true;
+      }
+      break;
+    }
+  }
+}
+
+if ($params.javascript) {
+  app.execute(app.windows[0].activeTab, { javascript: $params.javascript });
+}
@@ -0,0 +1,360 @@
+//! Actions for bringing a browser tab (and an element inside it) into focus.
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use tokio::process::Command;
+
+/// The tab to focus, and what to do once it's focused.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FocusTarget {
+    /// The first tab whose URL contains any of these is focused
+    pub url_patterns: Vec<String>,
+    /// JavaScript expression evaluated in the tab after focusing it
+    pub script: Option<String>,
+    /// Title of the browser window, for backends that activate windows by title
+    pub window_title: Option<String>,
+}
+
+impl FocusTarget {
+    pub fn new<S: AsRef<str>>(url_patterns: &[S]) -> Self {
+        Self {
+            url_patterns: url_patterns
+                .iter()
+                .map(|pattern| pattern.as_ref().to_owned())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn script<S: Into<String>>(mut self, script: S) -> Self {
+        self.script = Some(script.into());
+        self
+    }
+
+    pub fn window_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.window_title = Some(title.into());
+        self
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        self.url_patterns
+            .iter()
+            .any(|pattern| url.contains(pattern.as_str()))
+    }
+}
+
+#[async_trait]
+pub trait Focus: Send + Sync {
+    async fn focus(&self, target: &FocusTarget) -> Result<()>;
+}
+
+/// The best available backend for the platform: AppleScript on macOS (with the `macos`
+/// feature), otherwise the DevTools protocol (with the `devtools` feature), otherwise EWMH
+/// window activation.
+pub fn platform_default() -> Box<dyn Focus> {
+    #[cfg(all(feature = "macos", target_os = "macos"))]
+    let focus: Box<dyn Focus> = Box::new(ChromeAppleScript);
+
+    #[cfg(all(feature = "devtools", not(all(feature = "macos", target_os = "macos"))))]
+    let focus: Box<dyn Focus> = Box::new(DevTools::default());
+
+    #[cfg(not(any(feature = "devtools", all(feature = "macos", target_os = "macos"))))]
+    let focus: Box<dyn Focus> = Box::new(Ewmh::default());
+
+    focus
+}
+
+/// Focuses Chrome tabs through AppleScript (JavaScript for Automation).
+#[cfg(feature = "macos")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ChromeAppleScript;
+
+#[cfg(feature = "macos")]
+#[async_trait]
+impl Focus for ChromeAppleScript {
+    async fn focus(&self, target: &FocusTarget) -> Result<()> {
+        let params = serde_json::json!({
+            "urlPatterns": target.url_patterns,
+            "javascript": target.script,
+        });
+
+        tokio::task::spawn_blocking(move || {
+            osascript::JavaScript::new(include_str!("focus-chrome-tab.js"))
+                .execute_with_params::<_, ()>(params)
+        })
+        .await?
+        .context("failed to focus Chrome tab")
+    }
+}
+
+/// Activates the browser window by title through EWMH, using `wmctrl`. Tabs can't be
+/// selected this way, so the URL patterns and script are ignored.
+#[derive(Clone, Debug)]
+pub struct Ewmh {
+    default_title: String,
+}
+
+impl Ewmh {
+    /// Activates windows whose title contains `default_title`, unless the target has its
+    /// own window title.
+    pub fn new<S: Into<String>>(default_title: S) -> Self {
+        Self {
+            default_title: default_title.into(),
+        }
+    }
+}
+
+impl Default for Ewmh {
+    fn default() -> Self {
+        Self::new("Chrom")
+    }
+}
+
+#[async_trait]
+impl Focus for Ewmh {
+    async fn focus(&self, target: &FocusTarget) -> Result<()> {
+        activate_window(self.title(target)).await
+    }
+}
+
+impl Ewmh {
+    fn title<'a>(&'a self, target: &'a FocusTarget) -> &'a str {
+        target
+            .window_title
+            .as_deref()
+            .unwrap_or(&self.default_title)
+    }
+}
+
+fn wmctrl_args(title: &str) -> [&str; 2] {
+    ["-a", title]
+}
+
+async fn activate_window(title: &str) -> Result<()> {
+    let status = Command::new("wmctrl")
+        .args(wmctrl_args(title))
+        .status()
+        .await
+        .context("failed to run wmctrl")?;
+
+    if !status.success() {
+        bail!("no window found matching {:?}", title);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "devtools")]
+pub use self::devtools::DevTools;
+
+#[cfg(feature = "devtools")]
+mod devtools {
+    use super::{activate_window, Focus, FocusTarget};
+    use anyhow::{bail, Context as _, Result};
+    use async_trait::async_trait;
+    use futures::{SinkExt, StreamExt};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Target {
+        id: String,
+        r#type: String,
+        url: String,
+        web_socket_debugger_url: Option<String>,
+    }
+
+    /// Focuses tabs of a Chromium-based browser through the DevTools protocol. The browser
+    /// needs to be started with `--remote-debugging-port` (9222 by default).
+    #[derive(Clone, Debug)]
+    pub struct DevTools {
+        addr: String,
+    }
+
+    impl DevTools {
+        pub fn new<S: Into<String>>(addr: S) -> Self {
+            Self { addr: addr.into() }
+        }
+
+        async fn get(&self, path: &str) -> Result<Vec<u8>> {
+            let mut stream = TcpStream::connect(&self.addr)
+                .await
+                .with_context(|| format!("failed to connect to DevTools at {}", self.addr))?;
+
+            let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, self.addr);
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+
+            let body_start = response
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .context("invalid HTTP response from DevTools")?;
+
+            let status_line = response.split(|b| *b == b'\r').next().unwrap_or_default();
+            if !status_line.windows(5).any(|w| w == b" 200 ") {
+                bail!(
+                    "DevTools request {} failed: {}",
+                    path,
+                    String::from_utf8_lossy(status_line)
+                );
+            }
+
+            Ok(response.split_off(body_start + 4))
+        }
+
+        async fn evaluate(&self, ws_url: &str, expression: &str) -> Result<()> {
+            let (mut ws, _) = tokio_tungstenite::connect_async(ws_url)
+                .await
+                .context("failed to connect to DevTools target")?;
+
+            let request = json!({
+                "id": 1,
+                "method": "Runtime.evaluate",
+                "params": { "expression": expression, "userGesture": true },
+            });
+            ws.send(Message::Text(request.to_string())).await?;
+
+            while let Some(msg) = ws.next().await {
+                if let Message::Text(text) = msg? {
+                    let response: Value = serde_json::from_str(&text)?;
+                    if response["id"] != 1 {
+                        continue;
+                    }
+
+                    if let Some(exception) = response["result"].get("exceptionDetails") {
+                        bail!("script failed in tab: {}", exception);
+                    }
+
+                    return Ok(());
+                }
+            }
+
+            bail!("DevTools closed the connection")
+        }
+    }
+
+    impl Default for DevTools {
+        fn default() -> Self {
+            Self::new("127.0.0.1:9222")
+        }
+    }
+
+    #[async_trait]
+    impl Focus for DevTools {
+        async fn focus(&self, target: &FocusTarget) -> Result<()> {
+            let targets: Vec<Target> = serde_json::from_slice(&self.get("/json/list").await?)?;
+
+            let tab = targets
+                .into_iter()
+                .find(|tab| tab.r#type == "page" && target.matches(&tab.url))
+                .context("no matching browser tab found")?;
+
+            self.get(&format!("/json/activate/{}", tab.id)).await?;
+
+            // Activating the tab doesn't always raise the window, depending on the platform
+            if let Some(title) = &target.window_title {
+                activate_window(title).await?;
+            }
+
+            if let Some(script) = &target.script {
+                let ws_url = tab
+                    .web_socket_debugger_url
+                    .context("tab is already being debugged by another client")?;
+                self.evaluate(&ws_url, script).await?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ewmh_activates_window_by_title() {
+        let ewmh = Ewmh::default();
+        let target = FocusTarget::new(&["twitch.tv"]);
+        assert_eq!(wmctrl_args(ewmh.title(&target)), ["-a", "Chrom"]);
+
+        let target = target.window_title("Firefox");
+        assert_eq!(wmctrl_args(ewmh.title(&target)), ["-a", "Firefox"]);
+
+        let ewmh = Ewmh::new("Brave");
+        assert_eq!(
+            wmctrl_args(ewmh.title(&FocusTarget::default())),
+            ["-a", "Brave"]
+        );
+    }
+
+    #[cfg(feature = "devtools")]
+    #[tokio::test]
+    async fn devtools_activates_matching_tab() {
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let tabs = serde_json::json!([
+            { "id": "worker", "type": "service_worker", "url": "https://www.twitch.tv/sw.js" },
+            { "id": "other", "type": "page", "url": "https://example.com/" },
+            { "id": "chat", "type": "page", "url": "https://www.twitch.tv/popout/chat" },
+            { "id": "stream", "type": "page", "url": "https://www.twitch.tv/" },
+        ]);
+
+        // Minimal HTTP/1.0 server that records the requested paths
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn({
+            let paths = paths.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut buf = [0; 1024];
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+
+                    let request = String::from_utf8(request).unwrap();
+                    let path = request.split(' ').nth(1).unwrap().to_owned();
+                    let response = match path.as_str() {
+                        "/json/list" => format!("HTTP/1.0 200 OK\r\n\r\n{}", tabs),
+                        p if p.starts_with("/json/activate/") => {
+                            "HTTP/1.0 200 OK\r\n\r\nTarget activated".to_owned()
+                        }
+                        _ => "HTTP/1.0 404 Not Found\r\n\r\n".to_owned(),
+                    };
+                    paths.lock().unwrap().push(path);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+        });
+
+        let devtools = DevTools::new(addr.to_string());
+        devtools
+            .focus(&FocusTarget::new(&["twitch.tv/popout", "twitch.tv"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec!["/json/list", "/json/activate/chat"]
+        );
+
+        paths.lock().unwrap().clear();
+        let error = devtools
+            .focus(&FocusTarget::new(&["youtube.com"]))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no matching browser tab found");
+        assert_eq!(*paths.lock().unwrap(), vec!["/json/list"]);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::model::{Button, ButtonLedState};
+use crate::output::Controller;
+use anyhow::Result;
+
+/// A set of buttons bound to mutually exclusive values, like radio buttons.
+///
+/// At most one button is active at a time, and its LED is the only one lit. The values can be
+/// anything (a VTubeStudio param value, an expression file name, a hotkey), since applying
+/// the selection is left to the caller.
+#[derive(Clone, Debug)]
+pub struct RadioGroup<T> {
+    buttons: Vec<(Button, T)>,
+    active: Option<usize>,
+    clearable: bool,
+}
+
+/// The result of pressing a button in a [`RadioGroup`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection<T> {
+    /// A new value was selected, replacing the previously active one (if any)
+    Selected { value: T, previous: Option<T> },
+    /// The active button was pressed again, so nothing is selected anymore
+    Cleared { previous: T },
+}
+
+impl<T: Clone> RadioGroup<T> {
+    pub fn new<I>(buttons: I) -> Self
+    where
+        I: IntoIterator<Item = (Button, T)>,
+    {
+        Self {
+            buttons: buttons.into_iter().collect(),
+            active: None,
+            clearable: true,
+        }
+    }
+
+    /// Whether pressing the active button clears the selection (the default). If not, it
+    /// stays selected.
+    pub fn clearable(mut self, clearable: bool) -> Self {
+        self.clearable = clearable;
+        self
+    }
+
+    pub fn contains(&self, button: Button) -> bool {
+        self.index_of(button).is_some()
+    }
+
+    pub fn active(&self) -> Option<&T> {
+        self.active.map(|index| &self.buttons[index].1)
+    }
+
+    pub fn active_button(&self) -> Option<Button> {
+        self.active.map(|index| self.buttons[index].0)
+    }
+
+    /// What pressing the button would do, without changing anything. Returns `None` if the
+    /// button isn't part of the group, or if the selection wouldn't change.
+    ///
+    /// Use this to apply a selection before [`RadioGroup::press`], so the LEDs only change
+    /// once applying it succeeded.
+    pub fn peek(&self, button: Button) -> Option<Selection<T>> {
+        let index = self.index_of(button)?;
+        let previous = self.active().cloned();
+
+        if self.active == Some(index) {
+            if !self.clearable {
+                return None;
+            }
+
+            previous.map(|previous| Selection::Cleared { previous })
+        } else {
+            Some(Selection::Selected {
+                value: self.buttons[index].1.clone(),
+                previous,
+            })
+        }
+    }
+
+    /// Handles a button press, updating the LEDs of the group. Returns `None` if the button
+    /// isn't part of the group, or if the selection didn't change.
+    pub fn press(
+        &mut self,
+        controller: &mut Controller,
+        button: Button,
+    ) -> Result<Option<Selection<T>>> {
+        let selection = match self.peek(button) {
+            Some(selection) => selection,
+            None => return Ok(None),
+        };
+
+        self.active = match selection {
+            Selection::Selected { .. } => self.index_of(button),
+            Selection::Cleared { .. } => None,
+        };
+
+        self.sync(controller)?;
+        Ok(Some(selection))
+    }
+
+    /// Selects the button bound to `button` without it being pressed, e.g. to restore state.
+    pub fn select(&mut self, controller: &mut Controller, button: Option<Button>) -> Result<()> {
+        self.active = button.and_then(|button| self.index_of(button));
+        self.sync(controller)
+    }
+
+    /// Sets the LEDs of all buttons in the group to match the active selection.
+    pub fn sync(&self, controller: &mut Controller) -> Result<()> {
+        for (index, (button, _)) in self.buttons.iter().enumerate() {
+            let state = if self.active == Some(index) {
+                ButtonLedState::On
+            } else {
+                ButtonLedState::Off
+            };
+
+            controller.set_button(*button, state)?;
+        }
+
+        Ok(())
+    }
+
+    fn index_of(&self, button: Button) -> Option<usize> {
+        self.buttons.iter().position(|(b, _)| *b == button)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> Controller {
+        let (controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+        tokio::spawn(worker);
+        controller
+    }
+
+    fn group() -> RadioGroup<&'static str> {
+        RadioGroup::new(vec![
+            (Button::Button1, "sad"),
+            (Button::Button2, "angry"),
+            (Button::Button3, "happy"),
+        ])
+    }
+
+    fn leds(controller: &Controller) -> Vec<ButtonLedState> {
+        [Button::Button1, Button::Button2, Button::Button3]
+            .iter()
+            .map(|button| *controller.state().button(*button))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn selects_and_replaces_values() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        let mut group = group();
+
+        let selection = group.press(&mut controller, Button::Button2)?;
+        assert_eq!(
+            selection,
+            Some(Selection::Selected {
+                value: "angry",
+                previous: None
+            })
+        );
+        assert_eq!(group.active(), Some(&"angry"));
+        assert_eq!(group.active_button(), Some(Button::Button2));
+        assert_eq!(leds(&controller), vec![Off, On, Off]);
+
+        let selection = group.press(&mut controller, Button::Button3)?;
+        assert_eq!(
+            selection,
+            Some(Selection::Selected {
+                value: "happy",
+                previous: Some("angry")
+            })
+        );
+        assert_eq!(leds(&controller), vec![Off, Off, On]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clears_when_pressed_again() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        let mut group = group();
+
+        group.press(&mut controller, Button::Button1)?;
+        let selection = group.press(&mut controller, Button::Button1)?;
+        assert_eq!(selection, Some(Selection::Cleared { previous: "sad" }));
+        assert_eq!(group.active(), None);
+        assert_eq!(leds(&controller), vec![Off, Off, Off]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stays_selected_unless_clearable() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        let mut group = group().clearable(false);
+
+        group.press(&mut controller, Button::Button1)?;
+        assert_eq!(group.peek(Button::Button1), None);
+        assert_eq!(group.press(&mut controller, Button::Button1)?, None);
+        assert_eq!(group.active(), Some(&"sad"));
+        assert_eq!(leds(&controller), vec![On, Off, Off]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_other_buttons() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        controller.set_button(Button::Button9, Blink)?;
+        let mut group = group();
+
+        assert!(!group.contains(Button::Button9));
+        assert_eq!(group.peek(Button::Button9), None);
+        assert_eq!(group.press(&mut controller, Button::Button9)?, None);
+
+        group.press(&mut controller, Button::Button1)?;
+        assert_eq!(*controller.state().button(Button::Button9), Blink);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peeks_without_changing_anything() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        let mut group = group();
+        group.press(&mut controller, Button::Button1)?;
+
+        assert_eq!(
+            group.peek(Button::Button2),
+            Some(Selection::Selected {
+                value: "angry",
+                previous: Some("sad")
+            })
+        );
+        assert_eq!(
+            group.peek(Button::Button1),
+            Some(Selection::Cleared { previous: "sad" })
+        );
+        assert_eq!(group.active(), Some(&"sad"));
+        assert_eq!(leds(&controller), vec![On, Off, Off]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn selects_and_syncs_without_presses() -> Result<()> {
+        use ButtonLedState::*;
+
+        let mut controller = controller();
+        let mut group = group();
+
+        group.select(&mut controller, Some(Button::Button3))?;
+        assert_eq!(group.active(), Some(&"happy"));
+        assert_eq!(leds(&controller), vec![Off, Off, On]);
+
+        // Buttons outside the group clear the selection
+        group.select(&mut controller, Some(Button::Button9))?;
+        assert_eq!(group.active(), None);
+        assert_eq!(leds(&controller), vec![Off, Off, Off]);
+
+        group.select(&mut controller, Some(Button::Button2))?;
+        group.select(&mut controller, None)?;
+        assert_eq!(group.active(), None);
+
+        // LEDs changed elsewhere are restored
+        group.select(&mut controller, Some(Button::Button1))?;
+        controller.set_button(Button::Button1, Off)?;
+        controller.set_button(Button::Button2, On)?;
+        group.sync(&mut controller)?;
+        assert_eq!(leds(&controller), vec![On, Off, Off]);
+
+        Ok(())
+    }
+}
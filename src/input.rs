@@ -1,37 +1,90 @@
+use crate::metrics;
 use crate::model::Event;
-use crate::MIDI_DEVICE_NAME;
+use crate::{is_device_port, MIDI_CLIENT_NAME, MIDI_DEVICE_NAME};
 use anyhow::{Context as _, Result};
 use futures::channel::mpsc;
+use futures::stream::BoxStream;
 use futures::task::{Context, Poll};
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use midir::{Ignore, MidiInput, MidiInputConnection};
 use pin_project_lite::pin_project;
 use std::convert::TryFrom;
 use std::pin::Pin;
+use std::time::Instant;
 use tracing::error;
 
+type Inspect = Box<dyn FnMut(&[u8], &Result<Event>) + Send>;
+
 pin_project! {
     pub struct EventStream {
-        connection: MidiInputConnection<()>,
+        connection: Option<MidiInputConnection<()>>,
         #[pin]
-        stream: mpsc::UnboundedReceiver<Result<Event>>,
+        stream: BoxStream<'static, Received>,
+        inspect: Option<Inspect>,
+    }
+}
+
+/// A MIDI message as it came in, and the event parsed from it.
+struct Received {
+    // When it arrived, to measure how long it waits to be picked up
+    at: Instant,
+    bytes: Vec<u8>,
+    event: Result<Event>,
+}
+
+impl Received {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            at: Instant::now(),
+            event: Event::try_from(bytes.as_slice()),
+            bytes,
+        }
     }
 }
 
 impl EventStream {
     pub fn new() -> Result<Self> {
+        Self::with_device_name(MIDI_DEVICE_NAME)
+    }
+
+    /// Connects to a MIDI device with a different name, such as a virtual device.
+    pub fn with_device_name(device_name: &str) -> Result<Self> {
         let (tx, rx) = mpsc::unbounded();
-        let connection = get_input_port(MIDI_DEVICE_NAME, move |event| {
-            if let Err(error) = tx.unbounded_send(event) {
+        let connection = get_input_port(device_name, move |bytes| {
+            if let Err(error) = tx.unbounded_send(Received::new(bytes.to_vec())) {
                 error!(?error, "Failed to send controller event to stream");
             }
         })?;
 
         Ok(EventStream {
-            connection,
-            stream: rx,
+            connection: Some(connection),
+            stream: rx.boxed(),
+            inspect: None,
         })
     }
+
+    /// Events parsed from MIDI messages coming from somewhere other than the device, such as a
+    /// simulator. The stream ends when `messages` does.
+    pub fn from_midi<S>(messages: S) -> Self
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        EventStream {
+            connection: None,
+            stream: messages.map(Received::new).boxed(),
+            inspect: None,
+        }
+    }
+
+    /// Calls `inspect` with each MIDI message as it was received, along with the result of
+    /// parsing it, e.g. to record input including messages that couldn't be parsed.
+    pub fn inspect_raw<F>(mut self, inspect: F) -> Self
+    where
+        F: FnMut(&[u8], &Result<Event>) + Send + 'static,
+    {
+        self.inspect = Some(Box::new(inspect));
+        self
+    }
 }
 
 impl Stream for EventStream {
@@ -42,31 +95,38 @@ impl Stream for EventStream {
         context: &mut Context,
     ) -> Poll<Option<<Self as futures::Stream>::Item>> {
         let this = self.project();
-        this.stream.poll_next(context)
+        let inspect = this.inspect;
+        this.stream.poll_next(context).map(|item| {
+            item.map(|received| {
+                metrics::global().input_latency.record_since(received.at);
+                if let Some(inspect) = inspect {
+                    inspect(&received.bytes, &received.event);
+                }
+                received.event
+            })
+        })
     }
 }
 
-fn get_input_port<F>(port_name: &str, handler: F) -> Result<MidiInputConnection<()>>
+fn get_input_port<F>(device_name: &str, handler: F) -> Result<MidiInputConnection<()>>
 where
-    F: Fn(Result<Event>) + Send + 'static,
+    F: Fn(&[u8]) + Send + 'static,
 {
-    let mut midi_in = MidiInput::new(port_name)?;
+    let mut midi_in = MidiInput::new(MIDI_CLIENT_NAME)?;
     midi_in.ignore(Ignore::None);
 
     let ports = midi_in.ports();
 
     let in_port = ports
         .iter()
-        .find(|port| midi_in.port_name(port).as_deref() == Ok(MIDI_DEVICE_NAME))
-        .with_context(|| format!("could not find device {}", MIDI_DEVICE_NAME))?;
+        .find(|port| is_device_port(midi_in.port_name(port), device_name))
+        .with_context(|| format!("could not find device {}", device_name))?;
 
     let connection = midi_in
         .connect(
             in_port,
-            port_name,
-            move |_timestamp, bytes, ()| {
-                handler(Event::try_from(bytes));
-            },
+            MIDI_CLIENT_NAME,
+            move |_timestamp, bytes, ()| handler(bytes),
             (),
         )
         .map_err(|e| midir::ConnectError::new(e.kind(), ()))?;
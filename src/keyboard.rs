@@ -1,14 +1,423 @@
-use autopilot::key::{Character, Code, KeyCode};
-const INPUT_DELAY: u64 = 0;
+use crate::backend;
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
 
-pub fn tap_key(code: KeyCode) {
-    autopilot::key::tap(&Code(code), &[], INPUT_DELAY, 0);
+mod text;
+
+pub use self::text::TextEntry;
+pub use autopilot::key::{Flag, KeyCode};
+
+static TEXT_ENTRY: Lazy<RwLock<TextEntry>> = Lazy::new(Default::default);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Key {
+    Code(KeyCode),
+    Char(char),
+}
+
+impl From<KeyCode> for Key {
+    fn from(code: KeyCode) -> Self {
+        Self::Code(code)
+    }
+}
+
+impl From<char> for Key {
+    fn from(c: char) -> Self {
+        Self::Char(c)
+    }
+}
+
+/// Something that can emulate keyboard input. See [`crate::backend`] for implementations.
+pub trait KeyboardBackend: Send + Sync {
+    /// Presses (`down = true`) or releases a key, along with the given modifiers.
+    fn toggle(&self, key: Key, down: bool, flags: &[Flag]) -> Result<()>;
+
+    fn tap(&self, key: Key, flags: &[Flag]) -> Result<()> {
+        self.toggle(key, true, flags)?;
+        self.toggle(key, false, flags)
+    }
+
+    fn type_text(&self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            self.tap(Key::Char(c), &[])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the strategy used by [`type_text`] (see [`TextEntry::platform_default`]).
+pub fn set_text_entry(entry: TextEntry) {
+    *TEXT_ENTRY.write().unwrap() = entry;
+}
+
+pub fn tap_key(code: KeyCode) -> Result<()> {
+    backend::get().tap(Key::Code(code), &[])
+}
+
+pub fn tap_char(c: char) -> Result<()> {
+    backend::get().tap(Key::Char(c), &[])
+}
+
+pub async fn type_text(text: &str) -> Result<()> {
+    let entry = TEXT_ENTRY.read().unwrap().clone();
+    entry.type_text(text).await
+}
+
+/// A key along with modifiers, e.g. `"ctrl+shift+tab"`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyCombo {
+    pub key: Key,
+    pub flags: Vec<Flag>,
+}
+
+impl KeyCombo {
+    pub fn new<K: Into<Key>>(key: K, flags: &[Flag]) -> Self {
+        Self {
+            key: key.into(),
+            flags: flags.to_vec(),
+        }
+    }
+
+    pub fn tap(&self) -> Result<()> {
+        backend::get().tap(self.key, &self.flags)
+    }
+
+    /// Holds the combo down until [`KeyCombo::release`] is called, e.g. for as long as a
+    /// button is held.
+    pub fn press(&self) -> Result<()> {
+        backend::get().toggle(self.key, true, &self.flags)
+    }
+
+    pub fn release(&self) -> Result<()> {
+        backend::get().toggle(self.key, false, &self.flags)
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('+').map(str::trim).collect::<Vec<_>>();
+
+        // Allow "ctrl++" to mean ctrl and the plus key
+        if s.trim() == "+" || s.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+
+        let key = parts
+            .pop()
+            .filter(|key| !key.is_empty())
+            .with_context(|| format!("missing key in combo {:?}", s))?;
+
+        let flags = parts
+            .iter()
+            .map(|name| parse_flag(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            key: parse_key(key)?,
+            flags,
+        })
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for flag in &self.flags {
+            write!(f, "{}+", flag_name(*flag))?;
+        }
+
+        match self.key {
+            Key::Code(code) => write!(f, "{}", format!("{:?}", code).to_lowercase()),
+            Key::Char(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+fn parse_flag(name: &str) -> Result<Flag> {
+    Ok(match name.to_lowercase().as_str() {
+        "ctrl" | "control" => Flag::Control,
+        "shift" => Flag::Shift,
+        "alt" | "option" | "opt" => Flag::Alt,
+        "meta" | "cmd" | "command" | "super" | "win" => Flag::Meta,
+        "help" => Flag::Help,
+        _ => bail!("unknown modifier {:?}", name),
+    })
+}
+
+fn flag_name(flag: Flag) -> &'static str {
+    match flag {
+        Flag::Control => "ctrl",
+        Flag::Shift => "shift",
+        Flag::Alt => "alt",
+        Flag::Meta => "meta",
+        Flag::Help => "help",
+    }
 }
 
-pub fn tap_char(c: char) {
-    autopilot::key::tap(&Character(c), &[], INPUT_DELAY, 0);
+fn parse_key(name: &str) -> Result<Key> {
+    use KeyCode::*;
+
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Char(c));
+    }
+
+    let lower = name.to_lowercase();
+    if let Some(n) = lower
+        .strip_prefix('f')
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        const FUNCTION_KEYS: [KeyCode; 24] = [
+            F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15, F16, F17, F18, F19,
+            F20, F21, F22, F23, F24,
+        ];
+
+        if let Some(code) = n.checked_sub(1).and_then(|i| FUNCTION_KEYS.get(i)) {
+            return Ok(Key::Code(*code));
+        }
+    }
+
+    let code = match lower.as_str() {
+        "tab" => Tab,
+        "space" => Space,
+        "enter" | "return" => Return,
+        "esc" | "escape" => Escape,
+        "backspace" => Backspace,
+        "delete" | "del" => Delete,
+        "home" => Home,
+        "end" => End,
+        "pageup" | "pgup" => PageUp,
+        "pagedown" | "pgdn" => PageDown,
+        "up" | "uparrow" => UpArrow,
+        "down" | "downarrow" => DownArrow,
+        "left" | "leftarrow" => LeftArrow,
+        "right" | "rightarrow" => RightArrow,
+        "capslock" => CapsLock,
+        "ctrl" | "control" => Control,
+        "shift" => Shift,
+        "alt" => Alt,
+        "meta" => Meta,
+        "plus" => return Ok(Key::Char('+')),
+        "comma" => return Ok(Key::Char(',')),
+        _ => bail!("unknown key {:?}", name),
+    };
+
+    Ok(Key::Code(code))
 }
 
-pub fn type_text(text: &str) {
-    autopilot::key::type_string(text, &[], 0., 0.);
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Tap(KeyCombo),
+    Press(KeyCombo),
+    Release(KeyCombo),
+    Text(String),
+    Delay(Duration),
+}
+
+/// A macro of key presses, text and delays, played back in order.
+///
+/// Can be parsed from a comma-separated list of steps, where quoted steps are typed as text
+/// and steps like `100ms` are delays, e.g. `ctrl+l, 50ms, "example.com", enter`. The comma
+/// key can be used as a step of its own or after a modifier, e.g. `,` or `ctrl+,`, or be
+/// written as `comma`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sequence {
+    steps: Vec<Step>,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tap(mut self, combo: KeyCombo) -> Self {
+        self.steps.push(Step::Tap(combo));
+        self
+    }
+
+    pub fn press(mut self, combo: KeyCombo) -> Self {
+        self.steps.push(Step::Press(combo));
+        self
+    }
+
+    pub fn release(mut self, combo: KeyCombo) -> Self {
+        self.steps.push(Step::Release(combo));
+        self
+    }
+
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.steps.push(Step::Text(text.into()));
+        self
+    }
+
+    pub fn delay(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Delay(duration));
+        self
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub async fn play(&self) -> Result<()> {
+        for step in &self.steps {
+            match step {
+                Step::Tap(combo) => combo.tap()?,
+                Step::Press(combo) => combo.press()?,
+                Step::Release(combo) => combo.release()?,
+                Step::Text(text) => type_text(text).await?,
+                Step::Delay(duration) => tokio::time::sleep(*duration).await,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Sequence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut steps = Vec::new();
+        let mut rest = s.trim();
+
+        while !rest.is_empty() {
+            let (step, remaining) = if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted
+                    .find('"')
+                    .with_context(|| format!("unterminated text in sequence {:?}", s))?;
+                let remaining = quoted[end + 1..].trim_start();
+                (Step::Text(quoted[..end].to_owned()), remaining)
+            } else {
+                let end = step_end(rest);
+                let part = rest[..end].trim();
+
+                let step = match part.strip_suffix("ms").map(str::parse::<u64>) {
+                    Some(Ok(ms)) => Step::Delay(Duration::from_millis(ms)),
+                    _ => Step::Tap(part.parse()?),
+                };
+
+                (step, &rest[end..])
+            };
+
+            steps.push(step);
+
+            rest = remaining.trim_start();
+            if let Some(remaining) = rest.strip_prefix(',') {
+                rest = remaining.trim_start();
+            } else if !rest.is_empty() {
+                bail!("expected ',' in sequence {:?}", s);
+            }
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+/// Where the step at the start of `s` ends. A comma at the start of a step or after a
+/// modifier is the comma key, as in `,` or `ctrl+,`, while one after the plus key (`ctrl++,`)
+/// ends the step.
+fn step_end(s: &str) -> usize {
+    let bytes = s.as_bytes();
+
+    (1..bytes.len())
+        .find(|&i| {
+            let is_key = i >= 2 && bytes[i - 1] == b'+' && bytes[i - 2] != b'+';
+            bytes[i] == b',' && !is_key
+        })
+        .unwrap_or(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combo(s: &str) -> KeyCombo {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_combos() {
+        use Flag::*;
+
+        assert_eq!(combo("a"), KeyCombo::new('a', &[]));
+        assert_eq!(
+            combo("Ctrl + Shift + Tab"),
+            KeyCombo::new(KeyCode::Tab, &[Control, Shift])
+        );
+        assert_eq!(combo("cmd+f12"), KeyCombo::new(KeyCode::F12, &[Meta]));
+        assert_eq!(combo("ctrl++"), KeyCombo::new('+', &[Control]));
+        assert_eq!(combo("+"), KeyCombo::new('+', &[]));
+        assert_eq!(combo("ctrl+plus"), KeyCombo::new('+', &[Control]));
+        assert_eq!(combo("ctrl+,"), KeyCombo::new(',', &[Control]));
+        assert_eq!(combo("alt+comma"), KeyCombo::new(',', &[Alt]));
+
+        for invalid in &["", "ctrl+", "hyper+a", "f25", "notakey"] {
+            assert!(invalid.parse::<KeyCombo>().is_err(), "parsed {:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn displays_combos_that_parse_back() {
+        let combos = [
+            "a",
+            "ctrl+shift+tab",
+            "alt+f4",
+            "meta+space",
+            "ctrl++",
+            "+",
+            "ctrl+,",
+            "shift+pageup",
+            "return",
+            "escape",
+            "uparrow",
+        ];
+
+        for s in combos.iter() {
+            let parsed = combo(s);
+            assert_eq!(&parsed.to_string(), s);
+            assert_eq!(combo(&parsed.to_string()), parsed);
+        }
+    }
+
+    #[test]
+    fn parses_sequences() {
+        let sequence = r#"ctrl+l, 50ms, "example.com, or not", enter"#.parse::<Sequence>().unwrap();
+        let expected = Sequence::new()
+            .tap(combo("ctrl+l"))
+            .delay(Duration::from_millis(50))
+            .text("example.com, or not")
+            .tap(combo("enter"));
+        assert_eq!(sequence, expected);
+
+        assert_eq!("".parse::<Sequence>().unwrap(), Sequence::new());
+    }
+
+    #[test]
+    fn parses_commas_in_sequences() {
+        let sequence = ",, ctrl+,, a, ctrl++, alt+comma"
+            .parse::<Sequence>()
+            .unwrap();
+        let expected = Sequence::new()
+            .tap(combo(","))
+            .tap(combo("ctrl+,"))
+            .tap(combo("a"))
+            .tap(combo("ctrl++"))
+            .tap(combo("alt+,"));
+        assert_eq!(sequence, expected);
+    }
+
+    #[test]
+    fn rejects_invalid_sequences() {
+        for invalid in &[r#""unterminated"#, r#""text" enter"#, "a,, b", "ctrl+"] {
+            assert!(invalid.parse::<Sequence>().is_err(), "parsed {:?}", invalid);
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use super::{Flag, KeyCode, KeyCombo};
+use crate::backend;
+use anyhow::{bail, Context, Result};
+use std::process::Stdio;
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// How text (especially non-ASCII text like emoji) is entered.
+///
+/// Typing arbitrary Unicode through synthetic key events only works on some platforms, so
+/// there are a few alternatives for when it doesn't.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextEntry {
+    /// Type each character through the keyboard backend
+    Direct,
+    /// Type non-ASCII characters with the IBus/GTK Unicode sequence (ctrl+shift+u, the
+    /// hex code point, then space). ASCII characters are typed directly.
+    UnicodeHex,
+    /// Copy the text to the clipboard with a command (which reads it from stdin), then paste
+    Clipboard {
+        copy_command: Vec<String>,
+        paste: KeyCombo,
+    },
+    /// Run an xdotool-compatible command with the text as the last argument, e.g.
+    /// `xdotool type --` or `wtype --`
+    Command(Vec<String>),
+}
+
+impl TextEntry {
+    /// The strategy most likely to work on the current platform. Typing Unicode directly
+    /// doesn't work on Linux, so the IBus/GTK sequence is used there.
+    pub fn platform_default() -> Self {
+        if cfg!(target_os = "linux") {
+            Self::UnicodeHex
+        } else {
+            Self::Direct
+        }
+    }
+
+    /// Clipboard strategy using the usual clipboard tool for the platform.
+    pub fn clipboard() -> Self {
+        let (copy_command, paste): (&[&str], _) = if cfg!(target_os = "macos") {
+            (&["pbcopy"], KeyCombo::new('v', &[Flag::Meta]))
+        } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            (&["wl-copy"], KeyCombo::new('v', &[Flag::Control]))
+        } else {
+            (
+                &["xclip", "-selection", "clipboard"],
+                KeyCombo::new('v', &[Flag::Control]),
+            )
+        };
+
+        Self::Clipboard {
+            copy_command: copy_command.iter().map(|s| s.to_string()).collect(),
+            paste,
+        }
+    }
+
+    pub async fn type_text(&self, text: &str) -> Result<()> {
+        match self {
+            Self::Direct => backend::get().type_text(text),
+            Self::UnicodeHex => {
+                let backend = backend::get();
+                let start = [Flag::Control, Flag::Shift];
+
+                for c in text.chars() {
+                    if c.is_ascii() {
+                        backend.tap(c.into(), &[])?;
+                    } else {
+                        backend.tap('u'.into(), &start)?;
+                        backend.type_text(&format!("{:x}", c as u32))?;
+                        backend.tap(KeyCode::Space.into(), &[])?;
+                    }
+                }
+
+                Ok(())
+            }
+            Self::Clipboard {
+                copy_command,
+                paste,
+            } => {
+                let (program, args) = copy_command
+                    .split_first()
+                    .context("clipboard command is empty")?;
+
+                let mut child = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("failed to run {}", program))?;
+
+                // Dropped once written, so the command sees the end of the input
+                let mut stdin = child
+                    .stdin
+                    .take()
+                    .context("failed to open clipboard command stdin")?;
+                stdin.write_all(text.as_bytes()).await?;
+                drop(stdin);
+
+                let status = child.wait().await?;
+                if !status.success() {
+                    bail!("{} exited with {}", program, status);
+                }
+
+                paste.tap()
+            }
+            Self::Command(command) => {
+                let (program, args) = command.split_first().context("command is empty")?;
+
+                let status = Command::new(program)
+                    .args(args)
+                    .arg(text)
+                    .status()
+                    .await
+                    .with_context(|| format!("failed to run {}", program))?;
+
+                if !status.success() {
+                    bail!("{} exited with {}", program, status);
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for TextEntry {
+    fn default() -> Self {
+        Self::platform_default()
+    }
+}
+
+impl FromStr for TextEntry {
+    type Err = anyhow::Error;
+
+    /// Parses one of `direct`, `unicode-hex`, `clipboard`, `xdotool`, `wtype`, or
+    /// `command:<program> <args...>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let command =
+            |args: &str| Self::Command(args.split_whitespace().map(String::from).collect());
+
+        Ok(match s {
+            "direct" => Self::Direct,
+            "unicode-hex" => Self::UnicodeHex,
+            "clipboard" => Self::clipboard(),
+            "xdotool" => command("xdotool type --"),
+            "wtype" => command("wtype --"),
+            _ => match s.strip_prefix("command:") {
+                Some(args) if !args.trim().is_empty() => command(args),
+                _ => bail!("unknown text entry strategy {:?}", s),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!("direct".parse::<TextEntry>().unwrap(), TextEntry::Direct);
+        assert_eq!(
+            "unicode-hex".parse::<TextEntry>().unwrap(),
+            TextEntry::UnicodeHex
+        );
+        assert_eq!(
+            "clipboard".parse::<TextEntry>().unwrap(),
+            TextEntry::clipboard()
+        );
+        assert_eq!(
+            "wtype".parse::<TextEntry>().unwrap(),
+            TextEntry::Command(strings(&["wtype", "--"]))
+        );
+        assert_eq!(
+            "command:ydotool type  --".parse::<TextEntry>().unwrap(),
+            TextEntry::Command(strings(&["ydotool", "type", "--"]))
+        );
+
+        assert!("command:".parse::<TextEntry>().is_err());
+        assert!("command: ".parse::<TextEntry>().is_err());
+        assert!("typewriter".parse::<TextEntry>().is_err());
+    }
+}
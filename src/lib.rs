@@ -1,14 +1,60 @@
+#[cfg(feature = "api")]
+pub mod api;
+pub mod audio;
+pub mod backend;
+pub mod bridge;
+pub mod exec;
+pub mod focus;
+mod group;
 mod input;
 pub mod keyboard;
+pub mod metrics;
 mod model;
+pub mod mouse;
+#[cfg(feature = "mpris")]
+pub mod mpris;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "notify")]
+pub mod notify;
+#[cfg(feature = "obs")]
+pub mod obs;
+#[cfg(feature = "osc")]
+pub mod osc;
 mod output;
+pub mod record;
+pub mod router;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+pub mod status;
+#[cfg(unix)]
+pub mod virtual_ports;
 pub mod vtubestudio;
 
+pub use crate::group::{RadioGroup, Selection};
 pub use crate::input::EventStream;
 pub use crate::model::{
     Button, ButtonLedState, ControllerState, Event, FaderValue, Knob, KnobLedStyle, KnobLedValue,
     KnobState,
 };
 pub use crate::output::{Command, Controller};
+pub use crate::router::{EventHandler, Router};
 
 const MIDI_DEVICE_NAME: &'static str = "X-TOUCH MINI";
+
+// Kept distinct from the device name, so our own ports are never mistaken for the device
+const MIDI_CLIENT_NAME: &str = "xtouchmini";
+
+/// ALSA port names include the client name and port numbers, e.g.
+/// `X-TOUCH MINI:X-TOUCH MINI MIDI 1 20:0`, while other platforms use the device name.
+fn is_device_port<E>(port_name: std::result::Result<String, E>, device_name: &str) -> bool {
+    match port_name {
+        Ok(port_name) => {
+            port_name == device_name
+                || matches!(port_name.strip_prefix(device_name), Some(rest) if rest.starts_with(':'))
+        }
+        Err(_) => false,
+    }
+}
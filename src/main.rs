@@ -1,99 +1,497 @@
 use anyhow::Result;
-use autopilot::key::Flag::{Control, Meta, Shift};
-use autopilot::key::{Code, KeyCode};
-use futures::StreamExt;
-use std::time::{Duration, SystemTime};
-use tracing::{debug, error};
-use xtouchmini::keyboard;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::time::Duration;
+use strum::IntoEnumIterator;
+use tracing::error;
+use xtouchmini::backend;
+use xtouchmini::bridge::{Bridge, BridgeConfig};
+use xtouchmini::focus::{self, FocusTarget};
+use xtouchmini::keyboard::{self, KeyCode, KeyCombo};
+use xtouchmini::mouse;
+use xtouchmini::router::{
+    Gesture, HasController, Layer, Logging, Middleware, Next, RateLimit, Route,
+};
+use xtouchmini::status::{Indicator, Source, StatusIndicators};
 use xtouchmini::vtubestudio::Param;
 use xtouchmini::*;
 
 struct Context {
     controller: Controller,
     vtube: vtubestudio::Client,
+    #[cfg(feature = "plugin")]
+    plugin: vtubestudio::plugin::Client,
+    expressions: RadioGroup<f64>,
+    status: StatusIndicators,
+    mixer: Option<audio::Mixer>,
+    #[cfg(feature = "mpris")]
+    mpris: Option<mpris::Mpris>,
+    #[cfg(feature = "notify")]
+    notifier: Option<notify::Notifier>,
+    #[cfg(feature = "scripting")]
+    script: Option<script::ScriptEngine>,
+}
+
+impl HasController for Context {
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+
+    fn controller_mut(&mut self) -> &mut Controller {
+        &mut self.controller
+    }
+}
+
+impl Context {
+    /// Shows a parameter's new value, e.g. `Layer A: CheekPuff 0.42`.
+    #[cfg_attr(not(feature = "notify"), allow(unused_variables))]
+    fn show_param(&self, layer: Layer, param: Param, value: f64) {
+        #[cfg(feature = "notify")]
+        if let Some(notifier) = &self.notifier {
+            notifier.value(layer, param.as_ref(), value);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let simulate = std::env::var_os("XTOUCHMINI_SIMULATOR").is_some();
 
-    let (mut controller, worker) = Controller::new()?;
+    let (mut controller, stream) = if simulate {
+        start_simulator()?
+    } else {
+        tracing_subscriber::fmt::init();
 
-    tokio::spawn(worker);
-    let mut stream = EventStream::new()?;
+        let (controller, worker) = Controller::new()?;
+        tokio::spawn(worker);
+        (controller, EventStream::new()?)
+    };
+
+    // Emulate input without X11 (e.g. on Wayland) with `XTOUCHMINI_INPUT_BACKEND=uinput`
+    if let Ok(name) = std::env::var("XTOUCHMINI_INPUT_BACKEND") {
+        backend::select(&name)?;
+    }
+
+    // Enter emoji some other way, e.g. `XTOUCHMINI_TEXT_ENTRY=clipboard` (see `TextEntry`)
+    if let Ok(entry) = std::env::var("XTOUCHMINI_TEXT_ENTRY") {
+        keyboard::set_text_entry(entry.parse()?);
+    }
+
+    // Record events for reproducing bugs, e.g. `XTOUCHMINI_RECORD=events.jsonl`
+    let mut stream = match std::env::var_os("XTOUCHMINI_RECORD") {
+        Some(path) => record::Recorder::create(path)?
+            .wrap_input(stream)
+            .boxed_local(),
+        None => stream.boxed_local(),
+    };
+
+    // Log latencies and throughput, e.g. every 10 seconds with `XTOUCHMINI_METRICS_LOG=10`
+    if let Ok(seconds) = std::env::var("XTOUCHMINI_METRICS_LOG") {
+        let interval = match seconds.parse()? {
+            0 => anyhow::bail!("XTOUCHMINI_METRICS_LOG must be at least 1 second"),
+            seconds => Duration::from_secs(seconds),
+        };
+        tokio::spawn(metrics::log_every(interval));
+    }
 
     let vtube_addr = "127.0.0.1:25565".parse()?;
     let mut vtube = vtubestudio::Client::new(vtube_addr);
 
-    if vtube.connect().await.is_ok() {
-        controller.set_button(Button::Button16, ButtonLedState::On)?;
+    let mut status =
+        StatusIndicators::new().source(Source::VTubeStudio, Indicator::button(Button::Button16));
+    let connected = vtube.connect().await.is_ok();
+    status.set(&mut controller, &Source::VTubeStudio, connected)?;
+
+    // Hotkeys are triggered by name through the plugin API, which VTubeStudio asks the user
+    // to approve once. Save the token as `XTOUCHMINI_VTUBE_TOKEN` to skip that next time.
+    #[cfg(feature = "plugin")]
+    let plugin = {
+        let mut plugin =
+            vtubestudio::plugin::Client::new("ws://localhost:8001", "xtouchmini", "walfie");
+        if let Ok(token) = std::env::var("XTOUCHMINI_VTUBE_TOKEN") {
+            plugin = plugin.token(token);
+        }
+
+        match plugin.connect().await {
+            Ok(()) => {
+                tracing::info!(token = ?plugin.auth_token(), "Connected to the VTubeStudio plugin API")
+            }
+            Err(error) => error!(?error, "Failed to connect to the VTubeStudio plugin API"),
+        }
+        plugin
+    };
+
+    let expressions = RadioGroup::new(vec![
+        (Button::Button1, 1.0), // Sad
+        (Button::Button2, 2.0), // Angry
+        (Button::Button3, 3.0), // Shock
+        (Button::Button4, 4.0), // Smug
+        (Button::Button5, 5.0), // Excited
+        (Button::Button6, 6.0), // Crying
+    ]);
+
+    // Desktop notifications for layers, hotkeys and values, e.g. `XTOUCHMINI_NOTIFY=1`
+    #[cfg(feature = "notify")]
+    let notifier = match std::env::var_os("XTOUCHMINI_NOTIFY") {
+        Some(_) => {
+            let (notifier, sender) = notify::Config::new().connect().await?;
+            tokio::spawn(sender);
+
+            let hotkey = |button| {
+                Route::button(button)
+                    .layer(Layer::A)
+                    .gesture(Gesture::Press)
+            };
+            Some(
+                notifier
+                    .binding(hotkey(Button::Button7), "Dance")
+                    .binding(hotkey(Button::Button8), "Dab")
+                    .binding(hotkey(Button::Button10), "Sunglasses")
+                    .binding(hotkey(Button::Button16), "Reset"),
+            )
+        }
+        None => None,
+    };
+
+    // Volume mixer on layer B, e.g. `XTOUCHMINI_AUDIO=firefox,spotify`. Knob 1 controls the
+    // default sink, and the rest each control an application, in order.
+    let (mixer, mut audio_changes) = match std::env::var("XTOUCHMINI_AUDIO") {
+        Ok(applications) => {
+            let mut mixer = audio::Mixer::new()
+                .channel(audio::Channel::new(audio::Target::DefaultSink).knob(Knob::Knob1));
+            let applications = applications
+                .split(',')
+                .map(str::trim)
+                .filter(|application| !application.is_empty());
+            for (application, knob) in applications.zip(Knob::iter().skip(1)) {
+                mixer = mixer.channel(
+                    audio::Channel::new(audio::Target::application(application)).knob(knob),
+                );
+            }
+
+            if let Err(error) = mixer.sync(&mut controller).await {
+                error!(?error, "Failed to read audio levels");
+            }
+            (Some(mixer), audio::Mixer::changes()?)
+        }
+        Err(_) => (None, stream::pending().boxed()),
+    };
+
+    // Media player buttons on layer B, e.g. `XTOUCHMINI_MPRIS=spotify`, or
+    // `XTOUCHMINI_MPRIS=recent` for whichever player most recently started playing
+    #[cfg(feature = "mpris")]
+    let (mpris, mut mpris_changes) = match std::env::var("XTOUCHMINI_MPRIS") {
+        Ok(player) => {
+            let target = match player.as_str() {
+                "recent" => mpris::Target::Recent,
+                player => mpris::Target::player(player),
+            };
+            let mpris = mpris::Mpris::connect()
+                .await?
+                .target(target)
+                .button(Button::Button9, mpris::Action::Previous)
+                .button(Button::Button10, mpris::Action::PlayPause)
+                .button(Button::Button11, mpris::Action::Next);
+
+            if let Err(error) = mpris.sync(&mut controller).await {
+                error!(?error, "Failed to read media player state");
+            }
+            let changes = mpris.changes().await?;
+            (Some(mpris), changes)
+        }
+        Err(_) => (None, stream::pending().boxed()),
+    };
+    #[cfg(not(feature = "mpris"))]
+    let mut mpris_changes = stream::pending::<()>();
+
+    // Event handlers in a Rhai script, reloaded when it changes, e.g.
+    // `XTOUCHMINI_SCRIPT=handlers.rhai`
+    #[cfg(feature = "scripting")]
+    let script = match std::env::var_os("XTOUCHMINI_SCRIPT") {
+        Some(path) => Some(script::ScriptEngine::new(path)?),
+        None => None,
+    };
+
+    let mut context = Context {
+        controller,
+        vtube,
+        #[cfg(feature = "plugin")]
+        plugin,
+        expressions,
+        status,
+        mixer,
+        #[cfg(feature = "mpris")]
+        mpris,
+        #[cfg(feature = "notify")]
+        notifier,
+        #[cfg(feature = "scripting")]
+        script,
+    };
+
+    let mut router = Router::new().middleware(Logging);
+
+    #[cfg(feature = "notify")]
+    if let Some(notifier) = &context.notifier {
+        router = router.middleware(notifier.clone());
     }
 
-    let mut context = Context { controller, vtube };
-    let mut timestamp = SystemTime::UNIX_EPOCH;
+    #[cfg(feature = "scripting")]
+    if context.script.is_some() {
+        router = router.middleware(RunScript);
+    }
 
-    while let Some(event_opt) = stream.next().await {
-        if let Ok(event) = event_opt {
-            let now = SystemTime::now();
-            let timestamp_delta = now.duration_since(timestamp)?;
+    // LED changes from integrations, applied in the main loop
+    let mut led_commands = Vec::new();
 
-            let is_connected = context.vtube.is_connected();
+    // Forward events to a DAW or other MIDI software, e.g. `XTOUCHMINI_BRIDGE=bridge.json`
+    if let Some(path) = std::env::var_os("XTOUCHMINI_BRIDGE") {
+        let (bridge, feedback) = Bridge::connect(BridgeConfig::load(path)?)?;
+        router = router.middleware(bridge);
+        led_commands.push(feedback.boxed());
+    }
 
-            debug!(event = ?event);
+    // Publish events over OSC, e.g. `XTOUCHMINI_OSC=127.0.0.1:9000`
+    #[cfg(feature = "osc")]
+    if let Ok(target) = std::env::var("XTOUCHMINI_OSC") {
+        let listen =
+            std::env::var("XTOUCHMINI_OSC_LISTEN").unwrap_or_else(|_| "127.0.0.1:8000".into());
+        let osc = osc::Osc::bind(listen.parse()?)
+            .await?
+            .target(target.parse()?);
+
+        led_commands.push(osc.incoming());
+        router = router.middleware(osc);
+    }
 
-            let result = match event {
-                Event::KnobTurned { knob, delta } => {
-                    handle_knob(
-                        &mut context,
-                        timestamp_delta,
-                        knob,
-                        &KnobAction::Turned { delta },
-                    )
-                    .await
+    // Local API for overlays, e.g. `XTOUCHMINI_API_PORT=8420`
+    #[cfg(feature = "api")]
+    let api = match std::env::var("XTOUCHMINI_API_PORT") {
+        Ok(port) => {
+            let mut server = api::Server::new(port.parse()?);
+            if let Ok(token) = std::env::var("XTOUCHMINI_API_TOKEN") {
+                server = server.token(token);
+            }
+
+            let (api, commands, server) = server.bind(context.controller.state().clone())?;
+            tokio::spawn(server);
+            led_commands.push(commands);
+            router = router.middleware(api.clone());
+            Some(api)
+        }
+        Err(_) => None,
+    };
+
+    // Home automation, e.g. `XTOUCHMINI_MQTT=localhost:1883`
+    #[cfg(feature = "mqtt")]
+    let mqtt = match std::env::var("XTOUCHMINI_MQTT") {
+        Ok(addr) => {
+            let (host, port) = match addr.rsplit_once(':') {
+                Some((host, port)) => (host.to_owned(), port.parse()?),
+                None => (addr, 1883),
+            };
+            let device =
+                std::env::var("XTOUCHMINI_MQTT_DEVICE").unwrap_or_else(|_| "default".into());
+
+            let (mqtt, commands, connection) = mqtt::Config::new(host, port, device).connect();
+            tokio::spawn(connection);
+            led_commands.push(commands);
+            router = router.middleware(mqtt.clone());
+            Some(mqtt)
+        }
+        Err(_) => None,
+    };
+
+    let mut led_commands = stream::select_all(led_commands);
+
+    // Layer A uses the knob for VTubeStudio params, and the mixer uses it for volume on layer
+    // B, both of which should follow every turn
+    let mut rate_limit = RateLimit::new(Duration::from_millis(40)).only(
+        Route::knob(Knob::Knob1)
+            .layer(Layer::Default)
+            .gesture(Gesture::Turn),
+    );
+    if context.mixer.is_none() {
+        rate_limit = rate_limit.only(
+            Route::knob(Knob::Knob1)
+                .layer(Layer::B)
+                .gesture(Gesture::Turn),
+        );
+    }
+
+    let router = router
+        .middleware(TrackControls)
+        .middleware(rate_limit)
+        .route(
+            Route::button(Button::LayerA).gesture(Gesture::Press),
+            toggle_layer,
+        )
+        .route(
+            Route::button(Button::LayerB).gesture(Gesture::Press),
+            toggle_layer,
+        )
+        .route(
+            Route::buttons().layer(Layer::A).gesture(Gesture::Press),
+            handle_button_layer_a,
+        )
+        .route(
+            Route::buttons().layer(Layer::B).gesture(Gesture::Press),
+            handle_button_layer_b,
+        )
+        .route(
+            Route::buttons().gesture(Gesture::Press),
+            handle_button_default,
+        )
+        .route(Route::knobs().layer(Layer::A), handle_knob_layer_a)
+        .route(Route::knobs().layer(Layer::B), handle_knob_layer_b)
+        .route(Route::knobs(), handle_knob_default)
+        .route(Route::fader(), handle_fader);
+
+    let mut ticks = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        tokio::select! {
+            event_opt = stream.next() => {
+                let event = match event_opt {
+                    Some(Ok(event)) => event,
+                    Some(Err(_)) => continue,
+                    None => break,
+                };
+
+                // Sources that recover show up as healthy again on the next tick
+                if let Err(error) = router.dispatch(&mut context, &event).await {
+                    error!(?error);
+                    context.status.report_error(&mut context.controller, &error)?;
                 }
-                Event::KnobPressed { knob, is_down } => {
-                    handle_knob(
-                        &mut context,
-                        timestamp_delta,
-                        knob,
-                        &KnobAction::Pressed { is_down },
-                    )
-                    .await
+            }
+            Some(command) = led_commands.next() => context.controller.apply(command)?,
+            Some(()) = audio_changes.next() => {
+                if let Some(mixer) = &context.mixer {
+                    if let Err(error) = mixer.sync(&mut context.controller).await {
+                        error!(?error, "Failed to read audio levels");
+                    }
                 }
-                Event::ButtonPressed { button, is_down } => {
-                    handle_button(&mut context, button, is_down).await
+            }
+            Some(()) = mpris_changes.next() => {
+                #[cfg(feature = "mpris")]
+                if let Some(mpris) = &context.mpris {
+                    if let Err(error) = mpris.sync(&mut context.controller).await {
+                        error!(?error, "Failed to read media player state");
+                    }
                 }
-                Event::FaderMoved { value } => handle_fader(&mut context, value).await,
-            };
-
-            // On error, disable the last button to indicate that VTubeStudio failed
-            // TODO: Add specific error variant
-            if let Err(error) = result {
-                error!(?error);
-                context
-                    .controller
-                    .set_button(Button::Button16, ButtonLedState::Off)?;
-            } else if !is_connected && context.vtube.is_connected() {
-                context
-                    .controller
-                    .set_button(Button::Button16, ButtonLedState::On)?;
             }
+            _ = ticks.tick() => context.status.tick(&mut context.controller)?,
+        }
 
-            timestamp = now;
+        // LEDs also change outside of event handlers
+        #[cfg(feature = "api")]
+        if let Some(api) = &api {
+            api.sync(context.controller.state());
+        }
+
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &mqtt {
+            if let Err(error) = mqtt.sync(context.controller.state()).await {
+                error!(?error, "Failed to publish state over MQTT");
+            }
         }
     }
 
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-pub enum KnobAction {
-    Turned { delta: i32 },
-    Pressed { is_down: bool },
+#[cfg(feature = "simulator")]
+fn start_simulator() -> Result<(Controller, EventStream)> {
+    simulator::init_logging()?;
+
+    let (simulator, stream, controller, worker) = simulator::Simulator::new()?;
+    tokio::spawn(worker);
+
+    // The event stream ends when the simulator quits, which ends the main loop
+    tokio::spawn(async move {
+        if let Err(error) = simulator.run().await {
+            error!(?error, "Simulator failed");
+        }
+    });
+
+    Ok((controller, stream))
+}
+
+#[cfg(not(feature = "simulator"))]
+fn start_simulator() -> Result<(Controller, EventStream)> {
+    anyhow::bail!("the simulator requires the `simulator` feature")
+}
+
+/// Keeps the controller state in sync with knobs and the fader, once an event is handled.
+struct TrackControls;
+
+#[async_trait]
+impl Middleware<Context> for TrackControls {
+    async fn handle(
+        &self,
+        context: &mut Context,
+        event: &Event,
+        next: Next<'_, Context>,
+    ) -> Result<()> {
+        next.run(context, event).await?;
+
+        match *event {
+            Event::KnobTurned { knob, delta } => context.controller.apply_knob_diff(knob, delta),
+            Event::FaderMoved { value } => context.controller.set_fader(value),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the script's handler for every event, along with the built-in handlers.
+#[cfg(feature = "scripting")]
+struct RunScript;
+
+#[cfg(feature = "scripting")]
+#[async_trait]
+impl Middleware<Context> for RunScript {
+    async fn handle(
+        &self,
+        context: &mut Context,
+        event: &Event,
+        next: Next<'_, Context>,
+    ) -> Result<()> {
+        let result = match &mut context.script {
+            Some(script) => {
+                script
+                    .handle(
+                        event,
+                        &mut context.controller,
+                        Some(&mut context.vtube),
+                        Some(&mut context.plugin),
+                    )
+                    .await
+            }
+            None => Ok(()),
+        };
+
+        // A failing script doesn't stop the built-in handlers
+        let handled = next.run(context, event).await;
+        result.and(handled)
+    }
 }
 
-async fn handle_fader(context: &mut Context, value: FaderValue) -> Result<()> {
-    fn type_string_or_backspace(string: &str, prev: FaderValue, current: FaderValue, friction: u8) {
+async fn toggle_layer(context: &mut Context, event: &Event) -> Result<()> {
+    if let Event::ButtonPressed { button, .. } = *event {
+        context.controller.negate_button(button)?;
+    }
+
+    Ok(())
+}
+
+async fn handle_fader(context: &mut Context, event: &Event) -> Result<()> {
+    fn type_string_or_backspace(
+        string: &str,
+        prev: FaderValue,
+        current: FaderValue,
+        friction: u8,
+    ) -> Result<()> {
         let friction = friction.max(1);
         let current = current.0 / friction;
         let prev = prev.0 / friction;
@@ -102,72 +500,72 @@ async fn handle_fader(context: &mut Context, value: FaderValue) -> Result<()> {
             let last = if let Some(c) = string.chars().last() {
                 c
             } else {
-                return;
+                return Ok(());
             };
 
             for i in prev..current {
                 let c = string.chars().nth(i as usize).unwrap_or(last);
-                keyboard::tap_char(c);
+                keyboard::tap_char(c)?;
             }
         } else {
             for _ in 0..(prev - current) {
-                keyboard::tap_key(KeyCode::Backspace);
+                keyboard::tap_key(KeyCode::Backspace)?;
             }
         }
+
+        Ok(())
     }
 
+    let value = match *event {
+        Event::FaderMoved { value } => value,
+        _ => return Ok(()),
+    };
+
     if !context.controller.state().button(Button::LayerA).is_on() {
         let prev = context.controller.state().fader();
-        type_string_or_backspace("Let's go", *prev, value, 3);
+        type_string_or_backspace("Let's go", *prev, value, 3)?;
     }
 
-    context.controller.set_fader(value);
     Ok(())
 }
 
-async fn handle_knob(
-    context: &mut Context,
-    timestamp_delta: Duration,
-    knob: Knob,
-    action: &KnobAction,
-) -> Result<()> {
-    if context.controller.state().button(Button::LayerA).is_on() {
-        handle_knob_layer_a(context, timestamp_delta, knob, action).await?;
-    } else {
-        handle_knob_default(context, timestamp_delta, knob, action).await?;
-    }
+#[derive(Clone, Debug)]
+pub enum KnobAction {
+    Turned { delta: i32 },
+    Pressed { is_down: bool },
+}
 
-    if let KnobAction::Turned { delta } = action {
-        context.controller.apply_knob_diff(knob, *delta)
+fn knob_action(event: &Event) -> Option<(Knob, KnobAction)> {
+    match *event {
+        Event::KnobTurned { knob, delta } => Some((knob, KnobAction::Turned { delta })),
+        Event::KnobPressed { knob, is_down } => Some((knob, KnobAction::Pressed { is_down })),
+        _ => None,
     }
-
-    Ok(())
 }
 
-async fn handle_knob_default(
-    _context: &mut Context,
-    timestamp_delta: Duration,
-    knob: Knob,
-    action: &KnobAction,
-) -> Result<()> {
+async fn handle_knob_default(_context: &mut Context, event: &Event) -> Result<()> {
     use KnobAction::*;
 
-    match (knob, action) {
-        (Knob::Knob1, Turned { delta }) if timestamp_delta.as_millis() > 40 => {
-            if *delta > 0 {
-                autopilot::key::tap(&Code(KeyCode::Tab), &[Control], 0, 0);
+    let (knob, action) = match knob_action(event) {
+        Some(knob_action) => knob_action,
+        None => return Ok(()),
+    };
+
+    match (knob, &action) {
+        (Knob::Knob1, Turned { delta }) => {
+            let combo = if *delta > 0 {
+                "ctrl+tab"
             } else {
-                autopilot::key::tap(&Code(KeyCode::Tab), &[Control, Shift], 0, 0);
-            }
+                "ctrl+shift+tab"
+            };
+
+            combo.parse::<KeyCombo>()?.tap()?;
         }
         (Knob::Knob2, Turned { delta }) => {
-            use autopilot::mouse::ScrollDirection::{Down, Up};
-            let direction = if *delta > 0 { Down } else { Up };
-
-            autopilot::mouse::scroll(direction, 1);
+            mouse::scroll(mouse::Axis::Vertical, delta.signum())?;
         }
         (Knob::Knob2, Pressed { is_down: true }) => {
-            keyboard::tap_key(KeyCode::Home);
+            keyboard::tap_key(KeyCode::Home)?;
         }
         _ => {}
     }
@@ -175,15 +573,22 @@ async fn handle_knob_default(
     Ok(())
 }
 
-async fn handle_knob_layer_a(
-    context: &mut Context,
-    _timestamp_delta: Duration,
-    knob: Knob,
-    action: &KnobAction,
-) -> Result<()> {
+async fn handle_knob_layer_b(context: &mut Context, event: &Event) -> Result<()> {
+    match context.mixer.as_ref().filter(|mixer| mixer.handles(event)) {
+        Some(mixer) => mixer.handle(&mut context.controller, event).await,
+        None => handle_knob_default(context, event).await,
+    }
+}
+
+async fn handle_knob_layer_a(context: &mut Context, event: &Event) -> Result<()> {
     use KnobAction::*;
 
-    match (knob, action) {
+    let (knob, action) = match knob_action(event) {
+        Some(knob_action) => knob_action,
+        None => return Ok(()),
+    };
+
+    match (knob, &action) {
         // Raise arms
         (knob, action) if matches!(knob, Knob::Knob1 | Knob::Knob2) => {
             let multiplier = context.controller.state().fader().as_percent() * 0.1 + 0.01;
@@ -202,6 +607,7 @@ async fn handle_knob_layer_a(
             };
 
             context.vtube.set_param(param, value).await?;
+            context.show_param(Layer::A, param, value);
 
             let knob_value = if let Knob::Knob1 = knob {
                 value / 3.0
@@ -238,6 +644,7 @@ async fn handle_knob_layer_a(
             };
 
             context.vtube.set_param(param, value).await?;
+            context.show_param(Layer::A, param, value);
 
             context.controller.set_knob(
                 knob,
@@ -253,59 +660,36 @@ async fn handle_knob_layer_a(
     Ok(())
 }
 
-async fn handle_button(context: &mut Context, button: Button, is_down: bool) -> Result<()> {
-    if !is_down {
-        return Ok(());
+fn pressed_button(event: &Event) -> Option<Button> {
+    match *event {
+        Event::ButtonPressed { button, .. } => Some(button),
+        _ => None,
     }
-
-    if matches!(button, Button::LayerA | Button::LayerB) {
-        context.controller.negate_button(button)?;
-    } else if context.controller.state().button(Button::LayerA).is_on() {
-        handle_button_layer_a(context, button, is_down).await?;
-    } else {
-        handle_button_default(context, button, is_down).await?;
-    }
-
-    Ok(())
 }
 
-async fn handle_button_layer_a(
-    context: &mut Context,
-    button: Button,
-    _is_down: bool,
-) -> Result<()> {
-    async fn set_expression(context: &mut Context, button: Button, value: f64) -> Result<()> {
-        use Button::*;
-
-        let new_value = if context.vtube.param(Param::MouthX) == value {
-            0.0
-        } else {
-            value
+async fn handle_button_layer_a(context: &mut Context, event: &Event) -> Result<()> {
+    let button = match pressed_button(event) {
+        Some(button) => button,
+        None => return Ok(()),
+    };
+
+    if context.expressions.contains(button) {
+        let value = match context.expressions.peek(button) {
+            Some(Selection::Selected { value, .. }) => value,
+            Some(Selection::Cleared { .. }) => 0.0,
+            None => return Ok(()),
         };
 
-        context.vtube.set_param(Param::MouthX, new_value).await?;
-        for b in &[Button1, Button2, Button3, Button4, Button5, Button6] {
-            let state = if *b == button && new_value != 0.0 {
-                ButtonLedState::On
-            } else {
-                ButtonLedState::Off
-            };
-
-            context.controller.set_button(*b, state)?;
-        }
-
-        Ok(())
+        // Only light the button once VTubeStudio has the new expression
+        context.vtube.set_param(Param::MouthX, value).await?;
+        context.expressions.press(&mut context.controller, button)?;
+        context.vtube.refresh_params().await?;
+        return Ok(());
     }
 
     match button {
-        Button::Button1 => set_expression(context, button, 1.0).await?, // Sad
-        Button::Button2 => set_expression(context, button, 2.0).await?, // Angry
-        Button::Button3 => set_expression(context, button, 3.0).await?, // Shock
-        Button::Button4 => set_expression(context, button, 4.0).await?, // Smug
-        Button::Button5 => set_expression(context, button, 5.0).await?, // Excited
-        Button::Button6 => set_expression(context, button, 6.0).await?, // Crying
-        Button::Button7 => context.vtube.toggle_hotkey(2).await?,       // Dance
-        Button::Button8 => context.vtube.toggle_hotkey(3).await?,       // Dab
+        Button::Button7 => trigger_hotkey(context, "Dance").await?,
+        Button::Button8 => trigger_hotkey(context, "Dab").await?,
         Button::Button9 => {
             let was_frowning = context.vtube.param(Param::TongueOut) == 1.0;
 
@@ -319,14 +703,10 @@ async fn handle_button_layer_a(
             context.controller.set_button(button, state)?;
         }
         Button::Button10 => {
-            // Toggle sunglasses
-            context.vtube.toggle_hotkey(1).await?;
+            trigger_hotkey(context, "Sunglasses").await?;
             context.controller.negate_button(button)?;
         }
-        Button::Button16 => {
-            // Reset expressions
-            context.vtube.toggle_hotkey(8).await?;
-        }
+        Button::Button16 => trigger_hotkey(context, "Reset").await?,
         _ => {}
     }
 
@@ -335,30 +715,47 @@ async fn handle_button_layer_a(
     Ok(())
 }
 
-async fn handle_button_default(
-    _context: &mut Context,
-    button: Button,
-    _is_down: bool,
-) -> Result<()> {
+#[cfg(feature = "plugin")]
+async fn trigger_hotkey(context: &mut Context, name: &str) -> Result<()> {
+    context.plugin.trigger_hotkey(name).await
+}
+
+#[cfg(not(feature = "plugin"))]
+async fn trigger_hotkey(_context: &mut Context, name: &str) -> Result<()> {
+    anyhow::bail!("triggering hotkey {:?} requires the `plugin` feature", name)
+}
+
+async fn handle_button_layer_b(context: &mut Context, event: &Event) -> Result<()> {
+    #[cfg(feature = "mpris")]
+    if let Some(mpris) = context.mpris.as_ref().filter(|mpris| mpris.handles(event)) {
+        return mpris.handle(&mut context.controller, event).await;
+    }
+
+    handle_button_default(context, event).await
+}
+
+async fn handle_button_default(_context: &mut Context, event: &Event) -> Result<()> {
+    let button = match pressed_button(event) {
+        Some(button) => button,
+        None => return Ok(()),
+    };
+
     match button {
-        Button::Button1 => keyboard::type_text("👏"),
-        Button::Button2 => keyboard::type_text("🔜"),
-        Button::Button3 => keyboard::type_text("👀"),
-        Button::Button4 => keyboard::type_text("🙇"),
+        Button::Button1 => keyboard::type_text("👏").await?,
+        Button::Button2 => keyboard::type_text("🔜").await?,
+        Button::Button3 => keyboard::type_text("👀").await?,
+        Button::Button4 => keyboard::type_text("🙇").await?,
         Button::Button8 => {
-            // Find YouTube tab in Chrome, and focus on the chat input field
-            osascript::JavaScript::new(include_str!("focus-youtube.js")).execute()?;
-        }
-        Button::Button9 => {
-            autopilot::key::tap(&Code(KeyCode::Space), &[Meta, Control], 0, 0);
-        }
-        Button::Button11 => {
-            autopilot::key::tap(&Code(KeyCode::Tab), &[Control, Shift], 0, 0);
-        }
-        Button::Button12 => {
-            autopilot::key::tap(&Code(KeyCode::Tab), &[Control], 0, 0);
+            // Find YouTube/Twitch tab in Chrome, and focus on the chat input field
+            let target = FocusTarget::new(&["youtube.com/watch?v=", "twitch.tv/"])
+                .script(include_str!("focus-chat-input.js"));
+
+            focus::platform_default().focus(&target).await?;
         }
-        Button::Button16 => keyboard::tap_key(KeyCode::Return),
+        Button::Button9 => "meta+ctrl+space".parse::<KeyCombo>()?.tap()?,
+        Button::Button11 => "ctrl+shift+tab".parse::<KeyCombo>()?.tap()?,
+        Button::Button12 => "ctrl+tab".parse::<KeyCombo>()?.tap()?,
+        Button::Button16 => keyboard::tap_key(KeyCode::Return)?,
 
         _ => {}
     }
@@ -0,0 +1,330 @@
+//! Latency and throughput metrics, exposed in the Prometheus text format or logged
+//! periodically.
+//!
+//! * `xtouchmini_events_total`: events dispatched (use `rate()` for events per second)
+//! * `xtouchmini_input_latency_seconds`: from the MIDI message arriving to the event being
+//!   picked up for dispatch. Streams from [`EventStream::from_midi`](crate::EventStream::from_midi)
+//!   only start counting once the message is read.
+//! * `xtouchmini_handler_duration_seconds`: dispatching an event through middleware and its
+//!   handler
+//! * `xtouchmini_vtubestudio_send_duration_seconds`: sending a message to VTubeStudio,
+//!   including reconnecting
+//! * `xtouchmini_controller_queue_depth`: commands waiting to be sent to the controller
+
+use once_cell::sync::Lazy;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Upper bounds in seconds, from half a millisecond (a MIDI message) to a second (a stalled
+/// connection).
+const BUCKETS: [f64; 11] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// The process-wide registry.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Durations, counted into fixed buckets.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Not cumulative, unlike in the Prometheus format. The last one counts durations over
+    /// the largest bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records the time since `start`.
+    pub fn record_since(&self, start: Instant) {
+        self.record(start.elapsed());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.sum().div_f64(count as f64)),
+        }
+    }
+
+    /// The upper bound of the bucket the quantile falls into, e.g. `0.95` for the 95th
+    /// percentile. `None` if nothing was recorded, or it's over the largest bound.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let target = (self.count() as f64 * quantile).ceil() as u64;
+        if target == 0 {
+            return None;
+        }
+
+        let mut seen = 0;
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return Some(Duration::from_secs_f64(*bound));
+            }
+        }
+
+        None
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        // Read once, so `+Inf` and `count` agree even if a duration comes in meanwhile
+        let count = cumulative + self.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum().as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub events: Counter,
+    pub input_latency: Histogram,
+    pub handler_duration: Histogram,
+    pub vtubestudio_send_duration: Histogram,
+    pub controller_queue_depth: Gauge,
+}
+
+impl Metrics {
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP xtouchmini_events_total Events dispatched.");
+        let _ = writeln!(out, "# TYPE xtouchmini_events_total counter");
+        let _ = writeln!(out, "xtouchmini_events_total {}", self.events.get());
+
+        self.input_latency.render(
+            &mut out,
+            "xtouchmini_input_latency_seconds",
+            "Time from a MIDI message arriving to its event being dispatched.",
+        );
+        self.handler_duration.render(
+            &mut out,
+            "xtouchmini_handler_duration_seconds",
+            "Time spent dispatching an event through middleware and its handler.",
+        );
+        self.vtubestudio_send_duration.render(
+            &mut out,
+            "xtouchmini_vtubestudio_send_duration_seconds",
+            "Time spent sending a message to VTubeStudio, including reconnecting.",
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP xtouchmini_controller_queue_depth Commands waiting to be sent to the controller."
+        );
+        let _ = writeln!(out, "# TYPE xtouchmini_controller_queue_depth gauge");
+        let _ = writeln!(
+            out,
+            "xtouchmini_controller_queue_depth {}",
+            self.controller_queue_depth.get()
+        );
+
+        out
+    }
+}
+
+/// Logs a summary of the global metrics every `interval`, forever. Events per second are
+/// since the last summary, and latencies since startup. Needs to be spawned.
+pub async fn log_every(interval: Duration) {
+    let metrics = global();
+    let mut ticks = tokio::time::interval(interval);
+    let mut last_events = metrics.events.get();
+    let mut last_tick = Instant::now();
+
+    // The first tick completes immediately
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        let events = metrics.events.get();
+        let events_per_sec = (events - last_events) as f64 / last_tick.elapsed().as_secs_f64();
+        last_events = events;
+        last_tick = Instant::now();
+
+        info!(
+            events_per_sec = %format!("{:.1}", events_per_sec),
+            input_latency = %summarize(&metrics.input_latency),
+            handler = %summarize(&metrics.handler_duration),
+            vtubestudio_send = %summarize(&metrics.vtubestudio_send_duration),
+            controller_queue_depth = metrics.controller_queue_depth.get(),
+            "Metrics"
+        );
+    }
+}
+
+fn summarize(histogram: &Histogram) -> String {
+    let mean = match histogram.mean() {
+        Some(mean) => mean,
+        None => return "none".to_owned(),
+    };
+
+    let p95 = match histogram.quantile(0.95) {
+        Some(p95) => format!("<={:?}", p95),
+        None => format!(">{:?}", Duration::from_secs_f64(BUCKETS[BUCKETS.len() - 1])),
+    };
+
+    format!("mean={:?} p95{} count={}", mean, p95, histogram.count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// The value of every line of the rendered histogram that starts with `prefix`.
+    fn rendered(histogram: &Histogram, prefix: &str) -> Vec<(String, f64)> {
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "Test histogram.");
+
+        out.lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(|line| {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                (name.to_owned(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    fn bucket(histogram: &Histogram, le: &str) -> f64 {
+        let name = format!("test_bucket{{le=\"{}\"}}", le);
+        rendered(histogram, &name)[0].1
+    }
+
+    #[test]
+    fn places_durations_at_bounds_in_that_bucket() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::from_micros(500));
+        histogram.record(ms(1));
+        histogram.record(ms(1) + Duration::from_nanos(1));
+        histogram.record(Duration::ZERO);
+
+        assert_eq!(bucket(&histogram, "0.0005"), 2.0);
+        assert_eq!(bucket(&histogram, "0.001"), 3.0);
+        assert_eq!(bucket(&histogram, "0.0025"), 4.0);
+    }
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let histogram = Histogram::default();
+        for duration in &[ms(2), ms(2), ms(30), ms(400), ms(1500), ms(3000)] {
+            histogram.record(*duration);
+        }
+
+        let buckets = rendered(&histogram, "test_bucket");
+        assert_eq!(buckets.len(), BUCKETS.len() + 1);
+        assert!(buckets.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+        // Durations over the largest bound only show up in `+Inf`
+        assert_eq!(bucket(&histogram, "1"), 4.0);
+        assert_eq!(bucket(&histogram, "+Inf"), 6.0);
+        assert_eq!(
+            rendered(&histogram, "test_count"),
+            vec![("test_count".to_owned(), 6.0)]
+        );
+        assert_eq!(histogram.count(), 6);
+
+        let sum = rendered(&histogram, "test_sum")[0].1;
+        assert!((sum - 4.934).abs() < 1e-9, "sum was {}", sum);
+    }
+
+    #[test]
+    fn finds_quantile_buckets() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        assert_eq!(histogram.mean(), None);
+
+        for _ in 0..9 {
+            histogram.record(ms(3));
+        }
+        histogram.record(ms(80));
+
+        assert_eq!(histogram.quantile(0.5), Some(ms(5)));
+        assert_eq!(histogram.quantile(0.9), Some(ms(5)));
+        assert_eq!(histogram.quantile(0.95), Some(ms(100)));
+        assert_eq!(histogram.quantile(1.0), Some(ms(100)));
+    }
+
+    #[test]
+    fn has_no_quantile_past_the_largest_bound() {
+        let histogram = Histogram::default();
+        for _ in 0..9 {
+            histogram.record(ms(3));
+        }
+        histogram.record(Duration::from_secs(5));
+
+        assert_eq!(histogram.quantile(0.9), Some(ms(5)));
+        assert_eq!(histogram.quantile(0.95), None);
+        assert!(summarize(&histogram).contains("p95>1s"));
+    }
+}
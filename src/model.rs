@@ -4,8 +4,11 @@
 use crate::output::Command;
 use anyhow::{bail, Context, Result};
 use num_enum::IntoPrimitive;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
 use std::convert::TryFrom;
-use strum::{EnumIter, IntoEnumIterator};
+use std::marker::PhantomData;
+use strum::{AsRefStr, EnumIter, EnumString, IntoEnumIterator};
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct ControllerState {
@@ -59,7 +62,30 @@ impl ControllerState {
     }
 }
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
+impl Serialize for ControllerState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ControllerState", 3)?;
+        state.serialize_field("knobs", &ByName::<Knob, _>(&self.knobs, PhantomData))?;
+        state.serialize_field("buttons", &ByName::<Button, _>(&self.buttons, PhantomData))?;
+        state.serialize_field("fader", &self.fader)?;
+        state.end()
+    }
+}
+
+/// Serializes per-control values keyed by name (e.g. `{"Button1": "on"}`) instead of index.
+struct ByName<'a, K, V>(&'a [V], PhantomData<K>);
+
+impl<K, V> Serialize for ByName<'_, K, V>
+where
+    K: IntoEnumIterator + Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_map(K::iter().zip(self.0))
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct KnobState {
     pub style: KnobLedStyle,
     pub value: i32,
@@ -92,7 +118,20 @@ macro_rules! impl_midi {
 }
 
 #[repr(usize)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, EnumIter)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    IntoPrimitive,
+    EnumIter,
+    EnumString,
+    Serialize,
+    Deserialize,
+)]
+#[strum(ascii_case_insensitive)]
 pub enum Button {
     // Top row
     Button1,
@@ -143,7 +182,20 @@ impl Button {
 }
 
 #[repr(usize)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, EnumIter)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    IntoPrimitive,
+    EnumIter,
+    EnumString,
+    Serialize,
+    Deserialize,
+)]
+#[strum(ascii_case_insensitive)]
 pub enum Knob {
     // These u8 values are for knob turn messages.
     // For knob press, add 0x10 to the value.
@@ -171,7 +223,21 @@ impl Knob {
 }
 
 #[repr(usize)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, EnumIter)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    IntoPrimitive,
+    EnumIter,
+    EnumString,
+    AsRefStr,
+    Serialize,
+    Deserialize,
+)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[serde(rename_all = "lowercase")]
 pub enum ButtonLedState {
     Off,
     On,
@@ -204,7 +270,7 @@ impl Default for ButtonLedState {
     }
 }
 
-#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct KnobLedValue(pub(crate) u8);
 
 impl KnobLedValue {
@@ -226,7 +292,9 @@ impl KnobLedValue {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, EnumString, Serialize, Deserialize)]
+#[strum(ascii_case_insensitive)]
+#[serde(rename_all = "lowercase")]
 pub enum KnobLedStyle {
     /// One LED is lit
     Single,
@@ -246,7 +314,7 @@ impl Default for KnobLedStyle {
     }
 }
 
-#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FaderValue(pub u8);
 
 impl FaderValue {
@@ -258,7 +326,8 @@ impl FaderValue {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
     ButtonPressed { button: Button, is_down: bool },
     KnobPressed { knob: Knob, is_down: bool },
@@ -266,32 +335,60 @@ pub enum Event {
     FaderMoved { value: FaderValue },
 }
 
+impl Event {
+    /// The MIDI message the controller sends for this event. Knob deltas are limited to
+    /// what fits in a message (-63 to 63).
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let velocity = |is_down: bool| if is_down { 0x7f } else { 0x00 };
+
+        match self {
+            Event::KnobTurned { knob, delta } => {
+                let delta = (*delta).clamp(-63, 63);
+                let value = if delta < 0 { 64 - delta } else { delta };
+                [0xb0, knob.to_midi() + 0x0f, value as u8]
+            }
+            Event::FaderMoved { value } => [0xe8, 0x00, value.0],
+            Event::KnobPressed { knob, is_down } => {
+                [0x90, knob.to_midi() + 0x1f, velocity(*is_down)]
+            }
+            Event::ButtonPressed { button, is_down } => {
+                [0x90, button.to_midi(), velocity(*is_down)]
+            }
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for Event {
     type Error = anyhow::Error;
 
     fn try_from(bytes: &[u8]) -> Result<Event> {
         use Event::*;
 
-        match bytes {
-            &[0xb0, controller_num, value] => {
+        match *bytes {
+            [0xb0, controller_num, value] => {
                 let value = value as i32;
                 let delta = if value >= 64 { -(value - 64) } else { value };
 
                 Ok(KnobTurned {
-                    knob: Knob::from_midi(controller_num - 0x0f)
+                    knob: controller_num
+                        .checked_sub(0x0f)
+                        .and_then(Knob::from_midi)
                         .context("unknown controller number for knob")?,
-                    delta: delta as i32,
+                    delta,
                 })
             }
-            &[0xe8, _, value] => Ok(Event::FaderMoved {
+            [0xe8, _, value] => Ok(Event::FaderMoved {
                 value: FaderValue(value),
             }),
-            &[0x90, note, state] => {
+            [0x90, note, state] => {
                 let is_down = state != 0;
 
-                if note >= 0x20 && note <= 0x27 {
+                if (0x20..=0x27).contains(&note) {
                     Ok(Event::KnobPressed {
-                        knob: Knob::from_midi(note - 0x1f).context("unknown note for knob")?,
+                        knob: note
+                            .checked_sub(0x1f)
+                            .and_then(Knob::from_midi)
+                            .context("unknown note for knob")?,
                         is_down,
                     })
                 } else {
@@ -305,3 +402,68 @@ impl TryFrom<&[u8]> for Event {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(event: Event) -> Event {
+        Event::try_from(&event.to_bytes()[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_button_events() {
+        for button in Button::iter() {
+            for &is_down in &[true, false] {
+                let event = Event::ButtonPressed { button, is_down };
+                assert_eq!(round_trip(event.clone()), event);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_knob_events() {
+        for knob in Knob::iter() {
+            for &is_down in &[true, false] {
+                let event = Event::KnobPressed { knob, is_down };
+                assert_eq!(round_trip(event.clone()), event);
+            }
+
+            for delta in -63..=63 {
+                let event = Event::KnobTurned { knob, delta };
+                assert_eq!(round_trip(event.clone()), event);
+            }
+        }
+    }
+
+    #[test]
+    fn clamps_knob_deltas() {
+        let event = Event::KnobTurned {
+            knob: Knob::Knob1,
+            delta: 100,
+        };
+        let clamped = Event::KnobTurned {
+            knob: Knob::Knob1,
+            delta: 63,
+        };
+        assert_eq!(round_trip(event), clamped);
+    }
+
+    #[test]
+    fn round_trips_fader_events() {
+        for value in 0..=127 {
+            let event = Event::FaderMoved {
+                value: FaderValue(value),
+            };
+            assert_eq!(round_trip(event.clone()), event);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_messages() {
+        assert!(Event::try_from(&[0xb0, 0x0f, 0x01][..]).is_err());
+        assert!(Event::try_from(&[0x90, 0x7f, 0x7f][..]).is_err());
+        assert!(Event::try_from(&[0x80, 0x00, 0x00][..]).is_err());
+        assert!(Event::try_from(&[0x90, 0x00][..]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::backend;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+pub use autopilot::mouse::Button as MouseButton;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Axis {
+    Vertical,
+    Horizontal,
+}
+
+/// Something that can emulate mouse input. See [`crate::backend`] for implementations.
+pub trait MouseBackend: Send + Sync {
+    /// Scrolls by a number of clicks. Positive values scroll down (or right).
+    fn scroll(&self, axis: Axis, clicks: i32) -> Result<()>;
+
+    /// Moves the pointer relative to its current position, in pixels.
+    fn move_by(&self, dx: i32, dy: i32) -> Result<()>;
+
+    /// Moves the pointer to a position on the screen, in pixels.
+    fn move_to(&self, x: f64, y: f64) -> Result<()>;
+
+    /// Size of the screen in pixels.
+    fn screen_size(&self) -> Result<(f64, f64)>;
+
+    fn toggle_button(&self, button: MouseButton, down: bool) -> Result<()>;
+
+    fn click(&self, button: MouseButton) -> Result<()> {
+        self.toggle_button(button, true)?;
+        self.toggle_button(button, false)
+    }
+}
+
+pub fn scroll(axis: Axis, clicks: i32) -> Result<()> {
+    backend::get().scroll(axis, clicks)
+}
+
+pub fn move_by(dx: i32, dy: i32) -> Result<()> {
+    backend::get().move_by(dx, dy)
+}
+
+pub fn move_to(x: f64, y: f64) -> Result<()> {
+    backend::get().move_to(x, y)
+}
+
+/// Moves the pointer to a position given as fractions (0.0 to 1.0) of the screen size, e.g.
+/// from [`crate::FaderValue::as_percent`].
+pub fn move_to_fraction(x: f64, y: f64) -> Result<()> {
+    let backend = backend::get();
+    let (width, height) = backend.screen_size()?;
+    let x = x.clamp(0.0, 1.0) * (width - 1.0);
+    let y = y.clamp(0.0, 1.0) * (height - 1.0);
+    backend.move_to(x, y)
+}
+
+pub fn click(button: MouseButton) -> Result<()> {
+    backend::get().click(button)
+}
+
+/// Holds a mouse button down, e.g. to drag while a controller button is held.
+pub fn press(button: MouseButton) -> Result<()> {
+    backend::get().toggle_button(button, true)
+}
+
+pub fn release(button: MouseButton) -> Result<()> {
+    backend::get().toggle_button(button, false)
+}
+
+/// Scales up knob deltas when a knob is turned in quick succession.
+#[derive(Clone, Debug)]
+pub struct Acceleration {
+    window: Duration,
+    max_multiplier: f64,
+    last: Option<Instant>,
+}
+
+impl Acceleration {
+    /// Events closer together than `window` are accelerated, up to `max_multiplier` for
+    /// events that arrive at the same time.
+    pub fn new(window: Duration, max_multiplier: f64) -> Self {
+        Self {
+            window,
+            max_multiplier: max_multiplier.max(1.0),
+            last: None,
+        }
+    }
+
+    pub fn apply(&mut self, delta: i32) -> i32 {
+        self.apply_at(delta, Instant::now())
+    }
+
+    /// Same as [`apply`](Self::apply), for an event that happened at `now`.
+    pub fn apply_at(&mut self, delta: i32, now: Instant) -> i32 {
+        let elapsed = self.last.map(|last| now.duration_since(last));
+        self.last = Some(now);
+
+        let multiplier = match elapsed {
+            Some(elapsed) if elapsed < self.window => {
+                let speed = 1.0 - elapsed.as_secs_f64() / self.window.as_secs_f64();
+                1.0 + (self.max_multiplier - 1.0) * speed
+            }
+            _ => 1.0,
+        };
+
+        (delta as f64 * multiplier).round() as i32
+    }
+}
+
+impl Default for Acceleration {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), 4.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_event_is_not_accelerated() {
+        let mut acceleration = Acceleration::new(Duration::from_millis(100), 4.0);
+        assert_eq!(acceleration.apply_at(5, Instant::now()), 5);
+    }
+
+    #[test]
+    fn simultaneous_events_get_max_multiplier() {
+        let mut acceleration = Acceleration::new(Duration::from_millis(100), 4.0);
+        let now = Instant::now();
+        acceleration.apply_at(5, now);
+        assert_eq!(acceleration.apply_at(5, now), 20);
+        assert_eq!(acceleration.apply_at(-3, now), -12);
+    }
+
+    #[test]
+    fn events_outside_window_are_not_accelerated() {
+        let window = Duration::from_millis(100);
+        let mut acceleration = Acceleration::new(window, 4.0);
+        let start = Instant::now();
+        acceleration.apply_at(5, start);
+        assert_eq!(acceleration.apply_at(5, start + window), 5);
+        assert_eq!(acceleration.apply_at(5, start + window * 3), 5);
+    }
+}
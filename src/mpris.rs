@@ -0,0 +1,505 @@
+//! Media player controls through [MPRIS](https://specifications.freedesktop.org/mpris-spec/latest/)
+//! on the D-Bus session bus, for Spotify, browsers, mpv and most other Linux players.
+//!
+//! Buttons bound to play or pause are lit while playing and blink while paused. Knobs show
+//! the volume or how far into the track playback is. Call [`Mpris::sync`] whenever
+//! [`Mpris::changes`] yields to keep them up to date.
+
+use crate::model::{Button, ButtonLedState, Event, Knob, KnobLedStyle, KnobLedValue};
+use crate::output::Controller;
+use crate::status::{Source, WithSource};
+use anyhow::{anyhow, bail, Context as _, Result};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zbus::message::Type;
+use zbus::zvariant::{OwnedValue, Value};
+use zbus::{fdo, Connection, MatchRule, Message, MessageStream};
+
+const BUS_NAMESPACE: &str = "org.mpris.MediaPlayer2";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const DEFAULT_SEEK_STEP: Duration = Duration::from_secs(5);
+const DEFAULT_VOLUME_STEP: f64 = 0.02;
+
+/// Which player the controls apply to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// The player that most recently started playing, or any player if none has yet
+    Recent,
+    /// A player by the last part of its bus name, e.g. `spotify` for
+    /// `org.mpris.MediaPlayer2.spotify`. Instances like `firefox.instance_1_42` match too.
+    Player(String),
+}
+
+impl Target {
+    pub fn player<S: Into<String>>(name: S) -> Self {
+        Self::Player(name.into())
+    }
+
+    fn matches(&self, bus_name: &str) -> bool {
+        let name = match (self, player_name(bus_name)) {
+            (Self::Player(name), Some(player)) if player == name => return true,
+            (Self::Player(name), Some(player)) => player.strip_prefix(name.as_str()),
+            _ => return false,
+        };
+
+        matches!(name, Some(instance) if instance.starts_with('.'))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+}
+
+impl Action {
+    fn method(self) -> &'static str {
+        match self {
+            Self::PlayPause => "PlayPause",
+            Self::Play => "Play",
+            Self::Pause => "Pause",
+            Self::Stop => "Stop",
+            Self::Next => "Next",
+            Self::Previous => "Previous",
+        }
+    }
+
+    /// Whether the button's LED shows the playback status.
+    fn shows_status(self) -> bool {
+        matches!(self, Self::PlayPause | Self::Play | Self::Pause)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KnobAction {
+    /// Moves through the track, and shows how far into it playback is
+    Seek,
+    /// Changes and shows the player's own volume
+    Volume,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn led_state(self) -> ButtonLedState {
+        match self {
+            Self::Playing => ButtonLedState::On,
+            Self::Paused => ButtonLedState::Blink,
+            Self::Stopped => ButtonLedState::Off,
+        }
+    }
+}
+
+/// Controls bound to a media player.
+#[derive(Debug)]
+pub struct Mpris {
+    connection: Connection,
+    target: Target,
+    buttons: Vec<(Button, Action)>,
+    knobs: Vec<(Knob, KnobAction)>,
+    seek_step: Duration,
+    volume_step: f64,
+    /// Unique bus name of the player that most recently started playing
+    recent: Arc<Mutex<Option<String>>>,
+}
+
+impl Mpris {
+    /// Connects to the session bus. Set `DBUS_SESSION_BUS_ADDRESS` to use another bus.
+    pub async fn connect() -> Result<Self> {
+        let connection = Connection::session()
+            .await
+            .context("failed to connect to the D-Bus session bus")?;
+
+        Ok(Self::with_connection(connection))
+    }
+
+    pub fn with_connection(connection: Connection) -> Self {
+        Self {
+            connection,
+            target: Target::Recent,
+            buttons: Vec::new(),
+            knobs: Vec::new(),
+            seek_step: DEFAULT_SEEK_STEP,
+            volume_step: DEFAULT_VOLUME_STEP,
+            recent: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// [`Target::Recent`] by default.
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn button(mut self, button: Button, action: Action) -> Self {
+        self.buttons.push((button, action));
+        self
+    }
+
+    pub fn knob(mut self, knob: Knob, action: KnobAction) -> Self {
+        self.knobs.push((knob, action));
+        self
+    }
+
+    /// How far each knob step seeks (5 seconds by default).
+    pub fn seek_step(mut self, step: Duration) -> Self {
+        self.seek_step = step;
+        self
+    }
+
+    /// Volume change per knob step, from 0 to 1 (0.02 by default).
+    pub fn volume_step(mut self, step: f64) -> Self {
+        self.volume_step = step;
+        self
+    }
+
+    /// Bus name of the targeted player, if it's running.
+    pub async fn player(&self) -> Result<Option<String>> {
+        let dbus = fdo::DBusProxy::new(&self.connection).await?;
+        let players = dbus
+            .list_names()
+            .await?
+            .into_iter()
+            .map(|name| name.to_string())
+            .filter(|name| player_name(name).is_some())
+            .collect::<Vec<_>>();
+
+        if let Target::Player(_) = self.target {
+            return Ok(players.into_iter().find(|name| self.target.matches(name)));
+        }
+
+        let recent = self.lock_recent()?.clone();
+        if recent.is_some() {
+            for name in &players {
+                // The player may have quit since it was listed
+                let owner = match dbus.get_name_owner(name.as_str().try_into()?).await {
+                    Ok(owner) => owner,
+                    Err(_) => continue,
+                };
+
+                if Some(owner.as_str()) == recent.as_deref() {
+                    return Ok(Some(name.clone()));
+                }
+            }
+        }
+
+        // Nothing has started playing since `changes` was called, so prefer whatever is
+        // playing right now
+        for name in &players {
+            if let Ok(PlaybackStatus::Playing) = self.status(name).await {
+                return Ok(Some(name.clone()));
+            }
+        }
+
+        Ok(players.into_iter().next())
+    }
+
+    /// Whether the event comes from one of the bound controls, so callers can leave other
+    /// controls to their usual actions.
+    pub fn handles(&self, event: &Event) -> bool {
+        match *event {
+            Event::ButtonPressed { button, .. } => self.button_action(button).is_some(),
+            Event::KnobTurned { knob, .. } | Event::KnobPressed { knob, .. } => {
+                self.knob_action(knob).is_some()
+            }
+            Event::FaderMoved { .. } => false,
+        }
+    }
+
+    /// Runs actions for events from the bound controls. Other events, and events while no
+    /// player is running, are ignored.
+    pub async fn handle(&self, controller: &mut Controller, event: &Event) -> Result<()> {
+        let result = match *event {
+            Event::ButtonPressed {
+                button,
+                is_down: true,
+            } => match self.button_action(button) {
+                Some(action) => self.press(action).await,
+                None => return Ok(()),
+            },
+            Event::KnobTurned { knob, delta } => match self.knob_action(knob) {
+                Some(action) => self.turn(controller, knob, action, delta).await,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        result.with_source(Source::Mpris)
+    }
+
+    /// Updates the LEDs from the player's current state.
+    pub async fn sync(&self, controller: &mut Controller) -> Result<()> {
+        let player = self.player().await.with_source(Source::Mpris)?;
+        let status = match &player {
+            Some(player) => Some(self.status(player).await.with_source(Source::Mpris)?),
+            None => None,
+        };
+
+        for &(button, action) in &self.buttons {
+            let state = match status {
+                Some(status) if action.shows_status() => status.led_state(),
+                _ => ButtonLedState::Off,
+            };
+            controller.set_button(button, state)?;
+        }
+
+        for &(knob, action) in &self.knobs {
+            // Players may not support every property, which leaves the ring empty
+            let percent = match &player {
+                Some(player) => match action {
+                    KnobAction::Seek => self.progress(player).await.ok().flatten(),
+                    KnobAction::Volume => self.volume(player).await.ok(),
+                },
+                None => None,
+            };
+
+            match percent {
+                Some(percent) => controller.set_knob(
+                    knob,
+                    KnobLedStyle::Fan,
+                    KnobLedValue::from_percent(percent.clamp(0.0, 1.0)),
+                )?,
+                None => controller.set_knob(knob, KnobLedStyle::Single, KnobLedValue::MIN)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Yields whenever a player changes state, seeks, starts or quits. Bursts of changes are
+    /// merged into one item. While this is polled, it also keeps track of which player most
+    /// recently started playing, for [`Target::Recent`].
+    pub async fn changes(&self) -> Result<BoxStream<'static, ()>> {
+        let properties = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(PROPERTIES_INTERFACE)?
+            .member("PropertiesChanged")?
+            .path(OBJECT_PATH)?
+            .arg(0, PLAYER_INTERFACE)?
+            .build();
+        let seeked = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(PLAYER_INTERFACE)?
+            .member("Seeked")?
+            .path(OBJECT_PATH)?
+            .build();
+        let owners = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .arg0ns(BUS_NAMESPACE)?
+            .build();
+
+        let recent = self.recent.clone();
+        let properties = MessageStream::for_match_rule(properties, &self.connection, None)
+            .await?
+            .map(move |message| {
+                if let Ok(message) = message {
+                    track_recent(&recent, &message);
+                }
+            });
+        let seeked = MessageStream::for_match_rule(seeked, &self.connection, None)
+            .await?
+            .map(|_| ());
+        let owners = MessageStream::for_match_rule(owners, &self.connection, None)
+            .await?
+            .map(|_| ());
+
+        Ok(
+            stream::select_all(vec![properties.boxed(), seeked.boxed(), owners.boxed()])
+                .ready_chunks(64)
+                .map(|_| ())
+                .boxed(),
+        )
+    }
+
+    fn button_action(&self, button: Button) -> Option<Action> {
+        self.buttons
+            .iter()
+            .find(|(bound, _)| *bound == button)
+            .map(|&(_, action)| action)
+    }
+
+    fn knob_action(&self, knob: Knob) -> Option<KnobAction> {
+        self.knobs
+            .iter()
+            .find(|(bound, _)| *bound == knob)
+            .map(|&(_, action)| action)
+    }
+
+    async fn press(&self, action: Action) -> Result<()> {
+        let player = match self.player().await? {
+            Some(player) => player,
+            None => return Ok(()),
+        };
+
+        self.call(&player, action.method(), &()).await
+    }
+
+    async fn turn(
+        &self,
+        controller: &mut Controller,
+        knob: Knob,
+        action: KnobAction,
+        delta: i32,
+    ) -> Result<()> {
+        let player = match self.player().await? {
+            Some(player) => player,
+            None => return Ok(()),
+        };
+
+        match action {
+            KnobAction::Seek => {
+                let offset = delta as i64 * self.seek_step.as_micros() as i64;
+                self.call(&player, "Seek", &offset).await
+            }
+            KnobAction::Volume => {
+                let volume = self.volume(&player).await?;
+                let volume = (volume + delta as f64 * self.volume_step).clamp(0.0, 1.0);
+                self.set(&player, "Volume", Value::from(volume)).await?;
+
+                controller.set_knob(knob, KnobLedStyle::Fan, KnobLedValue::from_percent(volume))
+            }
+        }
+    }
+
+    async fn status(&self, player: &str) -> Result<PlaybackStatus> {
+        let value = self.get(player, "PlaybackStatus").await?;
+
+        match <&str>::try_from(&*value)? {
+            "Playing" => Ok(PlaybackStatus::Playing),
+            "Paused" => Ok(PlaybackStatus::Paused),
+            "Stopped" => Ok(PlaybackStatus::Stopped),
+            status => bail!("unknown playback status {:?} from {}", status, player),
+        }
+    }
+
+    async fn volume(&self, player: &str) -> Result<f64> {
+        Ok(f64::try_from(&*self.get(player, "Volume").await?)?)
+    }
+
+    /// How far into the track playback is, from 0 to 1, if the track's length is known.
+    async fn progress(&self, player: &str) -> Result<Option<f64>> {
+        let position = i64::try_from(&*self.get(player, "Position").await?)?;
+        let metadata =
+            HashMap::<String, OwnedValue>::try_from(self.get(player, "Metadata").await?)?;
+
+        // Meant to be an i64, but some players send a u64
+        let length = metadata.get("mpris:length").and_then(|length| {
+            i64::try_from(&**length)
+                .ok()
+                .or_else(|| u64::try_from(&**length).ok().map(|length| length as i64))
+        });
+
+        Ok(match length {
+            Some(length) if length > 0 => Some(position as f64 / length as f64),
+            _ => None,
+        })
+    }
+
+    async fn call<B>(&self, player: &str, method: &str, body: &B) -> Result<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        self.connection
+            .call_method(
+                Some(player),
+                OBJECT_PATH,
+                Some(PLAYER_INTERFACE),
+                method,
+                body,
+            )
+            .await
+            .with_context(|| format!("failed to call {} on {}", method, player))?;
+
+        Ok(())
+    }
+
+    async fn get(&self, player: &str, property: &str) -> Result<OwnedValue> {
+        let reply = self
+            .connection
+            .call_method(
+                Some(player),
+                OBJECT_PATH,
+                Some(PROPERTIES_INTERFACE),
+                "Get",
+                &(PLAYER_INTERFACE, property),
+            )
+            .await
+            .with_context(|| format!("failed to get {} from {}", property, player))?;
+
+        Ok(reply.body().deserialize()?)
+    }
+
+    async fn set(&self, player: &str, property: &str, value: Value<'_>) -> Result<()> {
+        self.connection
+            .call_method(
+                Some(player),
+                OBJECT_PATH,
+                Some(PROPERTIES_INTERFACE),
+                "Set",
+                &(PLAYER_INTERFACE, property, value),
+            )
+            .await
+            .with_context(|| format!("failed to set {} on {}", property, player))?;
+
+        Ok(())
+    }
+
+    fn lock_recent(&self) -> Result<std::sync::MutexGuard<'_, Option<String>>> {
+        self.recent
+            .lock()
+            .map_err(|_| anyhow!("recent player was poisoned"))
+    }
+}
+
+/// The part of an MPRIS bus name after `org.mpris.MediaPlayer2.`.
+fn player_name(bus_name: &str) -> Option<&str> {
+    bus_name
+        .strip_prefix(BUS_NAMESPACE)?
+        .strip_prefix('.')
+        .filter(|name| !name.is_empty())
+}
+
+/// Remembers the sender of a `PropertiesChanged` signal if it says playback started.
+fn track_recent(recent: &Mutex<Option<String>>, message: &Message) {
+    let header = message.header();
+    let sender = match header.sender() {
+        Some(sender) => sender,
+        None => return,
+    };
+
+    let body = message.body();
+    let (_, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) =
+        match body.deserialize() {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+
+    let started = changed
+        .get("PlaybackStatus")
+        .and_then(|status| <&str>::try_from(&**status).ok())
+        == Some("Playing");
+
+    if started {
+        if let Ok(mut recent) = recent.lock() {
+            *recent = Some(sender.to_string());
+        }
+    }
+}
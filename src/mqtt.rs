@@ -0,0 +1,497 @@
+//! MQTT client for home automation, e.g. driving smart lights from the controller.
+//!
+//! Topics are under `xtouchmini/<device>`:
+//! * `button/<n>`, `knob/<n>/press`: `ON` while pressed, `OFF` otherwise (retained). Layer
+//!   buttons are `button/layer_a` and `button/layer_b`.
+//! * `knob/<n>`: the knob's value (retained), and `knob/<n>/delta` for each turn
+//! * `fader`: 0 to 127 (retained)
+//! * `button/<n>/led`, `knob/<n>/led`: current LED states (retained)
+//! * `button/<n>/led/set`: sets a button LED to `on`, `off` or `blink`
+//! * `knob/<n>/led/set`: sets a knob ring from 0 to 12, with an optional style, e.g. `6 trim`
+//! * `status`: `online`, or `offline` once disconnected
+//!
+//! Entities for all of these are announced through Home Assistant MQTT discovery.
+
+use crate::model::{Button, ButtonLedState, ControllerState, Event, Knob, KnobLedStyle};
+use crate::model::{KnobLedValue, KnobState};
+use crate::output::Command;
+use crate::router::{HasController, Middleware, Next};
+use crate::status::{Source, WithSource};
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{Future, StreamExt};
+use rumqttc::{AsyncClient, ClientError, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use strum::IntoEnumIterator;
+use tracing::{debug, error, info};
+
+const TOPIC_PREFIX: &str = "xtouchmini";
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Enough to queue up discovery messages for every control without blocking
+const REQUEST_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    host: String,
+    port: u16,
+    device: String,
+    credentials: Option<(String, String)>,
+    discovery_prefix: Option<String>,
+}
+
+impl Config {
+    /// `device` names this controller in topics, so several can share a broker.
+    pub fn new<H: Into<String>, D: Into<String>>(host: H, port: u16, device: D) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            device: device.into(),
+            credentials: None,
+            discovery_prefix: Some(DEFAULT_DISCOVERY_PREFIX.to_owned()),
+        }
+    }
+
+    pub fn credentials<S: Into<String>>(mut self, username: S, password: S) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Topic prefix for Home Assistant discovery, `homeassistant` by default. `None` disables
+    /// discovery.
+    pub fn discovery_prefix<S: Into<String>>(mut self, prefix: Option<S>) -> Self {
+        self.discovery_prefix = prefix.map(Into::into);
+        self
+    }
+
+    /// Returns a handle for publishing, LED commands from the `led/set` topics (to be applied
+    /// with [`Controller::apply`](crate::Controller::apply)), and the connection, which needs
+    /// to be spawned. The connection is retried until it succeeds.
+    pub fn connect(self) -> (Mqtt, BoxStream<'static, Command>, impl Future<Output = ()>) {
+        let base = format!("{}/{}", TOPIC_PREFIX, self.device);
+
+        let mut options = MqttOptions::new(
+            format!("{}-{}", TOPIC_PREFIX, self.device),
+            &self.host,
+            self.port,
+        );
+        options.set_last_will(LastWill::new(
+            format!("{}/status", base),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+
+        let mqtt = Mqtt {
+            client,
+            base,
+            device: self.device,
+            discovery_prefix: self.discovery_prefix,
+            retained: Arc::new(Mutex::new(Retained::default())),
+        };
+
+        let connection = mqtt.clone().run(event_loop, commands_tx);
+        (mqtt, commands_rx.boxed(), connection)
+    }
+}
+
+/// Publishes events and LED states. As [`Middleware`], it publishes every handled event.
+#[derive(Clone)]
+pub struct Mqtt {
+    client: AsyncClient,
+    base: String,
+    device: String,
+    discovery_prefix: Option<String>,
+    retained: Arc<Mutex<Retained>>,
+}
+
+/// Retained topics, to skip unchanged ones and republish them all on reconnect.
+#[derive(Debug, Default)]
+struct Retained {
+    /// Last payload queued for each topic
+    published: HashMap<String, String>,
+    /// Latest payload of topics that couldn't be queued, e.g. while the broker is down
+    unsent: HashMap<String, String>,
+}
+
+impl Mqtt {
+    pub async fn publish(&self, event: &Event) -> Result<()> {
+        let on_off = |is_down: bool| if is_down { "ON" } else { "OFF" };
+
+        match *event {
+            Event::ButtonPressed { button, is_down } => {
+                let topic = format!("button/{}", button_id(button));
+                self.publish_retained(&topic, on_off(is_down)).await
+            }
+            Event::KnobPressed { knob, is_down } => {
+                let topic = format!("knob/{}/press", knob_id(knob));
+                self.publish_retained(&topic, on_off(is_down)).await
+            }
+            Event::KnobTurned { knob, delta } => {
+                let topic = format!("{}/knob/{}/delta", self.base, knob_id(knob));
+                match self
+                    .client
+                    .try_publish(topic, QoS::AtMostOnce, false, delta.to_string())
+                {
+                    // Only meaningful as it happens, so it's dropped rather than queued
+                    Err(ClientError::TryRequest(_)) => {
+                        debug!(?knob, delta, "MQTT queue is full, dropping knob turn");
+                        Ok(())
+                    }
+                    result => Ok(result?),
+                }
+            }
+            // Published from the controller state by `sync`
+            Event::FaderMoved { .. } => Ok(()),
+        }
+    }
+
+    /// Publishes LED states, knob values and the fader position that have changed.
+    pub async fn sync(&self, state: &ControllerState) -> Result<()> {
+        for button in Button::iter() {
+            let topic = format!("button/{}/led", button_id(button));
+            self.publish_retained(&topic, state.button(button).as_ref())
+                .await?;
+        }
+
+        for knob in Knob::iter() {
+            let knob_state = state.knob(knob);
+            let id = knob_id(knob);
+
+            self.publish_retained(&format!("knob/{}", id), &knob_state.value.to_string())
+                .await?;
+            self.publish_retained(
+                &format!("knob/{}/led", id),
+                &knob_state.led_value.0.to_string(),
+            )
+            .await?;
+        }
+
+        self.publish_retained("fader", &state.fader().0.to_string())
+            .await
+    }
+
+    /// Queues a retained message without waiting, so a broker that's down can't hold up
+    /// event handling. If the queue is full, the payload is kept for the next announce.
+    async fn publish_retained(&self, topic: &str, payload: &str) -> Result<()> {
+        let topic = format!("{}/{}", self.base, topic);
+
+        let mut retained = self
+            .retained
+            .lock()
+            .map_err(|_| anyhow!("retained MQTT topics were poisoned"))?;
+        if retained.published.get(&topic).map(String::as_str) == Some(payload) {
+            retained.unsent.remove(&topic);
+            return Ok(());
+        }
+
+        match self
+            .client
+            .try_publish(topic.as_str(), QoS::AtLeastOnce, true, payload)
+        {
+            Ok(()) => {
+                retained.unsent.remove(&topic);
+                retained.published.insert(topic, payload.to_owned());
+            }
+            Err(ClientError::TryRequest(_)) => {
+                debug!(%topic, "MQTT queue is full, deferring retained message");
+                retained.unsent.insert(topic, payload.to_owned());
+            }
+            Err(error) => return Err(error.into()),
+        }
+
+        Ok(())
+    }
+
+    async fn run(self, mut event_loop: EventLoop, commands: mpsc::UnboundedSender<Command>) {
+        loop {
+            let packet = match event_loop.poll().await {
+                Ok(rumqttc::Event::Incoming(packet)) => packet,
+                Ok(rumqttc::Event::Outgoing(_)) => continue,
+                Err(error) => {
+                    error!(?error, "MQTT connection failed, reconnecting");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+
+            match packet {
+                Packet::ConnAck(_) => {
+                    info!("Connected to MQTT broker");
+
+                    // Requests are only sent while the event loop is polled, so this can't
+                    // wait for them here
+                    let mqtt = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(error) = mqtt.announce().await {
+                            error!(?error, "Failed to announce MQTT topics");
+                        }
+                    });
+                }
+                Packet::Publish(publish) => {
+                    let payload = String::from_utf8_lossy(&publish.payload);
+
+                    match self.command(&publish.topic, &payload) {
+                        Ok(Some(command)) => {
+                            if commands.unbounded_send(command).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => debug!(topic = %publish.topic, "Ignoring MQTT message"),
+                        Err(error) => error!(?error, topic = %publish.topic, "Invalid LED command"),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Subscribes, then publishes discovery messages and retained state, after (re)connecting.
+    async fn announce(&self) -> Result<()> {
+        self.client
+            .subscribe(format!("{}/+/+/led/set", self.base), QoS::AtLeastOnce)
+            .await?;
+
+        if let Some(prefix) = &self.discovery_prefix {
+            for (component, object_id, config) in self.discovery_configs() {
+                let topic = format!(
+                    "{}/{}/{}_{}/{}/config",
+                    prefix, component, TOPIC_PREFIX, self.device, object_id
+                );
+                self.client
+                    .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                    .await?;
+            }
+        }
+
+        self.client
+            .publish(
+                format!("{}/status", self.base),
+                QoS::AtLeastOnce,
+                true,
+                "online",
+            )
+            .await?;
+
+        let (mut topics, unsent) = {
+            let retained = self
+                .retained
+                .lock()
+                .map_err(|_| anyhow!("retained MQTT topics were poisoned"))?;
+            (retained.published.clone(), retained.unsent.clone())
+        };
+        topics.extend(unsent.clone());
+
+        for (topic, payload) in topics {
+            self.client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await?;
+        }
+
+        let mut retained = self
+            .retained
+            .lock()
+            .map_err(|_| anyhow!("retained MQTT topics were poisoned"))?;
+        for (topic, payload) in unsent {
+            // Unless it changed again in the meantime
+            if retained.unsent.get(&topic) == Some(&payload) {
+                retained.unsent.remove(&topic);
+                retained.published.insert(topic, payload);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses a message on an `led/set` topic. Returns `None` for other topics.
+    fn command(&self, topic: &str, payload: &str) -> Result<Option<Command>> {
+        let path = match topic.strip_prefix(self.base.as_str()) {
+            Some(path) if path.starts_with('/') => &path[1..],
+            _ => return Ok(None),
+        };
+        let payload = payload.trim();
+
+        let command = match path.split('/').collect::<Vec<_>>()[..] {
+            ["button", id, "led", "set"] => Command::SetButtonLedState {
+                button: Button::iter()
+                    .find(|button| button_id(*button) == id)
+                    .with_context(|| format!("unknown button {:?}", id))?,
+                state: ButtonLedState::from_str(payload)
+                    .map_err(|_| anyhow!("unknown button LED state {:?}", payload))?,
+            },
+            ["knob", id, "led", "set"] => Command::SetKnobLedState {
+                knob: Knob::iter()
+                    .find(|knob| knob_id(*knob) == id)
+                    .with_context(|| format!("unknown knob {:?}", id))?,
+                state: knob_state(payload)?,
+            },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Home Assistant entities, as `(component, object ID, config)`.
+    fn discovery_configs(&self) -> Vec<(&'static str, String, Value)> {
+        let device = json!({
+            "identifiers": [format!("{}_{}", TOPIC_PREFIX, self.device)],
+            "name": format!("X-Touch Mini ({})", self.device),
+            "manufacturer": "Behringer",
+            "model": "X-Touch Mini",
+        });
+
+        let entity = |name: String, object_id: &str, config: Value| {
+            let mut config = config;
+            config["name"] = json!(name);
+            config["unique_id"] = json!(format!("{}_{}_{}", TOPIC_PREFIX, self.device, object_id));
+            config["availability_topic"] = json!(format!("{}/status", self.base));
+            config["device"] = device.clone();
+            config
+        };
+
+        let mut configs = Vec::new();
+
+        for button in Button::iter() {
+            let id = button_id(button);
+            let topic = format!("{}/button/{}", self.base, id);
+
+            configs.push((
+                "binary_sensor",
+                format!("button_{}", id),
+                entity(
+                    format!("Button {}", id),
+                    &format!("button_{}", id),
+                    json!({ "state_topic": topic }),
+                ),
+            ));
+            configs.push((
+                "switch",
+                format!("button_{}_led", id),
+                entity(
+                    format!("Button {} LED", id),
+                    &format!("button_{}_led", id),
+                    json!({
+                        "state_topic": format!("{}/led", topic),
+                        "command_topic": format!("{}/led/set", topic),
+                        "payload_on": "on",
+                        "payload_off": "off",
+                        "state_on": "on",
+                        "state_off": "off",
+                    }),
+                ),
+            ));
+        }
+
+        for knob in Knob::iter() {
+            let id = knob_id(knob);
+            let topic = format!("{}/knob/{}", self.base, id);
+
+            configs.push((
+                "sensor",
+                format!("knob_{}", id),
+                entity(
+                    format!("Knob {}", id),
+                    &format!("knob_{}", id),
+                    json!({ "state_topic": topic }),
+                ),
+            ));
+            configs.push((
+                "binary_sensor",
+                format!("knob_{}_press", id),
+                entity(
+                    format!("Knob {} press", id),
+                    &format!("knob_{}_press", id),
+                    json!({ "state_topic": format!("{}/press", topic) }),
+                ),
+            ));
+            configs.push((
+                "number",
+                format!("knob_{}_led", id),
+                entity(
+                    format!("Knob {} LED", id),
+                    &format!("knob_{}_led", id),
+                    json!({
+                        "state_topic": format!("{}/led", topic),
+                        "command_topic": format!("{}/led/set", topic),
+                        "min": KnobLedValue::MIN.0,
+                        "max": KnobLedValue::MAX.0,
+                    }),
+                ),
+            ));
+        }
+
+        configs.push((
+            "sensor",
+            "fader".to_owned(),
+            entity(
+                "Fader".to_owned(),
+                "fader",
+                json!({ "state_topic": format!("{}/fader", self.base) }),
+            ),
+        ));
+
+        configs
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Mqtt {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let result = next.run(context, event).await;
+
+        let state = context.controller().state();
+        let published = match self.publish(event).await {
+            Ok(()) => self.sync(state).await,
+            Err(error) => Err(error),
+        };
+
+        result.and(published.with_source(Source::Mqtt))
+    }
+}
+
+fn button_id(button: Button) -> String {
+    match button {
+        Button::LayerA => "layer_a".to_owned(),
+        Button::LayerB => "layer_b".to_owned(),
+        button => (button.to_index() + 1).to_string(),
+    }
+}
+
+fn knob_id(knob: Knob) -> String {
+    (knob.to_index() + 1).to_string()
+}
+
+/// Parses e.g. `6` or `6 trim`.
+fn knob_state(payload: &str) -> Result<KnobState> {
+    let mut parts = payload.split_whitespace();
+
+    let led_value = match parts.next().map(str::parse::<u8>) {
+        Some(Ok(value)) => KnobLedValue::new(value),
+        _ => bail!("expected a knob LED value from 0 to 12, got {:?}", payload),
+    };
+
+    let style = match parts.next() {
+        Some(style) => KnobLedStyle::from_str(style)
+            .map_err(|_| anyhow!("unknown knob LED style {:?}", style))?,
+        None => KnobLedStyle::Fan,
+    };
+
+    Ok(KnobState {
+        style,
+        led_value,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,198 @@
+//! Desktop notifications for layer switches, bindings and values, through the freedesktop
+//! notification service on the D-Bus session bus.
+//!
+//! Knob rings only have 12 positions and don't say what they're bound to, so a notification
+//! like `Layer A: CheekPuff 0.42` fills in the details. Notifications are throttled, and each
+//! one replaces the last instead of piling up.
+
+use crate::model::Event;
+use crate::router::{HasController, Layer, Middleware, Next, Route};
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::error;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+const DEFAULT_APP_NAME: &str = "X-Touch Mini";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_THROTTLE: Duration = Duration::from_millis(250);
+
+const SERVICE: &str = "org.freedesktop.Notifications";
+const OBJECT_PATH: &str = "/org/freedesktop/Notifications";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    app_name: String,
+    timeout: Duration,
+    throttle: Duration,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            app_name: DEFAULT_APP_NAME.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+            throttle: DEFAULT_THROTTLE,
+        }
+    }
+
+    pub fn app_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.app_name = name.into();
+        self
+    }
+
+    /// How long notifications stay up (2 seconds by default).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Minimum time between notifications (250ms by default). Anything shown in between is
+    /// dropped, except for the latest, which is shown once the time is up.
+    pub fn throttle(mut self, throttle: Duration) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Connects to the session bus, returning the notifier and a future that sends the
+    /// notifications, which needs to be spawned.
+    pub async fn connect(self) -> Result<(Notifier, impl Future<Output = ()>)> {
+        let connection = Connection::session()
+            .await
+            .context("failed to connect to the D-Bus session bus")?;
+
+        Ok(self.with_connection(connection))
+    }
+
+    pub fn with_connection(self, connection: Connection) -> (Notifier, impl Future<Output = ()>) {
+        let (pending_tx, pending_rx) = watch::channel(String::new());
+
+        let notifier = Notifier {
+            pending: Arc::new(pending_tx),
+            bindings: Vec::new(),
+        };
+
+        (notifier, run(connection, self, pending_rx))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shows notifications. As [`Middleware`], it shows layer switches and named bindings.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    pending: Arc<watch::Sender<String>>,
+    bindings: Vec<(Route, String)>,
+}
+
+impl Notifier {
+    /// Names the handler for a route, so events matching it show e.g. `Layer A: Dance`.
+    /// Routes are tried in the order they're added.
+    pub fn binding<S: Into<String>>(mut self, route: Route, name: S) -> Self {
+        self.bindings.push((route, name.into()));
+        self
+    }
+
+    pub fn show<S: Into<String>>(&self, summary: S) {
+        // Fails once the sending task is gone, which is already logged
+        let _ = self.pending.send(summary.into());
+    }
+
+    pub fn layer(&self, layer: Layer) {
+        self.show(layer_name(layer));
+    }
+
+    pub fn activated(&self, layer: Layer, binding: &str) {
+        self.show(format!("{}: {}", layer_name(layer), binding));
+    }
+
+    /// e.g. `Layer A: CheekPuff 0.42`
+    pub fn value(&self, layer: Layer, binding: &str, value: f64) {
+        self.show(format!("{}: {} {:.2}", layer_name(layer), binding, value));
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Notifier {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let before = Layer::current(context.controller().state());
+        let result = next.run(context, event).await;
+        let after = Layer::current(context.controller().state());
+
+        if before != after {
+            self.layer(after);
+        } else if result.is_ok() {
+            // Only announced if it worked, as errors are reported elsewhere
+            if let Some((_, name)) = self
+                .bindings
+                .iter()
+                .find(|(route, _)| route.matches(event, before))
+            {
+                self.activated(before, name);
+            }
+        }
+
+        result
+    }
+}
+
+fn layer_name(layer: Layer) -> &'static str {
+    match layer {
+        Layer::Default => "Default layer",
+        Layer::A => "Layer A",
+        Layer::B => "Layer B",
+    }
+}
+
+async fn run(connection: Connection, config: Config, mut pending: watch::Receiver<String>) {
+    // Replaced by each new notification, so there's only ever one on screen
+    let mut id = 0;
+
+    while pending.changed().await.is_ok() {
+        let summary = pending.borrow().clone();
+
+        match notify(&connection, &config, id, &summary).await {
+            Ok(new_id) => id = new_id,
+            Err(error) => error!(?error, "Failed to show notification"),
+        }
+
+        tokio::time::sleep(config.throttle).await;
+    }
+}
+
+async fn notify(connection: &Connection, config: &Config, id: u32, summary: &str) -> Result<u32> {
+    let mut hints = HashMap::new();
+    // Kept out of the notification history, since they're only useful right away
+    hints.insert("transient", Value::from(true));
+
+    let reply = connection
+        .call_method(
+            Some(SERVICE),
+            OBJECT_PATH,
+            Some(SERVICE),
+            "Notify",
+            &(
+                config.app_name.as_str(),
+                id,
+                "",
+                summary,
+                "",
+                Vec::<&str>::new(),
+                hints,
+                config.timeout.as_millis() as i32,
+            ),
+        )
+        .await
+        .context("failed to send notification")?;
+
+    Ok(reply.body().deserialize()?)
+}
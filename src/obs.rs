@@ -0,0 +1,695 @@
+//! Client for OBS Studio's obs-websocket v5 protocol.
+
+use crate::model::{Button, ButtonLedState};
+use crate::output::Controller;
+use crate::status::{Source, WithSource};
+use anyhow::{anyhow, bail, Context as _, Result};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, warn};
+
+const RPC_VERSION: u32 = 1;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Requests waiting for a response, or `None` once the connection is gone.
+type Pending = Arc<Mutex<Option<HashMap<String, oneshot::Sender<Result<Value>>>>>>;
+
+/// Event categories to subscribe to, as a bitmask.
+pub mod subscription {
+    pub const GENERAL: u32 = 1 << 0;
+    pub const CONFIG: u32 = 1 << 1;
+    pub const SCENES: u32 = 1 << 2;
+    pub const INPUTS: u32 = 1 << 3;
+    pub const TRANSITIONS: u32 = 1 << 4;
+    pub const FILTERS: u32 = 1 << 5;
+    pub const OUTPUTS: u32 = 1 << 6;
+    pub const SCENE_ITEMS: u32 = 1 << 7;
+    pub const MEDIA_INPUTS: u32 = 1 << 8;
+    pub const VENDORS: u32 = 1 << 9;
+    pub const UI: u32 = 1 << 10;
+
+    /// Every category except the high-volume ones (such as volume meters)
+    pub const ALL: u32 = GENERAL
+        | CONFIG
+        | SCENES
+        | INPUTS
+        | TRANSITIONS
+        | FILTERS
+        | OUTPUTS
+        | SCENE_ITEMS
+        | MEDIA_INPUTS
+        | VENDORS
+        | UI;
+}
+
+mod op {
+    pub const HELLO: u8 = 0;
+    pub const IDENTIFY: u8 = 1;
+    pub const IDENTIFIED: u8 = 2;
+    pub const EVENT: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const REQUEST_RESPONSE: u8 = 7;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    op: u8,
+    d: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Hello {
+    authentication: Option<Authentication>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authentication {
+    challenge: String,
+    salt: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestResponse {
+    request_id: String,
+    request_status: RequestStatus,
+    #[serde(default)]
+    response_data: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestStatus {
+    result: bool,
+    code: u32,
+    comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawEvent {
+    event_type: String,
+    #[serde(default)]
+    event_data: Value,
+}
+
+/// Events received from OBS. Events without a dedicated variant are passed through as
+/// [`Event::Other`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    CurrentProgramSceneChanged {
+        scene_name: String,
+    },
+    SceneItemEnableStateChanged {
+        scene_name: String,
+        scene_item_id: i64,
+        enabled: bool,
+    },
+    InputMuteStateChanged {
+        input_name: String,
+        muted: bool,
+    },
+    InputVolumeChanged {
+        input_name: String,
+        volume_mul: f64,
+    },
+    StreamStateChanged {
+        active: bool,
+    },
+    RecordStateChanged {
+        active: bool,
+    },
+    Other {
+        event_type: String,
+        data: Value,
+    },
+}
+
+impl Event {
+    fn parse(raw: RawEvent) -> Self {
+        let data = &raw.event_data;
+        let str_field = |name: &str| data[name].as_str().unwrap_or_default().to_owned();
+        let bool_field = |name: &str| data[name].as_bool().unwrap_or_default();
+
+        match raw.event_type.as_str() {
+            "CurrentProgramSceneChanged" => Self::CurrentProgramSceneChanged {
+                scene_name: str_field("sceneName"),
+            },
+            "SceneItemEnableStateChanged" => Self::SceneItemEnableStateChanged {
+                scene_name: str_field("sceneName"),
+                scene_item_id: data["sceneItemId"].as_i64().unwrap_or_default(),
+                enabled: bool_field("sceneItemEnabled"),
+            },
+            "InputMuteStateChanged" => Self::InputMuteStateChanged {
+                input_name: str_field("inputName"),
+                muted: bool_field("inputMuted"),
+            },
+            "InputVolumeChanged" => Self::InputVolumeChanged {
+                input_name: str_field("inputName"),
+                volume_mul: data["inputVolumeMul"].as_f64().unwrap_or_default(),
+            },
+            "StreamStateChanged" => Self::StreamStateChanged {
+                active: bool_field("outputActive"),
+            },
+            "RecordStateChanged" => Self::RecordStateChanged {
+                active: bool_field("outputActive"),
+            },
+            _ => Self::Other {
+                event_type: raw.event_type,
+                data: raw.event_data,
+            },
+        }
+    }
+}
+
+pub struct Client {
+    sink: tokio::sync::Mutex<SplitSink<WebSocket, WsMessage>>,
+    pending: Pending,
+    next_id: AtomicU64,
+    events: broadcast::Sender<Event>,
+    reader: JoinHandle<()>,
+    request_timeout: Duration,
+}
+
+impl Client {
+    /// Connects to obs-websocket (e.g. `ws://127.0.0.1:4455`), subscribing to all
+    /// non-high-volume events.
+    pub async fn connect(url: &str, password: Option<&str>) -> Result<Self> {
+        Self::connect_with_subscriptions(url, password, subscription::ALL).await
+    }
+
+    pub async fn connect_with_subscriptions(
+        url: &str,
+        password: Option<&str>,
+        subscriptions: u32,
+    ) -> Result<Self> {
+        Self::connect_inner(url, password, subscriptions)
+            .await
+            .with_source(Source::Obs)
+    }
+
+    async fn connect_inner(url: &str, password: Option<&str>, subscriptions: u32) -> Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .context("failed to connect to OBS")?;
+        let (mut sink, mut stream) = ws.split();
+
+        let hello: Hello = serde_json::from_value(expect_op(&mut stream, op::HELLO).await?)?;
+
+        let mut identify = json!({
+            "rpcVersion": RPC_VERSION,
+            "eventSubscriptions": subscriptions,
+        });
+
+        if let Some(auth) = hello.authentication {
+            let password = password.context("OBS requires a password")?;
+            identify["authentication"] = json!(auth_string(password, &auth.salt, &auth.challenge));
+        }
+
+        send_frame(&mut sink, op::IDENTIFY, identify).await?;
+        expect_op(&mut stream, op::IDENTIFIED)
+            .await
+            .context("OBS rejected identification")?;
+
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (events, _) = broadcast::channel(64);
+        let reader = tokio::spawn(read_frames(stream, pending.clone(), events.clone()));
+
+        Ok(Self {
+            sink: tokio::sync::Mutex::new(sink),
+            pending,
+            next_id: AtomicU64::new(0),
+            events,
+            reader,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        })
+    }
+
+    /// How long to wait for OBS to respond to a request (5 seconds by default).
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Subscribes to events from OBS. Each call returns an independent receiver.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Sends an arbitrary request, returning its `responseData`.
+    pub async fn request(&self, request_type: &str, data: Option<Value>) -> Result<Value> {
+        self.request_inner(request_type, data)
+            .await
+            .with_source(Source::Obs)
+    }
+
+    async fn request_inner(&self, request_type: &str, data: Option<Value>) -> Result<Value> {
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .context("OBS connection closed")?
+            .insert(request_id.clone(), tx);
+
+        let mut request = json!({
+            "requestType": request_type,
+            "requestId": request_id,
+        });
+        if let Some(data) = data {
+            request["requestData"] = data;
+        }
+
+        let sent = send_frame(&mut *self.sink.lock().await, op::REQUEST, request).await;
+        if let Err(error) = sent {
+            self.forget(&request_id);
+            return Err(error);
+        }
+
+        match tokio::time::timeout(self.request_timeout, rx).await {
+            Ok(response) => response
+                .map_err(|_| anyhow!("OBS connection closed"))?
+                .with_context(|| format!("OBS request {} failed", request_type)),
+            Err(_) => {
+                self.forget(&request_id);
+                bail!("timed out waiting for OBS to answer {}", request_type)
+            }
+        }
+    }
+
+    fn forget(&self, request_id: &str) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(request_id);
+        }
+    }
+
+    pub async fn current_program_scene(&self) -> Result<String> {
+        let response = self.request("GetCurrentProgramScene", None).await?;
+        string_field(&response, "currentProgramSceneName")
+    }
+
+    pub async fn set_current_program_scene(&self, scene_name: &str) -> Result<()> {
+        self.request(
+            "SetCurrentProgramScene",
+            Some(json!({ "sceneName": scene_name })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn scene_item_id(&self, scene_name: &str, source_name: &str) -> Result<i64> {
+        let response = self
+            .request(
+                "GetSceneItemId",
+                Some(json!({ "sceneName": scene_name, "sourceName": source_name })),
+            )
+            .await?;
+
+        response["sceneItemId"]
+            .as_i64()
+            .context("missing sceneItemId in OBS response")
+    }
+
+    pub async fn source_visible(&self, scene_name: &str, source_name: &str) -> Result<bool> {
+        let scene_item_id = self.scene_item_id(scene_name, source_name).await?;
+        let response = self
+            .request(
+                "GetSceneItemEnabled",
+                Some(json!({ "sceneName": scene_name, "sceneItemId": scene_item_id })),
+            )
+            .await?;
+
+        bool_field(&response, "sceneItemEnabled")
+    }
+
+    pub async fn set_source_visible(
+        &self,
+        scene_name: &str,
+        source_name: &str,
+        visible: bool,
+    ) -> Result<()> {
+        let scene_item_id = self.scene_item_id(scene_name, source_name).await?;
+        self.request(
+            "SetSceneItemEnabled",
+            Some(json!({
+                "sceneName": scene_name,
+                "sceneItemId": scene_item_id,
+                "sceneItemEnabled": visible,
+            })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Toggles the visibility of a source, returning the new visibility.
+    pub async fn toggle_source_visible(&self, scene_name: &str, source_name: &str) -> Result<bool> {
+        let visible = !self.source_visible(scene_name, source_name).await?;
+        self.set_source_visible(scene_name, source_name, visible)
+            .await?;
+        Ok(visible)
+    }
+
+    pub async fn input_muted(&self, input_name: &str) -> Result<bool> {
+        let response = self
+            .request("GetInputMute", Some(json!({ "inputName": input_name })))
+            .await?;
+        bool_field(&response, "inputMuted")
+    }
+
+    pub async fn set_input_muted(&self, input_name: &str, muted: bool) -> Result<()> {
+        self.request(
+            "SetInputMute",
+            Some(json!({ "inputName": input_name, "inputMuted": muted })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Toggles the mute state of an input, returning whether it's now muted.
+    pub async fn toggle_input_muted(&self, input_name: &str) -> Result<bool> {
+        let response = self
+            .request("ToggleInputMute", Some(json!({ "inputName": input_name })))
+            .await?;
+        bool_field(&response, "inputMuted")
+    }
+
+    /// Volume of an input, as a multiplier (0.0 is silent, 1.0 is 0 dB).
+    pub async fn input_volume(&self, input_name: &str) -> Result<f64> {
+        let response = self
+            .request("GetInputVolume", Some(json!({ "inputName": input_name })))
+            .await?;
+
+        response["inputVolumeMul"]
+            .as_f64()
+            .context("missing inputVolumeMul in OBS response")
+    }
+
+    pub async fn set_input_volume(&self, input_name: &str, volume_mul: f64) -> Result<()> {
+        self.request(
+            "SetInputVolume",
+            Some(json!({ "inputName": input_name, "inputVolumeMul": volume_mul })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_streaming(&self) -> Result<bool> {
+        let response = self.request("GetStreamStatus", None).await?;
+        bool_field(&response, "outputActive")
+    }
+
+    pub async fn start_streaming(&self) -> Result<()> {
+        self.request("StartStream", None).await?;
+        Ok(())
+    }
+
+    pub async fn stop_streaming(&self) -> Result<()> {
+        self.request("StopStream", None).await?;
+        Ok(())
+    }
+
+    /// Toggles streaming, returning whether the stream is now active.
+    pub async fn toggle_streaming(&self) -> Result<bool> {
+        let response = self.request("ToggleStream", None).await?;
+        bool_field(&response, "outputActive")
+    }
+
+    pub async fn is_recording(&self) -> Result<bool> {
+        let response = self.request("GetRecordStatus", None).await?;
+        bool_field(&response, "outputActive")
+    }
+
+    pub async fn start_recording(&self) -> Result<()> {
+        self.request("StartRecord", None).await?;
+        Ok(())
+    }
+
+    pub async fn stop_recording(&self) -> Result<()> {
+        self.request("StopRecord", None).await?;
+        Ok(())
+    }
+
+    /// Toggles recording, returning whether recording is now active.
+    pub async fn toggle_recording(&self) -> Result<bool> {
+        let response = self.request("ToggleRecord", None).await?;
+        bool_field(&response, "outputActive")
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Mirrors OBS state on button LEDs: the button of the current scene is lit, the recording
+/// button blinks while recording, and so on.
+#[derive(Clone, Debug, Default)]
+pub struct LedMirror {
+    scenes: Vec<(Button, String)>,
+    muted_inputs: Vec<(Button, String)>,
+    recording: Option<Button>,
+    streaming: Option<Button>,
+}
+
+impl LedMirror {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lights the button while the scene is the current program scene.
+    pub fn scene<S: Into<String>>(mut self, button: Button, scene_name: S) -> Self {
+        self.scenes.push((button, scene_name.into()));
+        self
+    }
+
+    /// Lights the button while the input is muted.
+    pub fn muted_input<S: Into<String>>(mut self, button: Button, input_name: S) -> Self {
+        self.muted_inputs.push((button, input_name.into()));
+        self
+    }
+
+    /// Blinks the button while recording.
+    pub fn recording(mut self, button: Button) -> Self {
+        self.recording = Some(button);
+        self
+    }
+
+    /// Lights the button while streaming.
+    pub fn streaming(mut self, button: Button) -> Self {
+        self.streaming = Some(button);
+        self
+    }
+
+    /// Queries the current state from OBS and updates all LEDs. Call this after connecting,
+    /// then call [`LedMirror::apply`] for each received event.
+    pub async fn sync(&self, client: &Client, controller: &mut Controller) -> Result<()> {
+        let scene_name = client.current_program_scene().await?;
+        self.apply(
+            controller,
+            &Event::CurrentProgramSceneChanged { scene_name },
+        )?;
+
+        for (_, input_name) in &self.muted_inputs {
+            let muted = client.input_muted(input_name).await?;
+            self.apply(
+                controller,
+                &Event::InputMuteStateChanged {
+                    input_name: input_name.clone(),
+                    muted,
+                },
+            )?;
+        }
+
+        if self.recording.is_some() {
+            let active = client.is_recording().await?;
+            self.apply(controller, &Event::RecordStateChanged { active })?;
+        }
+
+        if self.streaming.is_some() {
+            let active = client.is_streaming().await?;
+            self.apply(controller, &Event::StreamStateChanged { active })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, controller: &mut Controller, event: &Event) -> Result<()> {
+        match event {
+            Event::CurrentProgramSceneChanged { scene_name } => {
+                for (button, name) in &self.scenes {
+                    controller.set_button(*button, led_state(name == scene_name))?;
+                }
+            }
+            Event::InputMuteStateChanged { input_name, muted } => {
+                for (button, name) in &self.muted_inputs {
+                    if name == input_name {
+                        controller.set_button(*button, led_state(*muted))?;
+                    }
+                }
+            }
+            Event::RecordStateChanged { active } => {
+                if let Some(button) = self.recording {
+                    let state = if *active {
+                        ButtonLedState::Blink
+                    } else {
+                        ButtonLedState::Off
+                    };
+                    controller.set_button(button, state)?;
+                }
+            }
+            Event::StreamStateChanged { active } => {
+                if let Some(button) = self.streaming {
+                    controller.set_button(button, led_state(*active))?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn led_state(is_on: bool) -> ButtonLedState {
+    if is_on {
+        ButtonLedState::On
+    } else {
+        ButtonLedState::Off
+    }
+}
+
+fn auth_string(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = base64::encode(Sha256::digest(format!("{}{}", password, salt).as_bytes()));
+    base64::encode(Sha256::digest(
+        format!("{}{}", secret, challenge).as_bytes(),
+    ))
+}
+
+fn string_field(data: &Value, name: &str) -> Result<String> {
+    data[name]
+        .as_str()
+        .map(ToOwned::to_owned)
+        .with_context(|| format!("missing {} in OBS response", name))
+}
+
+fn bool_field(data: &Value, name: &str) -> Result<bool> {
+    data[name]
+        .as_bool()
+        .with_context(|| format!("missing {} in OBS response", name))
+}
+
+async fn send_frame(sink: &mut SplitSink<WebSocket, WsMessage>, op: u8, d: Value) -> Result<()> {
+    let text = serde_json::to_string(&Frame { op, d })?;
+    sink.send(WsMessage::Text(text))
+        .await
+        .context("failed to send message to OBS")
+}
+
+/// Reads the next frame. The outer error is for the connection, and the inner one for a frame
+/// that couldn't be decoded, which doesn't affect the ones after it.
+async fn next_frame(
+    stream: &mut SplitStream<WebSocket>,
+) -> Result<Option<serde_json::Result<Frame>>> {
+    while let Some(msg) = stream.next().await {
+        match msg.context("failed to read message from OBS")? {
+            WsMessage::Text(text) => return Ok(Some(serde_json::from_str(&text))),
+            WsMessage::Binary(bytes) => return Ok(Some(serde_json::from_slice(&bytes))),
+            WsMessage::Close(_) => return Ok(None),
+            _ => continue,
+        }
+    }
+
+    Ok(None)
+}
+
+async fn expect_op(stream: &mut SplitStream<WebSocket>, expected: u8) -> Result<Value> {
+    let frame = next_frame(stream)
+        .await?
+        .transpose()
+        .context("failed to decode message from OBS")?;
+
+    match frame {
+        Some(frame) if frame.op == expected => Ok(frame.d),
+        Some(frame) => bail!("expected OBS op {}, got {}", expected, frame.op),
+        None => bail!("OBS closed the connection"),
+    }
+}
+
+async fn read_frames(
+    mut stream: SplitStream<WebSocket>,
+    pending: Pending,
+    events: broadcast::Sender<Event>,
+) {
+    loop {
+        let frame = match next_frame(&mut stream).await {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(error))) => {
+                warn!(?error, "Skipping malformed frame from OBS");
+                continue;
+            }
+            Ok(None) => break,
+            Err(error) => {
+                error!(?error, "Failed to read frame from OBS");
+                break;
+            }
+        };
+
+        match frame.op {
+            op::EVENT => match serde_json::from_value::<RawEvent>(frame.d) {
+                // Sending only fails if nobody is subscribed, which is fine
+                Ok(raw) => drop(events.send(Event::parse(raw))),
+                Err(error) => error!(?error, "Failed to parse OBS event"),
+            },
+            op::REQUEST_RESPONSE => {
+                let response = match serde_json::from_value::<RequestResponse>(frame.d) {
+                    Ok(response) => response,
+                    Err(error) => {
+                        error!(?error, "Failed to parse OBS request response");
+                        continue;
+                    }
+                };
+
+                let request_id = response.request_id;
+                let status = response.request_status;
+                let result = if status.result {
+                    Ok(response.response_data)
+                } else {
+                    Err(anyhow!(
+                        "OBS returned status {}: {}",
+                        status.code,
+                        status.comment.unwrap_or_default()
+                    ))
+                };
+
+                let sender = pending
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .and_then(|pending| pending.remove(&request_id));
+                if let Some(sender) = sender {
+                    let _ = sender.send(result);
+                }
+            }
+            op => debug!(op, "Ignoring OBS message"),
+        }
+    }
+
+    // Fail any requests still waiting for a response, and any made from now on
+    pending.lock().unwrap().take();
+}
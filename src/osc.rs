@@ -0,0 +1,268 @@
+//! Publishing events as OSC messages over UDP, and setting LEDs from incoming OSC, for tools
+//! like TouchOSC, Resolume and QLab.
+//!
+//! Events are sent as:
+//! * `/xtouch/button/5 1` (and `0` on release), `/xtouch/layer/a 1`
+//! * `/xtouch/knob/3/press 1`, `/xtouch/knob/3/delta -1`
+//! * `/xtouch/fader 0.42`
+//!
+//! LEDs are set with:
+//! * `/xtouch/button/5/led blink` (or `on`, `off`, or a number, where non-zero is on)
+//! * `/xtouch/knob/3/led 0.5 fan` (a float from 0 to 1, or an int from 0 to 12, with an
+//!   optional style)
+
+use crate::model::{Button, ButtonLedState, Event, Knob, KnobLedStyle, KnobLedValue, KnobState};
+use crate::output::Command;
+use crate::router::{HasController, Middleware, Next};
+use crate::status::{Source, WithSource};
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use rosc::{OscMessage, OscPacket, OscType};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, error};
+
+const DEFAULT_PREFIX: &str = "/xtouch";
+
+// Large enough for any UDP datagram
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// An OSC endpoint. Clones share the same socket, so one clone can be added to a
+/// [`Router`](crate::Router) while another receives LED changes.
+#[derive(Clone, Debug)]
+pub struct Osc {
+    socket: Arc<UdpSocket>,
+    target: Option<SocketAddr>,
+    prefix: String,
+}
+
+impl Osc {
+    /// Listens for incoming OSC on `addr`, e.g. `127.0.0.1:8000`.
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind OSC socket to {}", addr))?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            target: None,
+            prefix: DEFAULT_PREFIX.to_owned(),
+        })
+    }
+
+    /// Where events are published. Nothing is sent without a target.
+    pub fn target(mut self, addr: SocketAddr) -> Self {
+        self.target = Some(addr);
+        self
+    }
+
+    /// Address prefix for sent and received messages, `/xtouch` by default.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// The OSC message for an event.
+    pub fn message(&self, event: &Event) -> OscMessage {
+        let pressed = |is_down: bool| OscType::Int(is_down as i32);
+
+        let (addr, arg) = match *event {
+            Event::ButtonPressed { button, is_down } => (
+                format!("{}/{}", self.prefix, button_path(button)),
+                pressed(is_down),
+            ),
+            Event::KnobPressed { knob, is_down } => (
+                format!("{}/knob/{}/press", self.prefix, knob.to_index() + 1),
+                pressed(is_down),
+            ),
+            Event::KnobTurned { knob, delta } => (
+                format!("{}/knob/{}/delta", self.prefix, knob.to_index() + 1),
+                OscType::Int(delta),
+            ),
+            Event::FaderMoved { value } => (
+                format!("{}/fader", self.prefix),
+                OscType::Float(value.as_percent() as f32),
+            ),
+        };
+
+        OscMessage {
+            addr,
+            args: vec![arg],
+        }
+    }
+
+    pub async fn publish(&self, event: &Event) -> Result<()> {
+        let target = match self.target {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+
+        let packet = OscPacket::Message(self.message(event));
+        let bytes = rosc::encoder::encode(&packet)?;
+        self.socket.send_to(&bytes, target).await?;
+        Ok(())
+    }
+
+    /// Waits for the next incoming packet, and returns the LED commands in it.
+    pub async fn recv(&self) -> Result<Vec<Command>> {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let (len, from) = self.socket.recv_from(&mut buf).await?;
+        let packet = rosc::decoder::decode(&buf[..len])
+            .with_context(|| format!("invalid OSC packet from {}", from))?;
+
+        let mut messages = Vec::new();
+        flatten(packet, &mut messages);
+
+        // One bad message shouldn't drop the rest of a bundle
+        let commands = messages
+            .iter()
+            .filter_map(|message| match self.command(message) {
+                Ok(command) => command,
+                Err(error) => {
+                    error!(?error, addr = %message.addr, %from, "Invalid OSC message");
+                    None
+                }
+            })
+            .collect();
+
+        Ok(commands)
+    }
+
+    /// LED commands from incoming OSC, to be applied with
+    /// [`Controller::apply`](crate::Controller::apply). Invalid packets are logged and skipped.
+    pub fn incoming(&self) -> BoxStream<'static, Command> {
+        stream::unfold(self.clone(), |osc| async move {
+            let commands = match osc.recv().await {
+                Ok(commands) => commands,
+                Err(error) => {
+                    error!(?error, "Failed to handle incoming OSC");
+                    Vec::new()
+                }
+            };
+
+            Some((stream::iter(commands), osc))
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// Parses an LED message. Returns `None` for messages with other addresses.
+    pub fn command(&self, message: &OscMessage) -> Result<Option<Command>> {
+        let path = match message.addr.strip_prefix(self.prefix.as_str()) {
+            Some(path) if path.starts_with('/') => &path[1..],
+            _ => return Ok(None),
+        };
+        let parts = path.split('/').collect::<Vec<_>>();
+
+        let command = match parts[..] {
+            ["button", number, "led"] => Command::SetButtonLedState {
+                button: parse_index(number, Button::from_index)?,
+                state: button_state(message.args.first())?,
+            },
+            ["layer", layer, "led"] => Command::SetButtonLedState {
+                button: match layer {
+                    "a" => Button::LayerA,
+                    "b" => Button::LayerB,
+                    _ => bail!("unknown layer {:?}", layer),
+                },
+                state: button_state(message.args.first())?,
+            },
+            ["knob", number, "led"] => Command::SetKnobLedState {
+                knob: parse_index(number, Knob::from_index)?,
+                state: knob_state(&message.args)?,
+            },
+            _ => {
+                debug!(addr = %message.addr, "Ignoring OSC message");
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(command))
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Osc {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let result = next.run(context, event).await;
+        let published = self.publish(event).await.with_source(Source::Osc);
+
+        result.and(published)
+    }
+}
+
+fn button_path(button: Button) -> String {
+    match button {
+        Button::LayerA => "layer/a".to_owned(),
+        Button::LayerB => "layer/b".to_owned(),
+        button => format!("button/{}", button.to_index() + 1),
+    }
+}
+
+fn flatten(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => messages.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                flatten(packet, messages);
+            }
+        }
+    }
+}
+
+/// Controls are numbered from 1 in addresses.
+fn parse_index<T>(number: &str, from_index: fn(usize) -> Option<T>) -> Result<T> {
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(from_index)
+        .ok_or_else(|| anyhow!("unknown control number {:?}", number))
+}
+
+fn button_state(arg: Option<&OscType>) -> Result<ButtonLedState> {
+    let on = match arg {
+        Some(OscType::String(state)) => {
+            return ButtonLedState::from_str(state)
+                .map_err(|_| anyhow!("unknown button LED state {:?}", state))
+        }
+        Some(OscType::Int(value)) => *value != 0,
+        Some(OscType::Float(value)) => *value != 0.0,
+        Some(OscType::Bool(value)) => *value,
+        arg => bail!("expected a button LED state, got {:?}", arg),
+    };
+
+    Ok(if on {
+        ButtonLedState::On
+    } else {
+        ButtonLedState::Off
+    })
+}
+
+fn knob_state(args: &[OscType]) -> Result<KnobState> {
+    let led_value = match args.first() {
+        Some(OscType::Float(value)) => KnobLedValue::from_percent(value.clamp(0.0, 1.0) as f64),
+        Some(OscType::Int(value)) => KnobLedValue::new((*value).clamp(0, u8::MAX as i32) as u8),
+        arg => bail!("expected a knob LED value, got {:?}", arg),
+    };
+
+    let style = match args.get(1) {
+        Some(OscType::String(style)) => KnobLedStyle::from_str(style)
+            .map_err(|_| anyhow!("unknown knob LED style {:?}", style))?,
+        Some(arg) => bail!("expected a knob LED style, got {:?}", arg),
+        None => KnobLedStyle::Fan,
+    };
+
+    Ok(KnobState {
+        style,
+        led_value,
+        ..Default::default()
+    })
+}
@@ -1,9 +1,12 @@
+use crate::metrics;
 use crate::model::*;
-use crate::MIDI_DEVICE_NAME;
-use anyhow::{Context as _, Result};
+use crate::status::{Source, WithSource};
+use crate::{is_device_port, MIDI_CLIENT_NAME, MIDI_DEVICE_NAME};
+use anyhow::{bail, Context as _, Result};
 use futures::channel::mpsc;
 use futures::StreamExt;
 use midir::{MidiOutput, MidiOutputConnection};
+use std::convert::TryFrom;
 use std::future::Future;
 use tracing::error;
 
@@ -15,12 +18,27 @@ pub struct Controller {
 
 impl Controller {
     pub fn new() -> Result<(Self, impl Future<Output = ()>)> {
+        Self::with_device_name(MIDI_DEVICE_NAME)
+    }
+
+    /// Connects to a MIDI device with a different name, such as a virtual device.
+    pub fn with_device_name(device_name: &str) -> Result<(Self, impl Future<Output = ()>)> {
+        let mut connection = get_output_port(device_name)?;
+        Self::with_output(move |bytes| Ok(connection.send(bytes)?))
+    }
+
+    /// Sends MIDI messages through `send` instead of to the device, e.g. to a simulator.
+    pub fn with_output<F>(mut send: F) -> Result<(Self, impl Future<Output = ()>)>
+    where
+        F: FnMut(&[u8]) -> Result<()> + Send + 'static,
+    {
         let (tx, mut rx) = mpsc::unbounded::<Command>();
-        let mut connection = get_output_port(MIDI_DEVICE_NAME)?;
 
         let worker = async move {
             while let Some(command) = rx.next().await {
-                if let Err(error) = connection.send(&command.as_bytes()) {
+                metrics::global().controller_queue_depth.decrement();
+
+                if let Err(error) = send(&command.as_bytes()).with_source(Source::MidiOut) {
                     error!(?error, "Failed to send command to controller");
                 }
             }
@@ -29,7 +47,7 @@ impl Controller {
         // Reset controller state
         let state = ControllerState::default();
         for command in state.to_commands() {
-            tx.unbounded_send(command)?;
+            enqueue(&tx, command)?;
         }
 
         let mut controller = Self { sender: tx, state };
@@ -42,7 +60,7 @@ impl Controller {
     }
 
     fn send(&mut self, command: Command) -> Result<()> {
-        Ok(self.sender.unbounded_send(command)?)
+        enqueue(&self.sender, command)
     }
 
     pub fn set_button(&mut self, button: Button, state: ButtonLedState) -> Result<()> {
@@ -71,6 +89,17 @@ impl Controller {
         self.send(Command::SetKnobLedState { knob, state })
     }
 
+    /// Sends a command, keeping track of the LED state it sets.
+    pub fn apply(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::SetButtonLedState { button, state } => self.set_button(button, state),
+            Command::SetKnobLedState { knob, state } => {
+                self.set_knob(knob, state.style, state.led_value)
+            }
+            Command::SetOperationMode { .. } => self.send(command),
+        }
+    }
+
     pub fn set_fader(&mut self, value: FaderValue) {
         self.state.fader_mut().0 = value.0;
     }
@@ -80,18 +109,32 @@ impl Controller {
     }
 }
 
-fn get_output_port(port_name: &str) -> Result<MidiOutputConnection> {
-    let midi_out = MidiOutput::new(port_name)?;
+/// Queues a command for the worker. It's counted first, since the worker may take it off the
+/// queue right away.
+fn enqueue(sender: &mpsc::UnboundedSender<Command>, command: Command) -> Result<()> {
+    let queue_depth = &metrics::global().controller_queue_depth;
+    queue_depth.increment();
+
+    if let Err(error) = sender.unbounded_send(command) {
+        queue_depth.decrement();
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+fn get_output_port(device_name: &str) -> Result<MidiOutputConnection> {
+    let midi_out = MidiOutput::new(MIDI_CLIENT_NAME)?;
 
     let ports = midi_out.ports();
 
     let out_port = ports
         .iter()
-        .find(|port| midi_out.port_name(port).as_deref() == Ok(MIDI_DEVICE_NAME))
-        .with_context(|| format!("could not find device {}", MIDI_DEVICE_NAME))?;
+        .find(|port| is_device_port(midi_out.port_name(port), device_name))
+        .with_context(|| format!("could not find device {}", device_name))?;
 
     let conn_out = midi_out
-        .connect(out_port, port_name)
+        .connect(out_port, MIDI_CLIENT_NAME)
         .map_err(|e| midir::ConnectError::new(e.kind(), ()))?;
 
     Ok(conn_out)
@@ -146,3 +189,107 @@ impl Command {
         }
     }
 }
+
+impl TryFrom<&[u8]> for Command {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Command> {
+        use Command::*;
+
+        match *bytes {
+            [0x90, note, value] => Ok(SetButtonLedState {
+                button: Button::from_midi(note).context("unknown note for button")?,
+                state: ButtonLedState::from_midi(value).context("unknown button LED state")?,
+            }),
+            [0xb0, 0x7f, data] => Ok(SetOperationMode {
+                mode: match data {
+                    0 => OperationMode::Standard,
+                    1 => OperationMode::MackieControl,
+                    _ => bail!("unknown operation mode {}", data),
+                },
+            }),
+            [0xb0, controller_num, value] if controller_num > 0x2f => {
+                let style = match value & 0xf0 {
+                    0x00 => KnobLedStyle::Single,
+                    0x10 => KnobLedStyle::Trim,
+                    0x20 => KnobLedStyle::Fan,
+                    0x40 => KnobLedStyle::Spread,
+                    0x50 => KnobLedStyle::Pan,
+                    _ => bail!("unknown knob LED style {:#x}", value),
+                };
+
+                Ok(SetKnobLedState {
+                    knob: Knob::from_midi(controller_num - 0x2f)
+                        .context("unknown controller number for knob")?,
+                    state: KnobState {
+                        style,
+                        led_value: KnobLedValue::new(value & 0x0f),
+                        ..Default::default()
+                    },
+                })
+            }
+            _ => bail!("unknown command: {:?}", bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    fn round_trip(command: Command) -> Command {
+        Command::try_from(&command.as_bytes()[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_button_commands() {
+        for button in Button::iter() {
+            for state in ButtonLedState::iter() {
+                let command = Command::SetButtonLedState { button, state };
+                assert_eq!(round_trip(command.clone()), command);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_knob_commands() {
+        use KnobLedStyle::*;
+
+        for knob in Knob::iter() {
+            for &style in &[Single, Pan, Fan, Spread, Trim] {
+                for value in 0..=KnobLedValue::MAX.0 {
+                    let command = Command::SetKnobLedState {
+                        knob,
+                        state: KnobState {
+                            style,
+                            led_value: KnobLedValue::new(value),
+                            ..Default::default()
+                        },
+                    };
+                    assert_eq!(round_trip(command.clone()), command);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_operation_modes() {
+        for mode in [OperationMode::Standard, OperationMode::MackieControl]
+            .iter()
+            .cloned()
+        {
+            let command = Command::SetOperationMode { mode };
+            assert_eq!(round_trip(command.clone()), command);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert!(Command::try_from(&[0x90, 0x7f, 0x7f][..]).is_err());
+        assert!(Command::try_from(&[0xb0, 0x7f, 0x02][..]).is_err());
+        assert!(Command::try_from(&[0xb0, 0x30, 0x30][..]).is_err());
+        assert!(Command::try_from(&[0xb0, 0x38, 0x00][..]).is_err());
+        assert!(Command::try_from(&[0xe8, 0x00, 0x00][..]).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Recording controller events to JSON Lines files, and replaying them.
+//!
+//! Each line is an [`Entry`], e.g.
+//! `{"time_ms":1520,"type":"knob_turned","knob":"Knob8","delta":3}`.
+//!
+//! Recordings of an [`EventStream`] made with [`Recorder::wrap_input`] also keep each MIDI
+//! message as received, including ones that couldn't be parsed, e.g.
+//! `{"time_ms":1520,"bytes":[176,1,70]}`. Replays parse those again, so they go through the
+//! same code as live input.
+//!
+//! Recordings also work as fixtures in tests, by replaying them instantly into a
+//! [`Router`](crate::Router).
+
+use crate::input::EventStream;
+use crate::model::Event;
+use anyhow::{Context as _, Result};
+use futures::future;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// A recorded event, and when it happened.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the recording started
+    pub time_ms: u64,
+    /// `None` if the MIDI message couldn't be parsed
+    #[serde(flatten)]
+    pub event: Option<Event>,
+    /// The MIDI message as received, if the recording included it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl Entry {
+    pub fn time(&self) -> Duration {
+        Duration::from_millis(self.time_ms)
+    }
+
+    /// The recorded event, parsed from its MIDI message if there is one, so replays go
+    /// through the same parsing as live input.
+    pub fn to_event(&self) -> Result<Event> {
+        match &self.bytes {
+            Some(bytes) => Event::try_from(bytes.as_slice()),
+            None => self
+                .event
+                .clone()
+                .context("recording entry has neither an event nor a MIDI message"),
+        }
+    }
+
+    /// The MIDI message as received, or one made up from the event if it wasn't recorded.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        match (&self.bytes, &self.event) {
+            (Some(bytes), _) => Some(bytes.clone()),
+            (None, Some(event)) => Some(event.to_bytes().to_vec()),
+            (None, None) => None,
+        }
+    }
+}
+
+pub struct Recorder<W: Write> {
+    writer: W,
+    started: Instant,
+}
+
+impl Recorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("failed to create recording {:?}", path))?;
+
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, event: &Event) -> Result<()> {
+        self.write(Some(event.clone()), None)
+    }
+
+    /// Records a MIDI message as received, along with the event parsed from it (if any).
+    pub fn record_raw(&mut self, bytes: &[u8], event: &Result<Event>) -> Result<()> {
+        self.write(event.as_ref().ok().cloned(), Some(bytes.to_vec()))
+    }
+
+    fn write(&mut self, event: Option<Event>, bytes: Option<Vec<u8>>) -> Result<()> {
+        let entry = Entry {
+            time_ms: self.started.elapsed().as_millis() as u64,
+            event,
+            bytes,
+        };
+
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+
+        // Flushed on every event, so recordings survive the process being killed
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Records every event passing through the stream, e.g. an
+    /// [`EventStream`](crate::EventStream).
+    pub fn wrap<S>(mut self, events: S) -> impl Stream<Item = Result<Event>>
+    where
+        S: Stream<Item = Result<Event>>,
+    {
+        events.map(move |event| {
+            if let Ok(event) = &event {
+                if let Err(error) = self.record(event) {
+                    error!(?error, "Failed to record event");
+                }
+            }
+
+            event
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send + 'static> Recorder<W> {
+    /// Records every MIDI message coming from the controller, as received.
+    pub fn wrap_input(mut self, events: EventStream) -> EventStream {
+        events.inspect_raw(move |bytes, event| {
+            if let Err(error) = self.record_raw(bytes, event) {
+                error!(?error, "Failed to record MIDI message");
+            }
+        })
+    }
+}
+
+/// Plays back a recording, keeping the original timing (optionally sped up).
+#[derive(Clone, Debug)]
+pub struct Replay {
+    entries: Vec<Entry>,
+    speed: Option<f64>,
+}
+
+impl Replay {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("failed to open recording {:?}", path))?;
+
+        Self::from_reader(BufReader::new(file))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry = serde_json::from_str(&line)
+                .with_context(|| format!("invalid recording entry on line {}", i + 1))?;
+            entries.push(entry);
+        }
+
+        Ok(Self::new(entries))
+    }
+
+    pub fn new(entries: Vec<Entry>) -> Self {
+        Self {
+            entries,
+            speed: Some(1.0),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Plays back faster (e.g. `4.0`) or slower (e.g. `0.5`) than recorded.
+    pub fn speed(mut self, multiplier: f64) -> Self {
+        self.speed = Some(multiplier);
+        self
+    }
+
+    /// Plays back every event without waiting, e.g. for tests.
+    pub fn instant(mut self) -> Self {
+        self.speed = None;
+        self
+    }
+
+    pub fn into_stream(self) -> BoxStream<'static, Result<Event>> {
+        self.play(Entry::to_event)
+    }
+
+    /// Plays back the MIDI messages instead, e.g. to send to a virtual device. Messages that
+    /// couldn't be parsed when recorded are sent as they were.
+    pub fn into_midi_stream(self) -> BoxStream<'static, Vec<u8>> {
+        self.play(Entry::to_bytes).filter_map(future::ready).boxed()
+    }
+
+    fn play<T, F>(self, item: F) -> BoxStream<'static, T>
+    where
+        T: Send + 'static,
+        F: Fn(&Entry) -> T + Send + Copy + 'static,
+    {
+        let speed = self.speed.filter(|speed| *speed > 0.0);
+
+        // Timing starts from the first poll, rather than when the stream was created
+        stream::unfold(
+            (self.entries.into_iter(), None),
+            move |(mut entries, started)| async move {
+                let entry = entries.next()?;
+                let started = started.unwrap_or_else(tokio::time::Instant::now);
+
+                if let Some(speed) = speed {
+                    tokio::time::sleep_until(started + entry.time().div_f64(speed)).await;
+                }
+
+                Some((item(&entry), (entries, Some(started))))
+            },
+        )
+        .boxed()
+    }
+}
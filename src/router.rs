@@ -0,0 +1,702 @@
+//! Dispatching controller events to handlers by control, layer and gesture.
+
+use crate::metrics;
+use crate::model::{Button, ButtonLedState, ControllerState, Event, Knob};
+use crate::output::Controller;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{Future, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, Instrument};
+
+/// State that handlers run with. The router needs the controller to know the current layer.
+pub trait HasController: Send {
+    fn controller(&self) -> &Controller;
+    fn controller_mut(&mut self) -> &mut Controller;
+}
+
+impl HasController for Controller {
+    fn controller(&self) -> &Controller {
+        self
+    }
+
+    fn controller_mut(&mut self) -> &mut Controller {
+        self
+    }
+}
+
+#[async_trait]
+pub trait EventHandler<C>: Send + Sync {
+    async fn handle(&self, context: &mut C, event: &Event) -> Result<()>;
+}
+
+/// Lets `async fn handler(context: &mut C, event: &Event) -> Result<()>` be used as an
+/// [`EventHandler`].
+pub trait HandlerFn<'a, C: 'a>: Send + Sync {
+    type Future: Future<Output = Result<()>> + Send + 'a;
+
+    fn call(&self, context: &'a mut C, event: &'a Event) -> Self::Future;
+}
+
+impl<'a, C, F, Fut> HandlerFn<'a, C> for F
+where
+    C: 'a,
+    F: Fn(&'a mut C, &'a Event) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'a,
+{
+    type Future = Fut;
+
+    fn call(&self, context: &'a mut C, event: &'a Event) -> Fut {
+        self(context, event)
+    }
+}
+
+#[async_trait]
+impl<C, F> EventHandler<C> for F
+where
+    C: Send,
+    F: for<'a> HandlerFn<'a, C>,
+{
+    async fn handle(&self, context: &mut C, event: &Event) -> Result<()> {
+        self.call(context, event).await
+    }
+}
+
+/// Wraps event dispatch. Middleware added to a [`Router`] first runs outermost.
+#[async_trait]
+pub trait Middleware<C>: Send + Sync {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()>;
+}
+
+/// The rest of the middleware chain, followed by the matching handlers.
+pub struct Next<'a, C> {
+    middleware: &'a [Box<dyn Middleware<C>>],
+    routes: &'a [(Route, Box<dyn EventHandler<C>>)],
+}
+
+impl<'a, C: HasController> Next<'a, C> {
+    pub async fn run(self, context: &mut C, event: &Event) -> Result<()> {
+        if let Some((first, rest)) = self.middleware.split_first() {
+            let next = Next {
+                middleware: rest,
+                routes: self.routes,
+            };
+            return first.handle(context, event, next).await;
+        }
+
+        let layer = Layer::current(context.controller().state());
+        for (route, handler) in self.routes {
+            if route.matches(event, layer) {
+                return handler.handle(context, event).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Selected with the layer buttons. If both are lit, layer A takes precedence.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Default,
+    A,
+    B,
+}
+
+impl Layer {
+    pub fn current(state: &ControllerState) -> Self {
+        if state.button(Button::LayerA).is_on() {
+            Self::A
+        } else if state.button(Button::LayerB).is_on() {
+            Self::B
+        } else {
+            Self::Default
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Control {
+    Button(Button),
+    Knob(Knob),
+    Fader,
+}
+
+impl From<&Event> for Control {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::ButtonPressed { button, .. } => Self::Button(*button),
+            Event::KnobPressed { knob, .. } | Event::KnobTurned { knob, .. } => Self::Knob(*knob),
+            Event::FaderMoved { .. } => Self::Fader,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    /// Button or knob pressed down
+    Press,
+    /// Button or knob released
+    Release,
+    Turn,
+    Move,
+}
+
+impl From<&Event> for Gesture {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::ButtonPressed { is_down: true, .. }
+            | Event::KnobPressed { is_down: true, .. } => Self::Press,
+            Event::ButtonPressed { .. } | Event::KnobPressed { .. } => Self::Release,
+            Event::KnobTurned { .. } => Self::Turn,
+            Event::FaderMoved { .. } => Self::Move,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Controls {
+    Any,
+    Buttons,
+    Knobs,
+    One(Control),
+}
+
+/// Which events a handler receives. Unset parts match anything.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    controls: Controls,
+    layer: Option<Layer>,
+    gesture: Option<Gesture>,
+}
+
+impl Route {
+    pub fn any() -> Self {
+        Self {
+            controls: Controls::Any,
+            layer: None,
+            gesture: None,
+        }
+    }
+
+    pub fn buttons() -> Self {
+        Self::with_controls(Controls::Buttons)
+    }
+
+    pub fn knobs() -> Self {
+        Self::with_controls(Controls::Knobs)
+    }
+
+    pub fn button(button: Button) -> Self {
+        Self::with_controls(Controls::One(Control::Button(button)))
+    }
+
+    pub fn knob(knob: Knob) -> Self {
+        Self::with_controls(Controls::One(Control::Knob(knob)))
+    }
+
+    pub fn fader() -> Self {
+        Self::with_controls(Controls::One(Control::Fader))
+    }
+
+    pub fn layer(mut self, layer: Layer) -> Self {
+        self.layer = Some(layer);
+        self
+    }
+
+    pub fn gesture(mut self, gesture: Gesture) -> Self {
+        self.gesture = Some(gesture);
+        self
+    }
+
+    pub fn matches(&self, event: &Event, layer: Layer) -> bool {
+        let control = Control::from(event);
+        let controls_match = match self.controls {
+            Controls::Any => true,
+            Controls::Buttons => matches!(control, Control::Button(_)),
+            Controls::Knobs => matches!(control, Control::Knob(_)),
+            Controls::One(expected) => control == expected,
+        };
+
+        controls_match
+            && self.layer.iter().all(|expected| *expected == layer)
+            && self
+                .gesture
+                .iter()
+                .all(|expected| *expected == Gesture::from(event))
+    }
+
+    fn with_controls(controls: Controls) -> Self {
+        Self {
+            controls,
+            ..Self::any()
+        }
+    }
+}
+
+/// Sends each event to the first route that matches it, through any middleware.
+pub struct Router<C> {
+    routes: Vec<(Route, Box<dyn EventHandler<C>>)>,
+    middleware: Vec<Box<dyn Middleware<C>>>,
+}
+
+impl<C: HasController> Router<C> {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Routes are tried in the order they're added.
+    pub fn route<H: EventHandler<C> + 'static>(mut self, route: Route, handler: H) -> Self {
+        self.routes.push((route, Box::new(handler)));
+        self
+    }
+
+    pub fn middleware<M: Middleware<C> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub async fn dispatch(&self, context: &mut C, event: &Event) -> Result<()> {
+        let next = Next {
+            middleware: &self.middleware,
+            routes: &self.routes,
+        };
+
+        let metrics = metrics::global();
+        metrics.events.increment();
+
+        let start = Instant::now();
+        let result = next
+            .run(context, event)
+            .instrument(debug_span!("dispatch", ?event))
+            .await;
+        metrics.handler_duration.record_since(start);
+
+        result
+    }
+
+    /// Dispatches events until the stream ends. Handler errors are logged, and don't stop
+    /// the loop.
+    pub async fn run<S>(&self, context: &mut C, mut events: S) -> Result<()>
+    where
+        S: Stream<Item = Result<Event>> + Unpin,
+    {
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(error) => {
+                    debug!(?error, "Ignoring unrecognized MIDI message");
+                    continue;
+                }
+            };
+
+            if let Err(error) = self.dispatch(context, &event).await {
+                error!(?error, ?event, "Failed to handle event");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<C: HasController> Default for Router<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Logs each event, along with how long it took to handle.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Logging;
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for Logging {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let start = Instant::now();
+        let result = next.run(context, event).await;
+        debug!(?event, elapsed = ?start.elapsed(), ok = result.is_ok(), "Handled event");
+        result
+    }
+}
+
+/// Drops events from a control that arrive too soon after the previous one (including dropped
+/// ones), e.g. to stop a fast knob turn from switching through many tabs at once.
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    only: Vec<Route>,
+    last_seen: Mutex<HashMap<Control, Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            only: Vec::new(),
+            last_seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only limits events matching the route. Call it again to also limit other routes.
+    pub fn only(mut self, route: Route) -> Self {
+        self.only.push(route);
+        self
+    }
+
+    /// Dropped events also restart the interval, so a control is only let through again once
+    /// it's been left alone for the whole interval. This makes it a debounce rather than a
+    /// throttle: a continuous turn gets through once, not once per interval.
+    fn allow(&self, control: Control) -> bool {
+        let now = Instant::now();
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let previous = last_seen.insert(control, now);
+
+        match previous {
+            Some(previous) => now.duration_since(previous) >= self.interval,
+            None => true,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for RateLimit {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        if !self.only.is_empty() {
+            let layer = Layer::current(context.controller().state());
+            if !self.only.iter().any(|route| route.matches(event, layer)) {
+                return next.run(context, event).await;
+            }
+        }
+
+        if self.allow(Control::from(event)) {
+            next.run(context, event).await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sets a button LED when a handler fails, e.g. to show that a connection was lost.
+#[derive(Copy, Clone, Debug)]
+pub struct ErrorLed {
+    button: Button,
+    state: ButtonLedState,
+}
+
+impl ErrorLed {
+    /// Turns the button off on error.
+    pub fn new(button: Button) -> Self {
+        Self {
+            button,
+            state: ButtonLedState::Off,
+        }
+    }
+
+    pub fn state(mut self, state: ButtonLedState) -> Self {
+        self.state = state;
+        self
+    }
+}
+
+#[async_trait]
+impl<C: HasController> Middleware<C> for ErrorLed {
+    async fn handle(&self, context: &mut C, event: &Event, next: Next<'_, C>) -> Result<()> {
+        let result = next.run(context, event).await;
+
+        if result.is_err() {
+            // The handler's error is more useful to return than a failure to set the LED
+            if let Err(error) = context.controller_mut().set_button(self.button, self.state) {
+                error!(?error, "Failed to set error LED");
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Context {
+        controller: Controller,
+        log: Vec<&'static str>,
+    }
+
+    impl HasController for Context {
+        fn controller(&self) -> &Controller {
+            &self.controller
+        }
+
+        fn controller_mut(&mut self) -> &mut Controller {
+            &mut self.controller
+        }
+    }
+
+    fn context() -> Context {
+        let (controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+        tokio::spawn(worker);
+
+        Context {
+            controller,
+            log: Vec::new(),
+        }
+    }
+
+    async fn layer_a(context: &mut Context, _event: &Event) -> Result<()> {
+        context.log.push("layer a");
+        Ok(())
+    }
+
+    async fn press(context: &mut Context, _event: &Event) -> Result<()> {
+        context.log.push("press");
+        Ok(())
+    }
+
+    async fn catch_all(context: &mut Context, _event: &Event) -> Result<()> {
+        context.log.push("catch all");
+        Ok(())
+    }
+
+    /// Logs its name around the rest of the chain, or stops it.
+    struct Tag {
+        name: &'static str,
+        stop: bool,
+    }
+
+    #[async_trait]
+    impl Middleware<Context> for Tag {
+        async fn handle(
+            &self,
+            context: &mut Context,
+            event: &Event,
+            next: Next<'_, Context>,
+        ) -> Result<()> {
+            context.log.push(self.name);
+            if self.stop {
+                return Ok(());
+            }
+
+            let result = next.run(context, event).await;
+            context.log.push(self.name);
+            result
+        }
+    }
+
+    fn turn(knob: Knob) -> Event {
+        Event::KnobTurned { knob, delta: 1 }
+    }
+
+    fn button(button: Button, is_down: bool) -> Event {
+        Event::ButtonPressed { button, is_down }
+    }
+
+    async fn dispatch_all(router: &Router<Context>, context: &mut Context, events: &[Event]) {
+        for event in events {
+            router.dispatch(context, event).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn layer_a_takes_precedence() {
+        let mut context = context();
+        for button in &[Button::LayerA, Button::LayerB] {
+            context
+                .controller
+                .set_button(*button, ButtonLedState::On)
+                .unwrap();
+        }
+
+        assert_eq!(Layer::current(context.controller.state()), Layer::A);
+    }
+
+    #[tokio::test]
+    async fn routes_to_first_match() {
+        let router = Router::new()
+            .route(Route::knob(Knob::Knob1).layer(Layer::A), layer_a)
+            .route(Route::buttons().gesture(Gesture::Press), press)
+            .route(Route::any(), catch_all);
+        let mut context = context();
+
+        dispatch_all(
+            &router,
+            &mut context,
+            &[turn(Knob::Knob1), button(Button::Button1, true)],
+        )
+        .await;
+        assert_eq!(context.log, vec!["catch all", "press"]);
+
+        context
+            .controller
+            .set_button(Button::LayerA, ButtonLedState::On)
+            .unwrap();
+        context.log.clear();
+
+        dispatch_all(
+            &router,
+            &mut context,
+            &[
+                turn(Knob::Knob1),
+                turn(Knob::Knob2),
+                button(Button::Button1, false),
+            ],
+        )
+        .await;
+        assert_eq!(context.log, vec!["layer a", "catch all", "catch all"]);
+    }
+
+    #[test]
+    fn matches_gestures_and_layers() {
+        let press = Route::buttons().gesture(Gesture::Press);
+        assert!(press.matches(&button(Button::Button1, true), Layer::Default));
+        assert!(!press.matches(&button(Button::Button1, false), Layer::Default));
+        assert!(!press.matches(&turn(Knob::Knob1), Layer::Default));
+
+        let release = Route::button(Button::Button2).gesture(Gesture::Release);
+        assert!(release.matches(&button(Button::Button2, false), Layer::B));
+        assert!(!release.matches(&button(Button::Button3, false), Layer::B));
+
+        let layer_b = Route::knobs().layer(Layer::B).gesture(Gesture::Turn);
+        assert!(layer_b.matches(&turn(Knob::Knob8), Layer::B));
+        assert!(!layer_b.matches(&turn(Knob::Knob8), Layer::A));
+        assert!(!layer_b.matches(
+            &Event::KnobPressed {
+                knob: Knob::Knob8,
+                is_down: true,
+            },
+            Layer::B
+        ));
+    }
+
+    #[tokio::test]
+    async fn runs_middleware_in_order() {
+        let router = Router::new()
+            .middleware(Tag {
+                name: "outer",
+                stop: false,
+            })
+            .middleware(Tag {
+                name: "inner",
+                stop: false,
+            })
+            .route(Route::any(), catch_all);
+        let mut context = context();
+
+        router
+            .dispatch(&mut context, &turn(Knob::Knob1))
+            .await
+            .unwrap();
+        assert_eq!(
+            context.log,
+            vec!["outer", "inner", "catch all", "inner", "outer"]
+        );
+    }
+
+    #[tokio::test]
+    async fn middleware_can_stop_dispatch() {
+        let router = Router::new()
+            .middleware(Tag {
+                name: "outer",
+                stop: false,
+            })
+            .middleware(Tag {
+                name: "stop",
+                stop: true,
+            })
+            .middleware(Tag {
+                name: "unreached",
+                stop: false,
+            })
+            .route(Route::any(), catch_all);
+        let mut context = context();
+
+        router
+            .dispatch(&mut context, &turn(Knob::Knob1))
+            .await
+            .unwrap();
+        assert_eq!(context.log, vec!["outer", "stop", "outer"]);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_drops_repeated_events() {
+        async fn knob1(context: &mut Context, _event: &Event) -> Result<()> {
+            context.log.push("knob 1");
+            Ok(())
+        }
+
+        async fn knob2(context: &mut Context, _event: &Event) -> Result<()> {
+            context.log.push("knob 2");
+            Ok(())
+        }
+
+        let router = Router::new()
+            .middleware(
+                RateLimit::new(Duration::from_secs(3600))
+                    .only(Route::knobs().gesture(Gesture::Turn)),
+            )
+            .route(Route::knob(Knob::Knob1).gesture(Gesture::Turn), knob1)
+            .route(Route::knob(Knob::Knob2).gesture(Gesture::Turn), knob2)
+            .route(Route::any(), catch_all);
+        let mut context = context();
+
+        dispatch_all(
+            &router,
+            &mut context,
+            &[
+                turn(Knob::Knob1),
+                turn(Knob::Knob1),
+                // Each knob has its own limit, so the first turn of another one still passes
+                turn(Knob::Knob2),
+                turn(Knob::Knob2),
+                turn(Knob::Knob1),
+                // Not limited at all
+                Event::KnobPressed {
+                    knob: Knob::Knob1,
+                    is_down: true,
+                },
+            ],
+        )
+        .await;
+        assert_eq!(context.log, vec!["knob 1", "knob 2", "catch all"]);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_passes_spaced_events() {
+        let router = Router::new()
+            .middleware(RateLimit::new(Duration::from_millis(0)))
+            .route(Route::any(), catch_all);
+        let mut context = context();
+
+        dispatch_all(
+            &router,
+            &mut context,
+            &[turn(Knob::Knob1), turn(Knob::Knob1), turn(Knob::Knob1)],
+        )
+        .await;
+        assert_eq!(context.log.len(), 3);
+    }
+
+    #[test]
+    fn rate_limit_restarts_interval_on_dropped_events() {
+        let limit = RateLimit::new(Duration::from_secs(1));
+        let control = Control::Knob(Knob::Knob1);
+        limit
+            .last_seen
+            .lock()
+            .unwrap()
+            .insert(control, Instant::now() - Duration::from_millis(500));
+
+        let before = Instant::now();
+        assert!(!limit.allow(control));
+        assert!(limit.last_seen.lock().unwrap()[&control] >= before);
+    }
+}
@@ -0,0 +1,528 @@
+//! Event handlers written in [Rhai](https://rhai.rs), reloaded whenever the script changes.
+//!
+//! The script should define `fn on_event(event)`, which is called for every controller event.
+//! `event` is a map with a `type` (`"button"`, `"knob_pressed"`, `"knob_turned"` or `"fader"`)
+//! and the fields of the corresponding [`Event`] variant, with controls given by name (e.g.
+//! `event.button == "Button5"`).
+//!
+//! Functions available to scripts:
+//!
+//! * `set_button(button, state)`, with `state` being `"on"`, `"off"` or `"blink"`
+//! * `set_knob(knob, style, percent)`, with `style` being a [`KnobLedStyle`] name
+//! * `button_state(button)`, `knob_value(knob)`, `fader()` (0.0 to 1.0)
+//! * `tap(combo)` (e.g. `tap("ctrl+shift+tab")`), `type_text(text)`
+//! * `scroll(clicks)`, `move_mouse(dx, dy)`, `click()`
+//! * `vtube_param(name)`, `vtube_set_param(name, value)`, `vtube_hotkey(name)`. Params that
+//!   aren't built in (e.g. ones created by plugins) are set through the plugin API
+//! * `get_var(name)`, `set_var(name, value)`, for values that persist between events
+//!
+//! Scripts are limited in how many operations they run per event, how deep calls nest, and
+//! how large strings, arrays and maps get, so a runaway script errors out instead of
+//! freezing event handling.
+//!
+//! Actions are performed in order after `on_event` returns. Errors in the script itself are
+//! tagged with [`Source::Script`], for [`StatusIndicators`](crate::status::StatusIndicators).
+
+use crate::keyboard::{self, KeyCombo};
+use crate::model::{
+    Button, ButtonLedState, ControllerState, Event, Knob, KnobLedStyle, KnobLedValue,
+};
+use crate::mouse::{self, Axis, MouseButton};
+use crate::output::Controller;
+use crate::status::{Source, WithSource};
+use crate::vtubestudio::{self, plugin, ParamId};
+use anyhow::{anyhow, Context as _, Result};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{debug, error};
+
+const HANDLER_FN: &str = "on_event";
+
+// Limits that stop a runaway script (e.g. an endless loop) from freezing event handling
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    SetButton(Button, ButtonLedState),
+    SetKnob(Knob, KnobLedStyle, KnobLedValue),
+    Tap(KeyCombo),
+    TypeText(String),
+    Scroll(i32),
+    MoveMouse(i32, i32),
+    Click,
+    SetParam(String, f64),
+    TriggerHotkey(String),
+}
+
+/// What scripts can see while handling an event.
+#[derive(Default)]
+struct Shared {
+    state: ControllerState,
+    params: HashMap<String, f64>,
+    vars: HashMap<String, Dynamic>,
+    actions: Vec<Action>,
+}
+
+type SharedRef = Arc<Mutex<Shared>>;
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+pub struct ScriptEngine {
+    engine: Engine,
+    path: PathBuf,
+    ast: AST,
+    modified: Option<SystemTime>,
+    shared: SharedRef,
+}
+
+impl ScriptEngine {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let shared = SharedRef::default();
+        let engine = create_engine(&shared);
+        let path = path.as_ref().to_owned();
+
+        let mut script = Self {
+            engine,
+            ast: AST::empty(),
+            modified: None,
+            path,
+            shared,
+        };
+
+        script.load()?;
+        Ok(script)
+    }
+
+    /// Reloads the script if the file has changed since it was last loaded. If the new
+    /// version fails to compile, the previous one is kept until the file changes again.
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        if modified_time(&self.path)? == self.modified {
+            return Ok(false);
+        }
+
+        self.load()?;
+        debug!(path = ?self.path, "Reloaded script");
+        Ok(true)
+    }
+
+    /// Runs the script's handler for an event, then performs the actions it requested.
+    pub async fn handle(
+        &mut self,
+        event: &Event,
+        controller: &mut Controller,
+        mut vtube: Option<&mut vtubestudio::Client>,
+        mut plugin: Option<&mut plugin::Client>,
+    ) -> Result<()> {
+        if let Err(error) = self.reload_if_changed() {
+            error!(?error, path = ?self.path, "Failed to reload script");
+        }
+
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.state = controller.state().clone();
+
+            // The plugin API doesn't report custom param values, so the last ones set by the
+            // script are kept
+            shared
+                .params
+                .retain(|name, _| matches!(ParamId::from(name.as_str()), ParamId::Custom(_)));
+            if let Some(vtube) = &vtube {
+                let params = vtube.params().iter();
+                shared
+                    .params
+                    .extend(params.map(|(param, value)| (param.as_ref().to_owned(), *value)));
+            }
+            shared.actions.clear();
+        }
+
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            HANDLER_FN,
+            (event_to_map(event),),
+        );
+
+        let actions = std::mem::take(&mut self.shared.lock().unwrap().actions);
+        if let Err(error) = result {
+            return Err(anyhow!("script error: {}", error)).with_source(Source::Script);
+        }
+
+        for action in actions {
+            match action {
+                Action::SetButton(button, state) => controller.set_button(button, state)?,
+                Action::SetKnob(knob, style, value) => controller.set_knob(knob, style, value)?,
+                Action::Tap(combo) => combo.tap()?,
+                Action::TypeText(text) => keyboard::type_text(&text).await?,
+                Action::Scroll(clicks) => mouse::scroll(Axis::Vertical, clicks)?,
+                Action::MoveMouse(dx, dy) => mouse::move_by(dx, dy)?,
+                Action::Click => mouse::click(MouseButton::Left)?,
+                // Only built-in params can be set through the tracking connection, so custom
+                // ones go through the plugin API
+                Action::SetParam(name, value) => match (ParamId::from(name), &mut vtube) {
+                    (ParamId::Builtin(param), Some(vtube)) => vtube.set_param(param, value).await?,
+                    (ParamId::Custom(name), _) => {
+                        if let Some(plugin) = plugin.as_mut() {
+                            plugin.inject_params(vec![(name, value)]).await?;
+                        }
+                    }
+                    (ParamId::Builtin(_), None) => {}
+                },
+                Action::TriggerHotkey(name) => {
+                    if let Some(plugin) = plugin.as_mut() {
+                        plugin.trigger_hotkey(&name).await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<()> {
+        // Recorded even if compiling fails, so a broken script is reported once rather than
+        // on every event
+        self.modified = modified_time(&self.path)?;
+
+        self.ast = self
+            .engine
+            .compile_file(self.path.clone())
+            .map_err(|error| anyhow!("failed to compile {:?}: {}", self.path, error))?;
+        Ok(())
+    }
+}
+
+fn modified_time(path: &Path) -> Result<Option<SystemTime>> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("failed to read script {:?}", path))?;
+    Ok(metadata.modified().ok())
+}
+
+fn event_to_map(event: &Event) -> Map {
+    let mut map = Map::new();
+    let mut insert = |key: &str, value: Dynamic| {
+        map.insert(key.into(), value);
+    };
+
+    match event {
+        Event::ButtonPressed { button, is_down } => {
+            insert("type", "button".into());
+            insert("button", format!("{:?}", button).into());
+            insert("is_down", (*is_down).into());
+        }
+        Event::KnobPressed { knob, is_down } => {
+            insert("type", "knob_pressed".into());
+            insert("knob", format!("{:?}", knob).into());
+            insert("is_down", (*is_down).into());
+        }
+        Event::KnobTurned { knob, delta } => {
+            insert("type", "knob_turned".into());
+            insert("knob", format!("{:?}", knob).into());
+            insert("delta", (*delta as i64).into());
+        }
+        Event::FaderMoved { value } => {
+            insert("type", "fader".into());
+            insert("value", value.as_percent().into());
+        }
+    }
+
+    map
+}
+
+fn parse<T: FromStr>(kind: &str, name: &str) -> ScriptResult<T> {
+    name.parse()
+        .map_err(|_| format!("unknown {} {:?}", kind, name).into())
+}
+
+fn create_engine(shared: &SharedRef) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE);
+
+    let push = |shared: &SharedRef, action: Action| shared.lock().unwrap().actions.push(action);
+
+    let s = shared.clone();
+    engine.register_fn(
+        "set_button",
+        move |button: &str, state: &str| -> ScriptResult<()> {
+            let action = Action::SetButton(parse("button", button)?, parse("LED state", state)?);
+            push(&s, action);
+            Ok(())
+        },
+    );
+
+    let s = shared.clone();
+    engine.register_fn(
+        "set_knob",
+        move |knob: &str, style: &str, percent: f64| -> ScriptResult<()> {
+            let action = Action::SetKnob(
+                parse("knob", knob)?,
+                parse("knob style", style)?,
+                KnobLedValue::from_percent(percent),
+            );
+            push(&s, action);
+            Ok(())
+        },
+    );
+
+    let s = shared.clone();
+    engine.register_fn(
+        "button_state",
+        move |button: &str| -> ScriptResult<String> {
+            let button = parse::<Button>("button", button)?;
+            let state = *s.lock().unwrap().state.button(button);
+            Ok(state.as_ref().to_owned())
+        },
+    );
+
+    let s = shared.clone();
+    engine.register_fn("knob_value", move |knob: &str| -> ScriptResult<i64> {
+        let knob = parse::<Knob>("knob", knob)?;
+        Ok(s.lock().unwrap().state.knob(knob).value as i64)
+    });
+
+    let s = shared.clone();
+    engine.register_fn("fader", move || {
+        s.lock().unwrap().state.fader().as_percent()
+    });
+
+    let s = shared.clone();
+    engine.register_fn("tap", move |combo: &str| -> ScriptResult<()> {
+        let combo = combo
+            .parse::<KeyCombo>()
+            .map_err(|error| error.to_string())?;
+        push(&s, Action::Tap(combo));
+        Ok(())
+    });
+
+    let s = shared.clone();
+    engine.register_fn("type_text", move |text: &str| {
+        push(&s, Action::TypeText(text.to_owned()))
+    });
+
+    let s = shared.clone();
+    engine.register_fn("scroll", move |clicks: i64| {
+        push(&s, Action::Scroll(clicks as i32))
+    });
+
+    let s = shared.clone();
+    engine.register_fn("move_mouse", move |dx: i64, dy: i64| {
+        push(&s, Action::MoveMouse(dx as i32, dy as i32))
+    });
+
+    let s = shared.clone();
+    engine.register_fn("click", move || push(&s, Action::Click));
+
+    let s = shared.clone();
+    engine.register_fn("vtube_param", move |name: &str| {
+        s.lock()
+            .unwrap()
+            .params
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    });
+
+    let s = shared.clone();
+    engine.register_fn("vtube_set_param", move |name: &str, value: f64| {
+        let mut shared = s.lock().unwrap();
+        shared.params.insert(name.to_owned(), value);
+        shared
+            .actions
+            .push(Action::SetParam(name.to_owned(), value));
+    });
+
+    let s = shared.clone();
+    engine.register_fn("vtube_hotkey", move |name: &str| {
+        push(&s, Action::TriggerHotkey(name.to_owned()))
+    });
+
+    let s = shared.clone();
+    engine.register_fn("get_var", move |name: &str| {
+        s.lock()
+            .unwrap()
+            .vars
+            .get(name)
+            .cloned()
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    let s = shared.clone();
+    engine.register_fn("set_var", move |name: &str, value: Dynamic| {
+        s.lock().unwrap().vars.insert(name.to_owned(), value);
+    });
+
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn script_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("xtouchmini-{}-{}.rhai", name, std::process::id()))
+    }
+
+    fn controller() -> Result<Controller> {
+        let (controller, worker) = Controller::with_output(|_| Ok(()))?;
+        tokio::spawn(worker);
+        Ok(controller)
+    }
+
+    fn press() -> Event {
+        Event::ButtonPressed {
+            button: Button::Button1,
+            is_down: true,
+        }
+    }
+
+    async fn run_script(name: &str, source: &str, controller: &mut Controller) -> Result<()> {
+        let path = script_path(name);
+        std::fs::write(&path, source)?;
+
+        let result = ScriptEngine::new(&path)?
+            .handle(&press(), controller, None, None)
+            .await;
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn stops_runaway_scripts() -> Result<()> {
+        let scripts = [
+            (
+                "loop",
+                "fn on_event(event) { loop {} }",
+                "Too many operations",
+            ),
+            (
+                "recursion",
+                "fn f(x) { f(x + 1) } fn on_event(event) { f(0) }",
+                "Stack overflow",
+            ),
+            (
+                "string",
+                r#"fn on_event(event) { let s = "x"; loop { s += s; } }"#,
+                "Length of string too large",
+            ),
+        ];
+
+        let mut controller = controller()?;
+        for (name, source, expected) in scripts.iter() {
+            let error = run_script(name, source, &mut controller).await.unwrap_err();
+            assert!(
+                format!("{:#}", error).contains(expected),
+                "{} script failed with {:#}",
+                name,
+                error
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_well_behaved_scripts() -> Result<()> {
+        let source = r#"
+            fn on_event(event) {
+                set_button(event.button, "on");
+                set_knob("Knob2", "fan", 0.5);
+                if button_state("Button2") == "blink" {
+                    set_button("Button3", "blink");
+                }
+            }
+        "#;
+
+        let mut controller = controller()?;
+        controller.set_button(Button::Button2, ButtonLedState::Blink)?;
+        run_script("ok", source, &mut controller).await?;
+
+        let state = controller.state();
+        assert_eq!(*state.button(Button::Button1), ButtonLedState::On);
+        assert_eq!(*state.button(Button::Button3), ButtonLedState::Blink);
+        assert_eq!(state.knob(Knob::Knob2).style, KnobLedStyle::Fan);
+        assert_eq!(
+            state.knob(Knob::Knob2).led_value,
+            KnobLedValue::from_percent(0.5)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn queues_actions_in_order() -> Result<()> {
+        let path = script_path("actions");
+        let source = r#"
+            fn on_event(event) {
+                tap("ctrl+shift+tab");
+                vtube_set_param("FaceAngleX", vtube_param("FaceAngleX") + 1.0);
+                vtube_hotkey("Wave");
+                scroll(-2);
+            }
+        "#;
+        std::fs::write(&path, source)?;
+
+        let script = ScriptEngine::new(&path)?;
+        std::fs::remove_file(&path)?;
+
+        script.engine.call_fn::<()>(
+            &mut Scope::new(),
+            &script.ast,
+            HANDLER_FN,
+            (event_to_map(&press()),),
+        )?;
+
+        let actions = std::mem::take(&mut script.shared.lock().unwrap().actions);
+        assert_eq!(
+            actions,
+            vec![
+                Action::Tap("ctrl+shift+tab".parse()?),
+                Action::SetParam("FaceAngleX".to_owned(), 1.0),
+                Action::TriggerHotkey("Wave".to_owned()),
+                Action::Scroll(-2),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_compile_errors_once_per_change() -> Result<()> {
+        let path = script_path("reload");
+        std::fs::write(
+            &path,
+            r#"fn on_event(event) { set_button(event.button, "on"); }"#,
+        )?;
+        let mut script = ScriptEngine::new(&path)?;
+
+        // Make sure the change is noticed, however coarse the file system's timestamps are
+        std::fs::write(&path, "fn on_event(event) {")?;
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))?;
+
+        assert!(script.reload_if_changed().is_err());
+        assert!(!script.reload_if_changed()?);
+
+        // The previous version keeps handling events
+        let mut controller = controller()?;
+        script.handle(&press(), &mut controller, None, None).await?;
+        assert_eq!(
+            *controller.state().button(Button::Button1),
+            ButtonLedState::On
+        );
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+//! A software X-Touch Mini in the terminal, for developing bindings without the device.
+//!
+//! Controls:
+//! * `q` to `i`, `a` to `k`: top and bottom row buttons
+//! * `z`, `x`: layer A and B buttons
+//! * Left/Right: select a knob, Up/Down: turn it (PageUp/PageDown for bigger turns), Enter:
+//!   press it
+//! * `-`, `=`: move the fader
+//! * Esc or Ctrl+C: quit
+
+use crate::input::EventStream;
+use crate::model::{Button, ButtonLedState, ControllerState, Event, FaderValue, Knob};
+use crate::model::{KnobLedStyle, KnobLedValue};
+use crate::output::{Command, Controller};
+use anyhow::{Context as _, Result};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{Event as TermEvent, EventStream as TermEventStream, KeyCode};
+use crossterm::event::{KeyEvent, KeyModifiers};
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use futures::channel::mpsc;
+use futures::{Future, StreamExt};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use tracing::warn;
+
+const TOP_ROW: &str = "qwertyui";
+const BOTTOM_ROW: &str = "asdfghjk";
+const FADER_STEP: u8 = 8;
+const FADER_WIDTH: usize = 32;
+const BLINK_INTERVAL: Duration = Duration::from_millis(250);
+const REDRAW_INTERVAL: Duration = Duration::from_millis(125);
+
+/// Sets up logging while the simulator takes over the terminal. Logs are appended to the file
+/// at `XTOUCHMINI_LOG`, or discarded if it isn't set.
+pub fn init_logging() -> Result<()> {
+    let subscriber = tracing_subscriber::fmt().with_ansi(false);
+
+    match std::env::var_os("XTOUCHMINI_LOG") {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open log file {:?}", path))?;
+            // The subscriber is global, so the file is kept open until the process exits
+            let file: &'static File = Box::leak(Box::new(file));
+            subscriber.with_writer(move || file).init();
+        }
+        None => subscriber.with_writer(io::sink).init(),
+    }
+
+    Ok(())
+}
+
+pub struct Simulator {
+    leds: ControllerState,
+    selected: Knob,
+    fader: FaderValue,
+    input: mpsc::UnboundedSender<Vec<u8>>,
+    output: mpsc::UnboundedReceiver<Vec<u8>>,
+    started: Instant,
+}
+
+impl Simulator {
+    /// Creates a simulator, along with an event stream and controller connected to it in place
+    /// of the device.
+    pub fn new() -> Result<(Self, EventStream, Controller, impl Future<Output = ()>)> {
+        let (input_tx, input_rx) = mpsc::unbounded();
+        let (output_tx, output_rx) = mpsc::unbounded();
+
+        let stream = EventStream::from_midi(input_rx);
+        let (controller, worker) =
+            Controller::with_output(move |bytes| Ok(output_tx.unbounded_send(bytes.to_vec())?))?;
+
+        let simulator = Self::with_channels(input_tx, output_rx);
+        Ok((simulator, stream, controller, worker))
+    }
+
+    /// A simulator that sends MIDI messages to `input` and receives them from `output`, e.g.
+    /// to run it over [virtual ports](crate::virtual_ports).
+    pub fn with_channels(
+        input: mpsc::UnboundedSender<Vec<u8>>,
+        output: mpsc::UnboundedReceiver<Vec<u8>>,
+    ) -> Self {
+        Self {
+            leds: ControllerState::default(),
+            selected: Knob::Knob1,
+            fader: FaderValue::MIN,
+            input,
+            output,
+            started: Instant::now(),
+        }
+    }
+
+    /// Runs the simulator until the user quits, at which point the event stream ends.
+    pub async fn run(mut self) -> Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+
+        let result = self.run_inner().await;
+
+        execute!(io::stdout(), Show, LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        result
+    }
+
+    async fn run_inner(&mut self) -> Result<()> {
+        let mut keys = TermEventStream::new();
+        let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+
+        loop {
+            tokio::select! {
+                key = keys.next() => match key {
+                    Some(Ok(TermEvent::Key(key))) => {
+                        if !self.handle_key(key)? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(error)) => return Err(error.into()),
+                    None => return Ok(()),
+                },
+                Some(bytes) = self.output.next() => {
+                    // Apps may send messages the simulator doesn't know, which shouldn't end it
+                    if let Err(error) = self.apply(&bytes) {
+                        warn!(?error, ?bytes, "Ignoring unrecognized MIDI message");
+                    }
+                }
+                _ = redraw.tick() => self.draw()?,
+            }
+        }
+    }
+
+    /// Updates LEDs from a MIDI message sent to the device.
+    pub fn apply(&mut self, bytes: &[u8]) -> Result<()> {
+        match Command::try_from(bytes)? {
+            Command::SetButtonLedState { button, state } => {
+                *self.leds.button_mut(button) = state;
+            }
+            Command::SetKnobLedState { knob, state } => {
+                *self.leds.knob_mut(knob) = state;
+            }
+            Command::SetOperationMode { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn leds(&self) -> &ControllerState {
+        &self.leds
+    }
+
+    /// Sends the MIDI message the device would send for the event.
+    pub fn send(&mut self, event: &Event) -> Result<()> {
+        if let Event::FaderMoved { value } = event {
+            self.fader = *value;
+        }
+
+        self.input
+            .unbounded_send(event.to_bytes().to_vec())
+            .context("event stream was dropped")
+    }
+
+    fn press(&mut self, event: impl Fn(bool) -> Event) -> Result<()> {
+        // Terminals don't report key releases, so releases are sent right away
+        self.send(&event(true))?;
+        self.send(&event(false))
+    }
+
+    /// Returns false if the user wants to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> Result<bool> {
+        let selected = self.selected;
+        let turn = |delta| Event::KnobTurned {
+            knob: selected,
+            delta,
+        };
+
+        match key.code {
+            KeyCode::Esc => return Ok(false),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(false)
+            }
+            KeyCode::Left => self.select(-1),
+            KeyCode::Right => self.select(1),
+            KeyCode::Up => self.send(&turn(1))?,
+            KeyCode::Down => self.send(&turn(-1))?,
+            KeyCode::PageUp => self.send(&turn(5))?,
+            KeyCode::PageDown => self.send(&turn(-5))?,
+            KeyCode::Enter => self.press(|is_down| Event::KnobPressed {
+                knob: selected,
+                is_down,
+            })?,
+            KeyCode::Char('-') => {
+                let value = FaderValue(self.fader.0.saturating_sub(FADER_STEP));
+                self.send(&Event::FaderMoved { value })?;
+            }
+            KeyCode::Char('=') => {
+                let value = FaderValue(
+                    self.fader
+                        .0
+                        .saturating_add(FADER_STEP)
+                        .min(FaderValue::MAX.0),
+                );
+                self.send(&Event::FaderMoved { value })?;
+            }
+            KeyCode::Char(c) => {
+                if let Some(button) = key_button(c) {
+                    self.press(|is_down| Event::ButtonPressed { button, is_down })?;
+                }
+            }
+            _ => {}
+        }
+
+        self.draw()?;
+        Ok(true)
+    }
+
+    fn select(&mut self, offset: isize) {
+        let count = Knob::iter().count() as isize;
+        let index = (self.selected.to_index() as isize + offset).rem_euclid(count);
+
+        if let Some(knob) = Knob::iter().nth(index as usize) {
+            self.selected = knob;
+        }
+    }
+
+    fn draw(&self) -> Result<()> {
+        let mut out = io::stdout();
+        let blink_on = (self.started.elapsed().as_millis() / BLINK_INTERVAL.as_millis()) & 1 == 0;
+
+        queue!(
+            out,
+            Clear(ClearType::All),
+            MoveTo(0, 0),
+            Print("X-Touch Mini")
+        )?;
+
+        for (row, knob) in Knob::iter().enumerate() {
+            let state = self.leds.knob(knob);
+            let marker = if knob == self.selected { '>' } else { ' ' };
+
+            queue!(
+                out,
+                MoveTo(0, row as u16 + 2),
+                Print(format!("{} {:?} ", marker, knob)),
+                SetForegroundColor(Color::Yellow),
+                Print(ring(state.style, state.led_value)),
+                ResetColor,
+                Print(format!(" {:?}", state.style)),
+            )?;
+        }
+
+        let rows = [(TOP_ROW, Button::Button1), (BOTTOM_ROW, Button::Button9)];
+        for (row, (keys, first)) in rows.iter().enumerate() {
+            queue!(out, MoveTo(0, row as u16 + 11))?;
+
+            for (i, key) in keys.chars().enumerate() {
+                let button = Button::from_index(first.to_index() + i).unwrap_or(*first);
+                self.draw_button(&mut out, key, button, blink_on)?;
+            }
+        }
+
+        queue!(out, MoveTo(0, 13))?;
+        self.draw_button(&mut out, 'z', Button::LayerA, blink_on)?;
+        self.draw_button(&mut out, 'x', Button::LayerB, blink_on)?;
+
+        let filled = self.fader.0 as usize * FADER_WIDTH / FaderValue::MAX.0 as usize;
+        queue!(
+            out,
+            MoveTo(0, 15),
+            Print(format!(
+                "Fader [-/=] [{}{}] {}",
+                "#".repeat(filled),
+                " ".repeat(FADER_WIDTH - filled),
+                self.fader.0
+            )),
+            MoveTo(0, 17),
+            Print("←/→ select knob, ↑/↓ turn, Enter press, Esc quit"),
+        )?;
+
+        out.flush()?;
+        Ok(())
+    }
+
+    fn draw_button(
+        &self,
+        out: &mut impl Write,
+        key: char,
+        button: Button,
+        blink_on: bool,
+    ) -> Result<()> {
+        let lit = match self.leds.button(button) {
+            ButtonLedState::On => true,
+            ButtonLedState::Off => false,
+            ButtonLedState::Blink => blink_on,
+        };
+
+        let color = if lit { Color::Red } else { Color::DarkGrey };
+        queue!(
+            out,
+            SetForegroundColor(color),
+            Print(format!("[{}] ", key)),
+            ResetColor,
+        )?;
+
+        Ok(())
+    }
+}
+
+fn key_button(c: char) -> Option<Button> {
+    let c = c.to_ascii_lowercase();
+
+    match c {
+        'z' => Some(Button::LayerA),
+        'x' => Some(Button::LayerB),
+        _ => TOP_ROW
+            .find(c)
+            .or_else(|| BOTTOM_ROW.find(c).map(|i| i + 8))
+            .and_then(Button::from_index),
+    }
+}
+
+/// The knob's LED ring as text, as lit in MC mode.
+fn ring(style: KnobLedStyle, value: KnobLedValue) -> String {
+    const CENTER: u8 = KnobLedValue::MAX.0 / 2;
+    let value = value.0;
+
+    (1..=KnobLedValue::MAX.0)
+        .map(|led| {
+            let lit = match style {
+                // Spread looks the same as Single in MC mode, and Pan doesn't light anything
+                KnobLedStyle::Single | KnobLedStyle::Spread => led == value,
+                KnobLedStyle::Pan => false,
+                KnobLedStyle::Fan => led <= value,
+                KnobLedStyle::Trim if value < CENTER => value <= led && led <= CENTER,
+                KnobLedStyle::Trim => CENTER <= led && led <= value,
+            };
+
+            if lit {
+                '●'
+            } else {
+                '·'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,485 @@
+//! Showing the health of connections and integrations on button LEDs or knob rings.
+
+use crate::model::{Button, ButtonLedState, Knob, KnobLedStyle, KnobLedValue};
+use crate::output::Controller;
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const FLASH_DURATION: Duration = Duration::from_millis(300);
+
+// Knob rings can't blink on their own, so they're toggled from `tick`
+const KNOB_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    MidiOut,
+    VTubeStudio,
+    Audio,
+    Mpris,
+    Mqtt,
+    Obs,
+    Osc,
+    Script,
+    Bridge,
+    Other(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MidiOut => f.write_str("MIDI output"),
+            Self::VTubeStudio => f.write_str("VTubeStudio"),
+            Self::Audio => f.write_str("audio"),
+            Self::Mpris => f.write_str("media player"),
+            Self::Mqtt => f.write_str("MQTT"),
+            Self::Obs => f.write_str("OBS"),
+            Self::Osc => f.write_str("OSC"),
+            Self::Script => f.write_str("script"),
+            Self::Bridge => f.write_str("MIDI bridge"),
+            Self::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+// The latest outcome of operations on each source, numbered so `StatusIndicators::tick` only
+// shows new ones. Global, since errors are tagged deep inside clients that don't know about
+// indicators.
+static OUTCOMES: Lazy<Mutex<HashMap<Source, (u64, bool)>>> = Lazy::new(Default::default);
+static NEXT_OUTCOME: AtomicU64 = AtomicU64::new(1);
+
+/// Attaches a [`Source`] to errors, so [`StatusIndicators::report_error`] knows which
+/// indicator to update.
+///
+/// The outcome is also recorded either way, so [`StatusIndicators::tick`] shows the source as
+/// healthy again once a later operation on it succeeds, and notices errors that are only
+/// logged (e.g. in the controller's worker).
+pub trait WithSource<T> {
+    fn with_source(self, source: Source) -> Result<T>;
+}
+
+impl<T, E> WithSource<T> for std::result::Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn with_source(self, source: Source) -> Result<T> {
+        let number = NEXT_OUTCOME.fetch_add(1, Ordering::Relaxed);
+        let outcome = (number, self.is_ok());
+        OUTCOMES.lock().unwrap().insert(source.clone(), outcome);
+        self.map_err(|error| error.into().context(source))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndicatorState {
+    On,
+    Off,
+    Blink,
+    /// Lights up briefly, then turns off
+    FlashOnce,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Button(Button),
+    Knob(Knob),
+}
+
+/// Where a source's health is shown, and how.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Indicator {
+    target: Target,
+    healthy: IndicatorState,
+    failed: IndicatorState,
+}
+
+impl Indicator {
+    /// On while healthy, off after an error.
+    pub fn button(button: Button) -> Self {
+        Self::new(Target::Button(button))
+    }
+
+    /// Fully lit while healthy, empty after an error.
+    pub fn knob(knob: Knob) -> Self {
+        Self::new(Target::Knob(knob))
+    }
+
+    pub fn healthy(mut self, state: IndicatorState) -> Self {
+        self.healthy = state;
+        self
+    }
+
+    pub fn failed(mut self, state: IndicatorState) -> Self {
+        self.failed = state;
+        self
+    }
+
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            healthy: IndicatorState::On,
+            failed: IndicatorState::Off,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    indicator: Indicator,
+    healthy: Option<bool>,
+    flash_until: Option<Instant>,
+    shown: Option<bool>,
+    outcome_seen: u64,
+}
+
+impl Entry {
+    fn state(&self) -> Option<IndicatorState> {
+        self.healthy.map(|healthy| {
+            if healthy {
+                self.indicator.healthy
+            } else {
+                self.indicator.failed
+            }
+        })
+    }
+
+    fn set_healthy(&mut self, healthy: bool, now: Instant) {
+        // Flashes repeat on every report, so repeated errors stay visible
+        if self.healthy != Some(healthy) || !healthy {
+            self.flash_until = Some(now + FLASH_DURATION);
+        }
+
+        self.healthy = Some(healthy);
+    }
+
+    /// Updates the LED, if what it should show has changed.
+    fn show(&mut self, controller: &mut Controller, now: Instant) -> Result<()> {
+        let state = match self.state() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        let lit = match state {
+            IndicatorState::On => true,
+            IndicatorState::Off => false,
+            IndicatorState::FlashOnce => self.flash_until.iter().any(|until| now < *until),
+            IndicatorState::Blink => match self.indicator.target {
+                // Buttons blink by themselves, so this is only used for knobs
+                Target::Button(_) => true,
+                Target::Knob(_) => blink_phase(now),
+            },
+        };
+
+        match self.indicator.target {
+            Target::Button(button) => {
+                let led = match (state, lit) {
+                    (IndicatorState::Blink, _) => ButtonLedState::Blink,
+                    (_, true) => ButtonLedState::On,
+                    (_, false) => ButtonLedState::Off,
+                };
+
+                if *controller.state().button(button) != led {
+                    controller.set_button(button, led)?;
+                }
+            }
+            Target::Knob(knob) => {
+                if self.shown != Some(lit) {
+                    let value = if lit {
+                        KnobLedValue::MAX
+                    } else {
+                        KnobLedValue::MIN
+                    };
+                    controller.set_knob(knob, KnobLedStyle::Fan, value)?;
+                }
+            }
+        }
+
+        self.shown = Some(lit);
+        Ok(())
+    }
+}
+
+/// Shows the health of each [`Source`] on its own indicator. LEDs go back to the healthy
+/// state as soon as a source reports success again.
+#[derive(Clone, Debug, Default)]
+pub struct StatusIndicators {
+    entries: HashMap<Source, Entry>,
+}
+
+impl StatusIndicators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn source(mut self, source: Source, indicator: Indicator) -> Self {
+        let entry = Entry {
+            indicator,
+            healthy: None,
+            flash_until: None,
+            shown: None,
+            outcome_seen: 0,
+        };
+
+        self.entries.insert(source, entry);
+        self
+    }
+
+    /// Whether the source was healthy when it last reported, if it has reported at all.
+    pub fn is_healthy(&self, source: &Source) -> Option<bool> {
+        self.entries.get(source).and_then(|entry| entry.healthy)
+    }
+
+    pub fn set(
+        &mut self,
+        controller: &mut Controller,
+        source: &Source,
+        healthy: bool,
+    ) -> Result<()> {
+        let now = Instant::now();
+
+        if let Some(entry) = self.entries.get_mut(source) {
+            entry.set_healthy(healthy, now);
+            entry.show(controller, now)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn ok(&mut self, controller: &mut Controller, source: &Source) -> Result<()> {
+        self.set(controller, source, true)
+    }
+
+    pub fn error(&mut self, controller: &mut Controller, source: &Source) -> Result<()> {
+        self.set(controller, source, false)
+    }
+
+    /// Marks the source attached with [`WithSource`] as failed. Returns the source, if the
+    /// error had one.
+    pub fn report_error(
+        &mut self,
+        controller: &mut Controller,
+        error: &anyhow::Error,
+    ) -> Result<Option<Source>> {
+        let source = error.downcast_ref::<Source>().cloned();
+
+        if let Some(source) = &source {
+            self.error(controller, source)?;
+        }
+
+        Ok(source)
+    }
+
+    /// Shows the outcomes recorded by [`WithSource`] since the last tick, ends flashes and
+    /// blinks knob rings. Should be called regularly, e.g. every 100ms.
+    pub fn tick(&mut self, controller: &mut Controller) -> Result<()> {
+        self.tick_at(controller, Instant::now())
+    }
+
+    fn tick_at(&mut self, controller: &mut Controller, now: Instant) -> Result<()> {
+        {
+            let outcomes = OUTCOMES.lock().unwrap();
+            for (source, entry) in self.entries.iter_mut() {
+                let (number, healthy) = match outcomes.get(source) {
+                    Some(&outcome) if outcome.0 > entry.outcome_seen => outcome,
+                    _ => continue,
+                };
+
+                entry.outcome_seen = number;
+                // Errors were usually reported already, and shouldn't flash twice
+                if entry.healthy != Some(healthy) {
+                    entry.set_healthy(healthy, now);
+                }
+            }
+        }
+
+        for entry in self.entries.values_mut() {
+            entry.show(controller, now)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn blink_phase(now: Instant) -> bool {
+    // Derived from a fixed point rather than per entry, so all knob rings blink together
+    static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+    let elapsed = now.saturating_duration_since(*START);
+    (elapsed.as_millis() / KNOB_BLINK_INTERVAL.as_millis()) & 1 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn controller() -> Controller {
+        let (controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+        tokio::spawn(worker);
+        controller
+    }
+
+    fn button(controller: &Controller) -> ButtonLedState {
+        *controller.state().button(Button::Button16)
+    }
+
+    fn knob(controller: &Controller) -> KnobLedValue {
+        controller.state().knob(Knob::Knob8).led_value
+    }
+
+    #[tokio::test]
+    async fn sets_button_leds() -> Result<()> {
+        let mut controller = controller();
+        let mut status = StatusIndicators::new()
+            .source(Source::VTubeStudio, Indicator::button(Button::Button16));
+
+        assert_eq!(status.is_healthy(&Source::VTubeStudio), None);
+
+        status.set(&mut controller, &Source::VTubeStudio, true)?;
+        assert_eq!(status.is_healthy(&Source::VTubeStudio), Some(true));
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        status.set(&mut controller, &Source::VTubeStudio, false)?;
+        assert_eq!(status.is_healthy(&Source::VTubeStudio), Some(false));
+        assert_eq!(button(&controller), ButtonLedState::Off);
+
+        // Sources without an indicator are ignored
+        status.set(&mut controller, &Source::Obs, false)?;
+        assert_eq!(status.is_healthy(&Source::Obs), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uses_custom_states() -> Result<()> {
+        let mut controller = controller();
+        let indicator = Indicator::button(Button::Button16)
+            .healthy(IndicatorState::Off)
+            .failed(IndicatorState::Blink);
+        let mut status = StatusIndicators::new().source(Source::Obs, indicator);
+
+        status.ok(&mut controller, &Source::Obs)?;
+        assert_eq!(button(&controller), ButtonLedState::Off);
+
+        status.error(&mut controller, &Source::Obs)?;
+        assert_eq!(button(&controller), ButtonLedState::Blink);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_tagged_errors() -> Result<()> {
+        let mut controller = controller();
+        let mut status = StatusIndicators::new()
+            .source(Source::VTubeStudio, Indicator::button(Button::Button16));
+        status.ok(&mut controller, &Source::VTubeStudio)?;
+
+        let untagged = anyhow!("something else failed");
+        assert_eq!(status.report_error(&mut controller, &untagged)?, None);
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        // The source is still found under further context
+        let tagged = Err::<(), _>(anyhow!("connection refused"))
+            .with_source(Source::VTubeStudio)
+            .context("failed to set param")
+            .unwrap_err();
+        assert_eq!(
+            status.report_error(&mut controller, &tagged)?,
+            Some(Source::VTubeStudio)
+        );
+        assert_eq!(status.is_healthy(&Source::VTubeStudio), Some(false));
+        assert_eq!(button(&controller), ButtonLedState::Off);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ends_flashes_on_tick() -> Result<()> {
+        let mut controller = controller();
+        // Sources only used here, since ticks pick up outcomes recorded by other tests
+        let source = Source::Other("flash test".to_owned());
+        let indicator = Indicator::button(Button::Button16).failed(IndicatorState::FlashOnce);
+        let mut status = StatusIndicators::new().source(source.clone(), indicator);
+
+        status.error(&mut controller, &source)?;
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        status.tick_at(&mut controller, Instant::now())?;
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        status.tick_at(&mut controller, Instant::now() + FLASH_DURATION)?;
+        assert_eq!(button(&controller), ButtonLedState::Off);
+
+        // Another error flashes again
+        status.error(&mut controller, &source)?;
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blinks_knob_rings_on_tick() -> Result<()> {
+        let mut controller = controller();
+        let source = Source::Other("blink test".to_owned());
+        let indicator = Indicator::knob(Knob::Knob8).failed(IndicatorState::Blink);
+        let mut status = StatusIndicators::new().source(source.clone(), indicator);
+
+        status.ok(&mut controller, &source)?;
+        assert_eq!(knob(&controller), KnobLedValue::MAX);
+
+        status.error(&mut controller, &source)?;
+        let now = Instant::now();
+        status.tick_at(&mut controller, now)?;
+        let first = knob(&controller);
+
+        status.tick_at(&mut controller, now + KNOB_BLINK_INTERVAL)?;
+        let second = knob(&controller);
+
+        assert_ne!(first, second);
+        assert!([KnobLedValue::MIN, KnobLedValue::MAX].contains(&first));
+        assert!([KnobLedValue::MIN, KnobLedValue::MAX].contains(&second));
+
+        // Ticks leave healthy sources alone
+        status.ok(&mut controller, &source)?;
+        status.tick_at(&mut controller, now + KNOB_BLINK_INTERVAL * 3)?;
+        assert_eq!(knob(&controller), KnobLedValue::MAX);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shows_recorded_outcomes_on_tick() -> Result<()> {
+        let mut controller = controller();
+        let source = Source::Other("outcome test".to_owned());
+        let mut status =
+            StatusIndicators::new().source(source.clone(), Indicator::button(Button::Button16));
+        status.ok(&mut controller, &source)?;
+
+        // Only logged rather than reported, like errors in the controller's worker
+        let _ = Err::<(), _>(anyhow!("failed")).with_source(source.clone());
+        status.tick(&mut controller)?;
+        assert_eq!(status.is_healthy(&source), Some(false));
+        assert_eq!(button(&controller), ButtonLedState::Off);
+
+        // Any later success clears the error
+        Ok::<_, anyhow::Error>(()).with_source(source.clone())?;
+        status.tick(&mut controller)?;
+        assert_eq!(status.is_healthy(&source), Some(true));
+        assert_eq!(button(&controller), ButtonLedState::On);
+
+        // The latest outcome wins
+        Ok::<_, anyhow::Error>(()).with_source(source.clone())?;
+        let error = Err::<(), _>(anyhow!("failed"))
+            .with_source(source.clone())
+            .unwrap_err();
+        status.report_error(&mut controller, &error)?;
+        status.tick(&mut controller)?;
+        assert_eq!(status.is_healthy(&source), Some(false));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Virtual MIDI ports (ALSA sequencer ports on Linux) that other apps see as a device.
+//!
+//! Named after the X-Touch Mini, they let the simulator or a replay stand in for the device
+//! while the app uses the usual [`EventStream`](crate::EventStream) and
+//! [`Controller`](crate::Controller). With other names, they can expose processed events to
+//! DAWs.
+
+use crate::MIDI_DEVICE_NAME;
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc;
+use midir::os::unix::{VirtualInput, VirtualOutput};
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use tracing::error;
+
+/// Names for a pair of virtual ports. Everything defaults to the same name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualPortNames {
+    pub client: String,
+    /// Port that other apps send to
+    pub input: String,
+    /// Port that other apps receive from
+    pub output: String,
+}
+
+impl VirtualPortNames {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        let name = name.into();
+
+        Self {
+            client: name.clone(),
+            input: name.clone(),
+            output: name,
+        }
+    }
+
+    pub fn input<S: Into<String>>(mut self, name: S) -> Self {
+        self.input = name.into();
+        self
+    }
+
+    pub fn output<S: Into<String>>(mut self, name: S) -> Self {
+        self.output = name.into();
+        self
+    }
+}
+
+impl Default for VirtualPortNames {
+    /// Names that the rest of the crate recognizes as an X-Touch Mini.
+    fn default() -> Self {
+        Self::new(MIDI_DEVICE_NAME)
+    }
+}
+
+/// A connected pair of virtual input and output ports. The ports are removed when this is
+/// dropped.
+pub struct VirtualPorts {
+    output: MidiOutputConnection,
+    _input: MidiInputConnection<()>,
+    received: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+impl VirtualPorts {
+    pub fn create(names: &VirtualPortNames) -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded();
+
+        let input = MidiInput::new(&names.client)?
+            .create_virtual(
+                &names.input,
+                move |_timestamp, bytes, ()| {
+                    if let Err(error) = tx.unbounded_send(bytes.to_vec()) {
+                        error!(?error, "Failed to forward message from virtual port");
+                    }
+                },
+                (),
+            )
+            .map_err(|e| anyhow!("failed to create virtual input port: {}", e))?;
+
+        let output = MidiOutput::new(&names.client)?
+            .create_virtual(&names.output)
+            .map_err(|e| anyhow!("failed to create virtual output port: {}", e))?;
+
+        Ok(Self {
+            output,
+            _input: input,
+            received: Some(rx),
+        })
+    }
+
+    /// Sends a message to apps connected to the output port.
+    pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(self.output.send(bytes)?)
+    }
+
+    /// Messages that apps send to the input port. Can only be taken once.
+    pub fn take_received(&mut self) -> Option<mpsc::UnboundedReceiver<Vec<u8>>> {
+        self.received.take()
+    }
+}
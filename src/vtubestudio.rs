@@ -1,13 +1,24 @@
+use crate::metrics;
+use crate::status::{Source, WithSource};
 use anyhow::{Context, Result};
 use futures::SinkExt;
-use serde::Serialize;
-use serde_repr::Serialize_repr;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 use smallvec::SmallVec;
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use strum::{AsRefStr, EnumIter, EnumString};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{debug_span, Instrument};
+
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "plugin")]
+pub mod plugin;
 
 type DataVec<T> = SmallVec<[T; 16]>;
 
@@ -32,7 +43,11 @@ impl Client {
     }
 
     pub async fn connect(&mut self) -> Result<()> {
-        self.tcp = Some(self.connect_inner().await?);
+        self.tcp = Some(
+            self.connect_inner()
+                .await
+                .with_source(Source::VTubeStudio)?,
+        );
         Ok(())
     }
 
@@ -40,9 +55,11 @@ impl Client {
         self.send_message(&Message::new_with_hotkey(hotkey)).await
     }
 
+    /// Sets a parameter through the tracking connection, which only knows the built-in
+    /// [`Param`]s. Custom parameters are set with `plugin::Client::inject_params` instead.
     pub async fn set_param(&mut self, param: Param, value: f64) -> Result<()> {
         let result = self
-            .send_message(&Message::new_with_param(param, value))
+            .send_message(&Message::new_with_param(param.into(), value))
             .await;
 
         if result.is_ok() {
@@ -59,7 +76,7 @@ impl Client {
             .params
             .iter()
             .map(|(param, value)| MessageData {
-                param: *param,
+                param: (*param).into(),
                 value: *value,
             })
             .collect::<DataVec<_>>();
@@ -67,6 +84,11 @@ impl Client {
         self.send_message(&msg).await
     }
 
+    /// Last known values of all params that have been set.
+    pub fn params(&self) -> &HashMap<Param, f64> {
+        &self.params
+    }
+
     pub fn param(&self, param: Param) -> f64 {
         if let Some(value) = self.params.get(&param) {
             *value
@@ -85,6 +107,16 @@ impl Client {
     }
 
     async fn send_message(&mut self, msg: &Message) -> Result<()> {
+        let start = Instant::now();
+        let span = debug_span!("vtubestudio_send", command = msg.command);
+        let result = self.send_message_inner(msg).instrument(span).await;
+        metrics::global()
+            .vtubestudio_send_duration
+            .record_since(start);
+        result.with_source(Source::VTubeStudio)
+    }
+
+    async fn send_message_inner(&mut self, msg: &Message) -> Result<()> {
         let mut tcp = if let Some(tcp) = self.tcp.take() {
             tcp
         } else {
@@ -100,7 +132,7 @@ impl Client {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Message {
     pub ver: i32,
@@ -131,7 +163,7 @@ impl Message {
         }
     }
 
-    pub fn new_with_param(param: Param, value: f64) -> Self {
+    pub fn new_with_param(param: ParamId, value: f64) -> Self {
         let mut msg = Self::new();
         msg.data.push(MessageData { param, value });
         msg
@@ -144,16 +176,84 @@ impl Message {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MessageData {
     #[serde(rename = "p")]
-    pub param: Param,
+    pub param: ParamId,
     #[serde(rename = "v")]
     pub value: f64,
 }
 
+/// Identifies a parameter, either one of VTubeStudio's built-in tracking inputs (sent as its
+/// numeric ID) or any other parameter by name, such as ones created by plugins.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParamId {
+    Builtin(Param),
+    Custom(String),
+}
+
+impl ParamId {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Builtin(param) => param.as_ref(),
+            Self::Custom(name) => name,
+        }
+    }
+}
+
+impl From<Param> for ParamId {
+    fn from(param: Param) -> Self {
+        Self::Builtin(param)
+    }
+}
+
+impl From<&str> for ParamId {
+    fn from(name: &str) -> Self {
+        match Param::from_str(name) {
+            Ok(param) => Self::Builtin(param),
+            Err(_) => Self::Custom(name.to_owned()),
+        }
+    }
+}
+
+impl From<String> for ParamId {
+    fn from(name: String) -> Self {
+        match Param::from_str(&name) {
+            Ok(param) => Self::Builtin(param),
+            Err(_) => Self::Custom(name),
+        }
+    }
+}
+
+impl FromStr for ParamId {
+    type Err = std::convert::Infallible;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(name))
+    }
+}
+
+impl fmt::Display for ParamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[repr(usize)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize_repr)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize_repr,
+    Deserialize_repr,
+    AsRefStr,
+    EnumIter,
+    EnumString,
+)]
 pub enum Param {
     FacePositionX = 1,
     FacePositionY,
@@ -183,3 +283,28 @@ pub enum Param {
     VoiceFrequencyPlusMouthSmile, // Desktop
     MouthX,                       // iOS
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn param_id_round_trips() {
+        for param in [
+            ParamId::Builtin(Param::MouthX),
+            ParamId::Custom("XTouchCheek".to_owned()),
+        ] {
+            let json = serde_json::to_string(&param).unwrap();
+            assert_eq!(serde_json::from_str::<ParamId>(&json).unwrap(), param);
+        }
+
+        assert_eq!(
+            serde_json::to_value(ParamId::Custom("XTouchCheek".to_owned())).unwrap(),
+            serde_json::json!("XTouchCheek")
+        );
+        assert_eq!(
+            serde_json::to_value(ParamId::Builtin(Param::FacePositionX)).unwrap(),
+            serde_json::json!(1)
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! A local stand-in for VTubeStudio, for tests and offline development.
+//!
+//! The server speaks the same length-delimited JSON protocol as [`Client`] and records every
+//! message it receives, though like VTubeStudio it only takes built-in params there. It also serves the public plugin API on a separate port, approving
+//! any plugin, and answers hotkey and parameter requests for a model set with
+//! [`Server::set_model`]. Plugins subscribed to `ModelLoadedEvent` are told when it changes.
+//! Other answers, such as errors, can be scripted with [`Server::respond_with`].
+
+use super::plugin::{self, Envelope, Hotkey, ParamDefinition};
+use super::{Client, Message, ParamId};
+use anyhow::{bail, Result};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{debug, error, warn};
+
+const TOKEN: &str = "mock-token";
+
+/// Answers a plugin API request given its message type and data, with `Err` being sent as an
+/// `APIError` with that message.
+type Responder = dyn Fn(&str, &Value) -> Option<Result<Value, String>> + Send + Sync;
+
+/// Everything the mock server has received so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recorded {
+    pub messages: Vec<Message>,
+    pub params: HashMap<ParamId, f64>,
+    pub hotkeys: Vec<i32>,
+    /// Message types of the plugin API requests, in order
+    pub requests: Vec<String>,
+    /// IDs of the hotkeys triggered through the plugin API, in order
+    pub triggered_hotkeys: Vec<String>,
+    pub custom_params: HashMap<String, ParamDefinition>,
+}
+
+struct Model {
+    id: String,
+    hotkeys: Vec<Hotkey>,
+}
+
+#[derive(Default)]
+struct Shared {
+    recorded: Mutex<Recorded>,
+    model: Mutex<Option<Model>>,
+    responder: Mutex<Option<Box<Responder>>>,
+    received: Notify,
+    model_changed: Notify,
+}
+
+/// State of a plugin API connection.
+#[derive(Default)]
+struct Session {
+    authenticated: bool,
+    subscribed: bool,
+}
+
+pub struct Server {
+    addr: SocketAddr,
+    plugin_addr: SocketAddr,
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Starts a server on an unused local port.
+    pub async fn start() -> Result<Self> {
+        Self::bind("127.0.0.1:0".parse()?).await
+    }
+
+    /// Starts a server on `addr`, with the plugin API on an unused port of the same host.
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let plugin_listener = TcpListener::bind((addr.ip(), 0)).await?;
+        let plugin_addr = plugin_listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+
+        let tasks = vec![
+            tokio::spawn(accept(listener, shared.clone(), handle_connection)),
+            tokio::spawn(accept(plugin_listener, shared.clone(), handle_plugin)),
+        ];
+
+        Ok(Self {
+            addr,
+            plugin_addr,
+            shared,
+            tasks,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL of the plugin API.
+    pub fn plugin_url(&self) -> String {
+        format!("ws://{}", self.plugin_addr)
+    }
+
+    /// Creates a client pointed at this server.
+    pub fn client(&self) -> Client {
+        Client::new(self.addr)
+    }
+
+    /// Creates a plugin API client pointed at this server.
+    pub fn plugin_client(&self) -> plugin::Client {
+        plugin::Client::new(self.plugin_url(), "Mock", "xtouchmini")
+    }
+
+    /// Loads a model with the given hotkeys, as reported to and triggered by plugins.
+    pub fn set_model<S: Into<String>>(&self, model_id: S, hotkeys: Vec<Hotkey>) {
+        *self.shared.model.lock().unwrap() = Some(Model {
+            id: model_id.into(),
+            hotkeys,
+        });
+        self.shared.model_changed.notify_waiters();
+    }
+
+    /// Scripts answers to plugin API requests. If the function returns `Some`, that's sent
+    /// back instead of the usual answer, e.g. `Some(Err(..))` to make a request fail.
+    pub fn respond_with<F>(&self, responder: F)
+    where
+        F: Fn(&str, &Value) -> Option<Result<Value, String>> + Send + Sync + 'static,
+    {
+        *self.shared.responder.lock().unwrap() = Some(Box::new(responder));
+    }
+
+    pub fn recorded(&self) -> Recorded {
+        self.shared.recorded.lock().unwrap().clone()
+    }
+
+    /// Latest value received for a parameter.
+    pub fn param<P: Into<ParamId>>(&self, param: P) -> Option<f64> {
+        self.shared
+            .recorded
+            .lock()
+            .unwrap()
+            .params
+            .get(&param.into())
+            .copied()
+    }
+
+    /// IDs of the hotkeys triggered so far, in order.
+    pub fn hotkeys(&self) -> Vec<i32> {
+        self.shared.recorded.lock().unwrap().hotkeys.clone()
+    }
+
+    pub fn clear(&self) {
+        *self.shared.recorded.lock().unwrap() = Recorded::default();
+    }
+
+    /// Waits until at least `count` messages have been received in total.
+    pub async fn wait_for_messages(&self, count: usize, timeout: Duration) -> Result<()> {
+        let wait = async {
+            loop {
+                let notified = self.shared.received.notified();
+                if self.shared.recorded.lock().unwrap().messages.len() >= count {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        if tokio::time::timeout(timeout, wait).await.is_err() {
+            bail!("timed out waiting for {} messages", count);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn accept<F, Fut>(listener: TcpListener, shared: Arc<Shared>, handle: F)
+where
+    F: Fn(TcpStream, Arc<Shared>) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!(%peer, "Mock VTubeStudio accepted connection");
+                tokio::spawn(handle(stream, shared.clone()));
+            }
+            Err(error) => {
+                error!(?error, "Mock VTubeStudio failed to accept connection");
+                break;
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, shared: Arc<Shared>) {
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+
+    while let Some(frame) = framed.next().await {
+        let msg = match frame
+            .map_err(anyhow::Error::from)
+            .and_then(|frame| Ok(serde_json::from_slice::<Message>(&frame)?))
+        {
+            Ok(msg) => msg,
+            Err(error) => {
+                error!(?error, "Mock VTubeStudio received invalid message");
+                break;
+            }
+        };
+
+        record(&shared, msg);
+        shared.received.notify_waiters();
+    }
+}
+
+fn record(shared: &Shared, msg: Message) {
+    let mut recorded = shared.recorded.lock().unwrap();
+
+    for data in &msg.data {
+        match &data.param {
+            ParamId::Builtin(_) => {
+                recorded.params.insert(data.param.clone(), data.value);
+            }
+            // Those have to be injected through the plugin API
+            ParamId::Custom(name) => {
+                warn!(name = %name, "Mock VTubeStudio ignored a custom param sent for tracking")
+            }
+        }
+    }
+
+    if msg.hotkey >= 0 {
+        recorded.hotkeys.push(msg.hotkey);
+    }
+
+    recorded.messages.push(msg);
+}
+
+async fn handle_plugin(stream: TcpStream, shared: Arc<Shared>) {
+    let mut ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(error) => {
+            error!(
+                ?error,
+                "Mock VTubeStudio failed to accept WebSocket connection"
+            );
+            return;
+        }
+    };
+
+    let mut session = Session::default();
+
+    loop {
+        let model_changed = shared.model_changed.notified();
+        let msg = tokio::select! {
+            msg = ws.next() => msg,
+            _ = model_changed => {
+                if session.subscribed {
+                    if let Err(error) = send(&mut ws, model_loaded_event(&shared)).await {
+                        error!(?error, "Mock VTubeStudio failed to send event");
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+
+        let text = match msg {
+            Some(Ok(WsMessage::Text(text))) => text,
+            Some(Ok(WsMessage::Close(_))) | None => break,
+            Some(Ok(_)) => continue,
+            Some(Err(error)) => {
+                error!(?error, "Mock VTubeStudio failed to read plugin request");
+                break;
+            }
+        };
+
+        let request: Envelope = match serde_json::from_str(&text) {
+            Ok(request) => request,
+            Err(error) => {
+                error!(?error, "Mock VTubeStudio received invalid plugin request");
+                break;
+            }
+        };
+
+        let result = answer(&shared, &mut session, &request);
+        let (message_type, data) = match result {
+            Ok(data) => (request.message_type.replace("Request", "Response"), data),
+            Err(message) => (
+                "APIError".to_owned(),
+                json!({ "errorID": 0, "message": message }),
+            ),
+        };
+
+        let response = Envelope {
+            message_type,
+            data,
+            ..request
+        };
+
+        if let Err(error) = send(&mut ws, response).await {
+            error!(?error, "Mock VTubeStudio failed to send plugin response");
+            break;
+        }
+    }
+}
+
+async fn send(ws: &mut WebSocketStream<TcpStream>, message: Envelope) -> Result<()> {
+    ws.send(WsMessage::Text(serde_json::to_string(&message)?))
+        .await?;
+    Ok(())
+}
+
+fn model_loaded_event(shared: &Shared) -> Envelope {
+    let model = shared.model.lock().unwrap();
+
+    Envelope {
+        api_name: "VTubeStudioPublicAPI".to_owned(),
+        api_version: "1.0".to_owned(),
+        request_id: "event".to_owned(),
+        message_type: "ModelLoadedEvent".to_owned(),
+        data: json!({
+            "modelLoaded": model.is_some(),
+            "modelID": model.as_ref().map(|model| model.id.as_str()).unwrap_or_default(),
+        }),
+    }
+}
+
+fn answer(shared: &Shared, session: &mut Session, request: &Envelope) -> Result<Value, String> {
+    shared
+        .recorded
+        .lock()
+        .unwrap()
+        .requests
+        .push(request.message_type.clone());
+
+    let data = &request.data;
+
+    if let Some(responder) = shared.responder.lock().unwrap().as_ref() {
+        if let Some(response) = responder(&request.message_type, data) {
+            return response;
+        }
+    }
+
+    match request.message_type.as_str() {
+        "AuthenticationTokenRequest" => return Ok(json!({ "authenticationToken": TOKEN })),
+        "AuthenticationRequest" => {
+            session.authenticated = data["authenticationToken"] == TOKEN;
+            return Ok(json!({ "authenticated": session.authenticated, "reason": "" }));
+        }
+        _ if !session.authenticated => return Err("plugin not authenticated".to_owned()),
+        "EventSubscriptionRequest" => {
+            if data["eventName"] != "ModelLoadedEvent" {
+                return Err(format!("unsupported event {}", data["eventName"]));
+            }
+
+            session.subscribed = data["subscribe"].as_bool().unwrap_or_default();
+            let events = if session.subscribed {
+                vec!["ModelLoadedEvent"]
+            } else {
+                Vec::new()
+            };
+            return Ok(json!({ "subscribedEvents": events }));
+        }
+        _ => {}
+    }
+
+    let model = shared.model.lock().unwrap();
+    let mut recorded = shared.recorded.lock().unwrap();
+
+    match request.message_type.as_str() {
+        "HotkeysInCurrentModelRequest" => Ok(json!({
+            "modelLoaded": model.is_some(),
+            "modelID": model.as_ref().map(|model| model.id.as_str()).unwrap_or_default(),
+            "availableHotkeys": model.as_ref().map(|model| &model.hotkeys[..]).unwrap_or_default(),
+        })),
+        "HotkeyTriggerRequest" => {
+            let hotkey = data["hotkeyID"].as_str().unwrap_or_default();
+            let id = model
+                .iter()
+                .flat_map(|model| &model.hotkeys)
+                .find(|candidate| candidate.id == hotkey || candidate.name == hotkey)
+                .map(|hotkey| hotkey.id.clone())
+                .ok_or_else(|| format!("hotkey {:?} not found", hotkey))?;
+
+            recorded.triggered_hotkeys.push(id.clone());
+            Ok(json!({ "hotkeyID": id }))
+        }
+        "ParameterCreationRequest" => {
+            let definition: ParamDefinition =
+                serde_json::from_value(data.clone()).map_err(|error| error.to_string())?;
+            let name = definition.name.clone();
+            recorded.custom_params.insert(name.clone(), definition);
+            Ok(json!({ "parameterName": name }))
+        }
+        "InjectParameterDataRequest" => {
+            let values = data["parameterValues"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            let mut updates = Vec::new();
+
+            for value in &values {
+                let name = value["id"].as_str().unwrap_or_default();
+                let param = ParamId::from(name);
+                if let ParamId::Custom(name) = &param {
+                    if !recorded.custom_params.contains_key(name) {
+                        return Err(format!("parameter {:?} not found", name));
+                    }
+                }
+
+                let value = value["value"]
+                    .as_f64()
+                    .ok_or_else(|| format!("missing value for parameter {:?}", name))?;
+                updates.push((param, value));
+            }
+
+            recorded.params.extend(updates);
+            Ok(json!({}))
+        }
+        "ParameterDeletionRequest" => {
+            let name = data["parameterName"].as_str().unwrap_or_default();
+            recorded
+                .custom_params
+                .remove(name)
+                .ok_or_else(|| format!("parameter {:?} not found", name))?;
+            recorded.params.remove(&ParamId::Custom(name.to_owned()));
+            Ok(json!({ "parameterName": name }))
+        }
+        other => Err(format!("unsupported request {}", other)),
+    }
+}
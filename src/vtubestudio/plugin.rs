@@ -0,0 +1,512 @@
+//! Client for VTubeStudio's public plugin API, which runs over WebSocket (by default on
+//! `ws://localhost:8001`) and has to be enabled in VTubeStudio's settings. It can list and
+//! trigger hotkeys by name, and create and set custom parameters.
+//!
+//! The hotkey list is cached, and fetched again when VTubeStudio reports that another model
+//! was loaded, or when triggering a cached hotkey fails.
+//!
+//! Unlike the tracking connection used by [`super::Client`], plugins have to be approved by
+//! the user once. The token from that is kept for reconnecting, and can be saved with
+//! [`Client::auth_token`] and passed back in with [`Client::token`] to skip the prompt.
+
+use super::ParamId;
+use crate::status::{Source, WithSource};
+use anyhow::{bail, Context as _, Result};
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::debug;
+
+const API_NAME: &str = "VTubeStudioPublicAPI";
+const API_VERSION: &str = "1.0";
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Waits for the user to click through VTubeStudio's approval prompt
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct Client {
+    url: String,
+    plugin_name: String,
+    plugin_developer: String,
+    token: Option<String>,
+    ws: Option<WebSocket>,
+    next_id: u64,
+    hotkeys: Vec<Hotkey>,
+    model_id: Option<String>,
+    // Set when the model may have changed since the hotkeys were fetched
+    hotkeys_stale: bool,
+}
+
+impl Client {
+    pub fn new<U, N, D>(url: U, plugin_name: N, plugin_developer: D) -> Self
+    where
+        U: Into<String>,
+        N: Into<String>,
+        D: Into<String>,
+    {
+        Self {
+            url: url.into(),
+            plugin_name: plugin_name.into(),
+            plugin_developer: plugin_developer.into(),
+            token: None,
+            ws: None,
+            next_id: 0,
+            hotkeys: Vec::new(),
+            model_id: None,
+            hotkeys_stale: true,
+        }
+    }
+
+    /// Authentication token from a previous session, so the user isn't asked again.
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The token the client is authenticated with, once it's been approved.
+    pub fn auth_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.ws.is_some()
+    }
+
+    /// Connects and authenticates, asking the user for approval if there's no token yet.
+    /// Requests connect on demand, so this is only needed to get the prompt out of the way.
+    pub async fn connect(&mut self) -> Result<()> {
+        self.ws = None;
+        self.ws = Some(
+            self.connect_inner()
+                .await
+                .with_source(Source::VTubeStudio)?,
+        );
+        Ok(())
+    }
+
+    /// Creates a custom parameter, owned by this plugin.
+    pub async fn create_param(&mut self, definition: &ParamDefinition) -> Result<()> {
+        self.request::<Value>(
+            "ParameterCreationRequest",
+            serde_json::to_value(definition)?,
+        )
+        .await
+        .with_context(|| format!("failed to create parameter {:?}", definition.name))?;
+        Ok(())
+    }
+
+    /// Deletes a custom parameter created by this plugin.
+    pub async fn delete_param(&mut self, name: &str) -> Result<()> {
+        self.request::<Value>("ParameterDeletionRequest", json!({ "parameterName": name }))
+            .await
+            .with_context(|| format!("failed to delete parameter {:?}", name))?;
+        Ok(())
+    }
+
+    /// Sets parameter values, by name. Custom parameters need to be created first, and reset
+    /// to their default unless they're set again within a second.
+    pub async fn inject_params<I, P>(&mut self, values: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (P, f64)>,
+        P: Into<ParamId>,
+    {
+        let values = values
+            .into_iter()
+            .map(|(param, value)| json!({ "id": param.into().name(), "value": value }))
+            .collect::<Vec<_>>();
+
+        self.request::<Value>(
+            "InjectParameterDataRequest",
+            json!({ "faceFound": false, "mode": "set", "parameterValues": values }),
+        )
+        .await
+        .context("failed to set parameters")?;
+        Ok(())
+    }
+
+    /// Hotkeys of the current model, as of the last refresh.
+    pub fn hotkeys(&self) -> &[Hotkey] {
+        &self.hotkeys
+    }
+
+    /// ID of the current model, as of the last refresh. `None` if no model is loaded.
+    pub fn model_id(&self) -> Option<&str> {
+        self.model_id.as_deref()
+    }
+
+    pub async fn refresh_hotkeys(&mut self) -> Result<&[Hotkey]> {
+        let response: HotkeysResponse = self
+            .request("HotkeysInCurrentModelRequest", json!({}))
+            .await
+            .context("failed to fetch hotkeys from VTubeStudio")?;
+
+        let model_id = if response.model_loaded {
+            Some(response.model_id)
+        } else {
+            None
+        };
+        if self.model_id != model_id {
+            debug!(?model_id, "VTubeStudio model changed");
+        }
+
+        self.model_id = model_id;
+        self.hotkeys = response.available_hotkeys;
+        self.hotkeys_stale = false;
+
+        Ok(&self.hotkeys)
+    }
+
+    /// Triggers a hotkey of the current model, by its name or its ID.
+    pub async fn trigger_hotkey(&mut self, hotkey: &str) -> Result<()> {
+        self.poll_events();
+
+        let (id, refreshed) = self.hotkey_id(hotkey).await?;
+        let result = match self.send_trigger(&id).await {
+            // The cached ID may belong to a model that's no longer loaded
+            Err(error) if !refreshed => {
+                debug!(?error, hotkey, "Retrying hotkey with a fresh hotkey list");
+                self.hotkeys_stale = true;
+                let (id, _) = self.hotkey_id(hotkey).await?;
+                self.send_trigger(&id).await
+            }
+            result => result,
+        };
+
+        result
+            .with_context(|| format!("failed to trigger hotkey {:?}", hotkey))
+            .with_source(Source::VTubeStudio)
+    }
+
+    /// Looks up a hotkey's ID by its name or ID, and whether the list had to be fetched for it.
+    async fn hotkey_id(&mut self, hotkey: &str) -> Result<(String, bool)> {
+        let find = |hotkeys: &[Hotkey]| {
+            hotkeys
+                .iter()
+                .find(|candidate| candidate.name == hotkey || candidate.id == hotkey)
+                .map(|candidate| candidate.id.clone())
+        };
+
+        if !self.hotkeys_stale {
+            if let Some(id) = find(&self.hotkeys) {
+                return Ok((id, false));
+            }
+        }
+
+        let id = find(self.refresh_hotkeys().await?)
+            .with_context(|| format!("hotkey {:?} not found in the current model", hotkey))?;
+        Ok((id, true))
+    }
+
+    async fn send_trigger(&mut self, id: &str) -> Result<()> {
+        self.request::<Value>("HotkeyTriggerRequest", json!({ "hotkeyID": id }))
+            .await?;
+        Ok(())
+    }
+
+    /// Handles events that arrived since the last request, without waiting for more.
+    fn poll_events(&mut self) {
+        let mut ws = match self.ws.take() {
+            Some(ws) => ws,
+            None => return,
+        };
+
+        loop {
+            match ws.next().now_or_never() {
+                Some(Some(Ok(WsMessage::Text(text)))) => {
+                    match serde_json::from_str::<Envelope>(&text) {
+                        Ok(message) => self.handle_event(&message),
+                        Err(error) => debug!(?error, "Ignoring invalid VTubeStudio message"),
+                    }
+                }
+                Some(Some(Ok(_))) => {}
+                // Closed, so the next request reconnects
+                Some(None) | Some(Some(Err(_))) => return,
+                None => break,
+            }
+        }
+
+        self.ws = Some(ws);
+    }
+
+    fn handle_event(&mut self, message: &Envelope) {
+        if message.message_type == "ModelLoadedEvent" {
+            debug!("VTubeStudio loaded another model");
+            self.hotkeys_stale = true;
+        } else {
+            debug!(request_id = %message.request_id, "Ignoring unrelated VTubeStudio message");
+        }
+    }
+
+    /// Sends a request, returning its `data`.
+    pub async fn request<T: DeserializeOwned>(
+        &mut self,
+        message_type: &str,
+        data: Value,
+    ) -> Result<T> {
+        self.send_request(message_type, data)
+            .await
+            .with_source(Source::VTubeStudio)
+    }
+
+    async fn send_request<T: DeserializeOwned>(
+        &mut self,
+        message_type: &str,
+        data: Value,
+    ) -> Result<T> {
+        let mut ws = match self.ws.take() {
+            Some(ws) => ws,
+            None => self.connect_inner().await?,
+        };
+
+        // Only kept if the exchange went through, so a late or garbled response can't be
+        // mistaken for the answer to the next request
+        let response = self
+            .exchange(&mut ws, message_type, data, RESPONSE_TIMEOUT)
+            .await?;
+        self.ws = Some(ws);
+
+        Ok(serde_json::from_value(response.into_result()?)?)
+    }
+
+    async fn connect_inner(&mut self) -> Result<WebSocket> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(self.url.as_str())
+            .await
+            .context("failed to connect to the VTubeStudio plugin API")?;
+
+        let token = match self.token.clone() {
+            Some(token) => token,
+            None => {
+                let response: TokenResponse = self
+                    .exchange(
+                        &mut ws,
+                        "AuthenticationTokenRequest",
+                        self.plugin_info(),
+                        TOKEN_TIMEOUT,
+                    )
+                    .await?
+                    .into_data()
+                    .context("VTubeStudio didn't grant an authentication token")?;
+                self.token = Some(response.authentication_token.clone());
+                response.authentication_token
+            }
+        };
+
+        let mut data = self.plugin_info();
+        data["authenticationToken"] = json!(token);
+
+        let response: AuthenticationResponse = self
+            .exchange(&mut ws, "AuthenticationRequest", data, RESPONSE_TIMEOUT)
+            .await?
+            .into_data()?;
+
+        if !response.authenticated {
+            // Most likely revoked in VTubeStudio, so ask again next time
+            self.token = None;
+            bail!("VTubeStudio rejected the plugin: {}", response.reason);
+        }
+
+        // Older versions of VTubeStudio don't have events, in which case a stale hotkey list is
+        // only noticed when triggering fails
+        let subscription = self
+            .exchange(
+                &mut ws,
+                "EventSubscriptionRequest",
+                json!({ "eventName": "ModelLoadedEvent", "subscribe": true }),
+                RESPONSE_TIMEOUT,
+            )
+            .await?
+            .into_result();
+        if let Err(error) = subscription {
+            debug!(?error, "Failed to subscribe to VTubeStudio model changes");
+        }
+
+        // The model may have changed while disconnected
+        self.hotkeys_stale = true;
+
+        debug!(url = %self.url, "Authenticated with the VTubeStudio plugin API");
+        Ok(ws)
+    }
+
+    fn plugin_info(&self) -> Value {
+        json!({
+            "pluginName": self.plugin_name,
+            "pluginDeveloper": self.plugin_developer,
+        })
+    }
+
+    async fn exchange(
+        &mut self,
+        ws: &mut WebSocket,
+        message_type: &str,
+        data: Value,
+        timeout: Duration,
+    ) -> Result<Envelope> {
+        self.next_id += 1;
+        let request_id = self.next_id.to_string();
+
+        let request = Envelope {
+            api_name: API_NAME.to_owned(),
+            api_version: API_VERSION.to_owned(),
+            request_id: request_id.clone(),
+            message_type: message_type.to_owned(),
+            data,
+        };
+
+        ws.send(WsMessage::Text(serde_json::to_string(&request)?))
+            .await
+            .context("failed to send request to VTubeStudio")?;
+
+        let response = async {
+            loop {
+                let text = match ws.next().await {
+                    Some(msg) => match msg.context("failed to read response from VTubeStudio")? {
+                        WsMessage::Text(text) => text,
+                        WsMessage::Close(_) => bail!("VTubeStudio closed the connection"),
+                        _ => continue,
+                    },
+                    None => bail!("VTubeStudio closed the connection"),
+                };
+
+                let response: Envelope = serde_json::from_str(&text)
+                    .context("failed to decode response from VTubeStudio")?;
+
+                if response.request_id == request_id {
+                    return Ok(response);
+                }
+
+                self.handle_event(&response);
+            }
+        };
+
+        match tokio::time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => bail!(
+                "timed out waiting for VTubeStudio to answer {}",
+                message_type
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Envelope {
+    pub api_name: String,
+    pub api_version: String,
+    #[serde(rename = "requestID")]
+    pub request_id: String,
+    pub message_type: String,
+    #[serde(default)]
+    pub data: Value,
+}
+
+impl Envelope {
+    fn into_result(self) -> Result<Value> {
+        if self.message_type == "APIError" {
+            let error: ApiError = serde_json::from_value(self.data)?;
+            bail!(
+                "VTubeStudio returned error {}: {}",
+                error.error_id,
+                error.message
+            );
+        }
+
+        Ok(self.data)
+    }
+
+    fn into_data<T: DeserializeOwned>(self) -> Result<T> {
+        Ok(serde_json::from_value(self.into_result()?)?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiError {
+    #[serde(rename = "errorID")]
+    error_id: i32,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    authentication_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthenticationResponse {
+    authenticated: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HotkeysResponse {
+    model_loaded: bool,
+    #[serde(rename = "modelID", default)]
+    model_id: String,
+    #[serde(default)]
+    available_hotkeys: Vec<Hotkey>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hotkey {
+    pub name: String,
+    /// What the hotkey does, e.g. `ToggleExpression` or `TriggerAnimation`
+    pub r#type: String,
+    /// The expression or animation file, if any
+    #[serde(default)]
+    pub file: String,
+    #[serde(rename = "hotkeyID")]
+    pub id: String,
+}
+
+/// Definition of a custom parameter, as sent in a `ParameterCreationRequest`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParamDefinition {
+    #[serde(rename = "parameterName")]
+    pub name: String,
+    pub explanation: String,
+    pub min: f64,
+    pub max: f64,
+    pub default_value: f64,
+}
+
+impl ParamDefinition {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            explanation: String::new(),
+            min: 0.0,
+            max: 1.0,
+            default_value: 0.0,
+        }
+    }
+
+    pub fn explanation<S: Into<String>>(mut self, explanation: S) -> Self {
+        self.explanation = explanation.into();
+        self
+    }
+
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub fn default_value(mut self, value: f64) -> Self {
+        self.default_value = value;
+        self
+    }
+}
@@ -0,0 +1,110 @@
+use std::process::Command;
+use xtouchmini::audio::{Channel, Mixer, Target};
+use xtouchmini::{Button, ButtonLedState, Controller, Event, FaderValue, Knob, KnobLedValue};
+
+const SINK: &str = "xtouchmini_test";
+
+/// A `module-null-sink` sink, unloaded on drop.
+struct NullSink {
+    module: String,
+}
+
+impl NullSink {
+    fn load() -> Option<Self> {
+        let sink_name = format!("sink_name={}", SINK);
+        let module = pactl(&["load-module", "module-null-sink", &sink_name])?;
+        Some(Self { module })
+    }
+}
+
+impl Drop for NullSink {
+    fn drop(&mut self) {
+        pactl(&["unload-module", &self.module]);
+    }
+}
+
+/// Runs `pactl`, returning its output if it succeeded.
+fn pactl(args: &[&str]) -> Option<String> {
+    let output = Command::new("pactl").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+#[test]
+fn only_handles_bound_controls() {
+    let mixer = Mixer::new()
+        .channel(Channel::new(Target::DefaultSink).knob(Knob::Knob1))
+        .channel(Channel::new(Target::application("firefox")).button(Button::Button9));
+
+    let turn = |knob| Event::KnobTurned { knob, delta: 1 };
+    let press = |button| Event::ButtonPressed {
+        button,
+        is_down: true,
+    };
+
+    assert!(mixer.handles(&turn(Knob::Knob1)));
+    assert!(mixer.handles(&Event::KnobPressed {
+        knob: Knob::Knob1,
+        is_down: false,
+    }));
+    assert!(mixer.handles(&press(Button::Button9)));
+    assert!(!mixer.handles(&turn(Knob::Knob2)));
+    assert!(!mixer.handles(&press(Button::Button1)));
+    assert!(!mixer.handles(&Event::FaderMoved {
+        value: FaderValue(10),
+    }));
+}
+
+/// Runs against the local PulseAudio or PipeWire server, and is skipped without one.
+#[tokio::test]
+async fn knob_controls_sink_volume_and_mute() {
+    let _sink = match NullSink::load() {
+        Some(sink) => sink,
+        None => {
+            eprintln!("Skipping, as there's no PulseAudio or PipeWire server to test against");
+            return;
+        }
+    };
+    pactl(&["set-sink-volume", SINK, "50%"]).unwrap();
+    pactl(&["set-sink-mute", SINK, "0"]).unwrap();
+
+    let (mut controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+    tokio::spawn(worker);
+
+    let mixer = Mixer::new().step(5.0).channel(
+        Channel::new(Target::sink(SINK))
+            .knob(Knob::Knob1)
+            .button(Button::Button9),
+    );
+
+    mixer.sync(&mut controller).await.unwrap();
+    let knob = controller.state().knob(Knob::Knob1).clone();
+    assert_eq!(knob.led_value, KnobLedValue::from_percent(0.5));
+
+    let turn = Event::KnobTurned {
+        knob: Knob::Knob1,
+        delta: 2,
+    };
+    mixer.handle(&mut controller, &turn).await.unwrap();
+
+    let volume = pactl(&["get-sink-volume", SINK]).unwrap();
+    assert!(volume.contains("60%"), "unexpected volume: {}", volume);
+    let knob = controller.state().knob(Knob::Knob1).clone();
+    assert_eq!(knob.led_value, KnobLedValue::from_percent(0.6));
+
+    let press = Event::KnobPressed {
+        knob: Knob::Knob1,
+        is_down: true,
+    };
+    mixer.handle(&mut controller, &press).await.unwrap();
+
+    let mute = pactl(&["get-sink-mute", SINK]).unwrap();
+    assert!(mute.ends_with("yes"), "unexpected mute state: {}", mute);
+    assert_eq!(
+        *controller.state().button(Button::Button9),
+        ButtonLedState::On
+    );
+}
@@ -0,0 +1,41 @@
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use zbus::connection::Builder;
+use zbus::Connection;
+
+/// A private session bus, which stops when dropped.
+pub struct SessionBus {
+    address: String,
+    _daemon: Child,
+}
+
+impl SessionBus {
+    /// Returns `None` if `dbus-daemon` isn't installed.
+    pub async fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--print-address", "--nofork"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .ok()?;
+
+        let stdout = daemon.stdout.take()?;
+        let address = BufReader::new(stdout).lines().next_line().await.ok()??;
+
+        Some(Self {
+            address,
+            _daemon: daemon,
+        })
+    }
+
+    /// A connection builder for the bus, e.g. to serve a stub service.
+    pub fn builder(&self) -> Builder<'static> {
+        Builder::address(self.address.as_str()).unwrap()
+    }
+
+    pub async fn connect(&self) -> Connection {
+        self.builder().build().await.unwrap()
+    }
+}
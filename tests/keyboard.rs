@@ -0,0 +1,34 @@
+use xtouchmini::backend::{self, Action, Recorder};
+use xtouchmini::keyboard::{self, Flag, Key, KeyCode, TextEntry};
+
+fn tap(key: Key, flags: &[Flag]) -> Vec<Action> {
+    vec![
+        Action::Key {
+            key,
+            down: true,
+            flags: flags.to_vec(),
+        },
+        Action::Key {
+            key,
+            down: false,
+            flags: flags.to_vec(),
+        },
+    ]
+}
+
+// The backend and text entry strategy are global, so they're only set in this test
+#[tokio::test]
+async fn types_unicode_with_hex_sequence() {
+    let recorder = Recorder::new();
+    backend::set(recorder.clone());
+    keyboard::set_text_entry(TextEntry::UnicodeHex);
+
+    keyboard::type_text("a👏").await.unwrap();
+
+    let mut expected = tap(Key::Char('a'), &[]);
+    expected.extend(tap(Key::Char('u'), &[Flag::Control, Flag::Shift]));
+    expected.push(Action::Text("1f44f".to_owned()));
+    expected.extend(tap(Key::Code(KeyCode::Space), &[]));
+
+    assert_eq!(recorder.actions(), expected);
+}
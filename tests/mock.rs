@@ -0,0 +1,295 @@
+#![cfg(feature = "mock")]
+
+use anyhow::Result;
+use serde_json::json;
+use std::time::Duration;
+use xtouchmini::router::{HasController, Route, Router};
+use xtouchmini::status::Source;
+use xtouchmini::vtubestudio::mock::Server;
+use xtouchmini::vtubestudio::plugin::{Hotkey, ParamDefinition};
+use xtouchmini::vtubestudio::{self, Message, Param, ParamId};
+use xtouchmini::{Controller, Event, Knob};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn hotkey(name: &str, id: &str) -> Hotkey {
+    Hotkey {
+        name: name.to_owned(),
+        r#type: "ToggleExpression".to_owned(),
+        file: format!("{}.exp3.json", name),
+        id: id.to_owned(),
+    }
+}
+
+struct Context {
+    controller: Controller,
+    vtube: vtubestudio::Client,
+}
+
+impl HasController for Context {
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+
+    fn controller_mut(&mut self) -> &mut Controller {
+        &mut self.controller
+    }
+}
+
+async fn puff_cheeks(context: &mut Context, event: &Event) -> Result<()> {
+    if let Event::KnobTurned { delta, .. } = *event {
+        let value = context.vtube.param(Param::CheekPuff) + delta as f64 * 0.1;
+        context.vtube.set_param(Param::CheekPuff, value).await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn knob_turns_set_params() {
+    let server = Server::start().await.unwrap();
+    let (controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+    tokio::spawn(worker);
+
+    let mut context = Context {
+        controller,
+        vtube: server.client(),
+    };
+    let router = Router::new().route(Route::knob(Knob::Knob1), puff_cheeks);
+
+    for delta in &[3, -1] {
+        let event = Event::KnobTurned {
+            knob: Knob::Knob1,
+            delta: *delta,
+        };
+        router.dispatch(&mut context, &event).await.unwrap();
+    }
+    // Not routed
+    let event = Event::KnobTurned {
+        knob: Knob::Knob2,
+        delta: 5,
+    };
+    router.dispatch(&mut context, &event).await.unwrap();
+
+    server.wait_for_messages(2, TIMEOUT).await.unwrap();
+    assert_eq!(server.recorded().messages.len(), 2);
+    let value = server.param(Param::CheekPuff).unwrap();
+    assert!((value - 0.2).abs() < 1e-9, "unexpected value {}", value);
+}
+
+#[tokio::test]
+async fn records_params_and_hotkeys() {
+    let server = Server::start().await.unwrap();
+    let mut client = server.client();
+
+    client.set_param(Param::MouthX, 0.5).await.unwrap();
+    client.set_param(Param::FaceAngry, 0.25).await.unwrap();
+    client.toggle_hotkey(2).await.unwrap();
+    server.wait_for_messages(3, TIMEOUT).await.unwrap();
+
+    let recorded = server.recorded();
+    assert_eq!(recorded.messages.len(), 3);
+    assert_eq!(server.param(Param::MouthX), Some(0.5));
+    assert_eq!(server.param(Param::FaceAngry), Some(0.25));
+    assert_eq!(server.hotkeys(), vec![2]);
+}
+
+#[tokio::test]
+async fn ignores_custom_params_for_tracking() {
+    use futures::SinkExt;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    let server = Server::start().await.unwrap();
+    let stream = TcpStream::connect(server.addr()).await.unwrap();
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+
+    let custom = ParamId::Custom("XTouchCheek".to_owned());
+    let msg = Message::new_with_param(custom.clone(), 0.25);
+    let json = serde_json::to_vec(&msg).unwrap();
+    framed.send(json.into()).await.unwrap();
+
+    server.wait_for_messages(1, TIMEOUT).await.unwrap();
+    assert_eq!(server.param(custom), None);
+}
+
+#[tokio::test]
+async fn triggers_hotkeys_by_name() {
+    let server = Server::start().await.unwrap();
+    server.set_model(
+        "model-1",
+        vec![hotkey("Dance", "id-dance"), hotkey("Dab", "id-dab")],
+    );
+
+    let mut plugin = server.plugin_client();
+    plugin.connect().await.unwrap();
+    assert_eq!(plugin.auth_token(), Some("mock-token"));
+
+    let hotkeys = plugin.refresh_hotkeys().await.unwrap();
+    assert_eq!(hotkeys.len(), 2);
+    assert_eq!(plugin.model_id(), Some("model-1"));
+
+    plugin.trigger_hotkey("Dab").await.unwrap();
+    plugin.trigger_hotkey("id-dance").await.unwrap();
+    assert!(plugin.trigger_hotkey("Missing").await.is_err());
+
+    // An API error leaves the connection usable
+    assert!(plugin.is_connected());
+
+    let recorded = server.recorded();
+    assert_eq!(recorded.triggered_hotkeys, vec!["id-dab", "id-dance"]);
+    assert_eq!(
+        recorded.requests,
+        vec![
+            "AuthenticationTokenRequest",
+            "AuthenticationRequest",
+            "EventSubscriptionRequest",
+            "HotkeysInCurrentModelRequest",
+            "HotkeyTriggerRequest",
+            "HotkeyTriggerRequest",
+            // Not in the cached list, so it's fetched again before giving up
+            "HotkeysInCurrentModelRequest",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn refreshes_hotkeys_when_the_model_changes() {
+    let server = Server::start().await.unwrap();
+    server.set_model("model-1", vec![hotkey("Dance", "id-dance-1")]);
+
+    let mut plugin = server.plugin_client().token("mock-token");
+    plugin.trigger_hotkey("Dance").await.unwrap();
+    plugin.trigger_hotkey("Dance").await.unwrap();
+
+    server.set_model("model-2", vec![hotkey("Dance", "id-dance-2")]);
+    plugin.trigger_hotkey("Dance").await.unwrap();
+    assert_eq!(plugin.model_id(), Some("model-2"));
+
+    let recorded = server.recorded();
+    assert_eq!(
+        recorded.triggered_hotkeys,
+        vec!["id-dance-1", "id-dance-1", "id-dance-2"]
+    );
+    // The list is only fetched once per model
+    assert_eq!(
+        recorded
+            .requests
+            .iter()
+            .filter(|request| *request == "HotkeysInCurrentModelRequest")
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn creates_and_deletes_params() {
+    let server = Server::start().await.unwrap();
+    let mut plugin = server.plugin_client().token("mock-token");
+
+    let definition = ParamDefinition::new("XTouchCheek")
+        .explanation("Cheek puff from knob 1")
+        .range(0.0, 1.0)
+        .default_value(0.5);
+    plugin.create_param(&definition).await.unwrap();
+
+    let recorded = server.recorded();
+    assert_eq!(recorded.custom_params.get("XTouchCheek"), Some(&definition));
+    // The token was reused rather than asking for a new one
+    assert_eq!(
+        recorded.requests,
+        vec![
+            "AuthenticationRequest",
+            "EventSubscriptionRequest",
+            "ParameterCreationRequest"
+        ]
+    );
+
+    plugin
+        .inject_params(vec![("XTouchCheek", 0.75), ("MouthX", 0.5)])
+        .await
+        .unwrap();
+    assert_eq!(
+        server.param(ParamId::Custom("XTouchCheek".to_owned())),
+        Some(0.75)
+    );
+    assert_eq!(server.param(Param::MouthX), Some(0.5));
+    assert!(plugin.inject_params(vec![("Missing", 1.0)]).await.is_err());
+
+    plugin.delete_param("XTouchCheek").await.unwrap();
+    assert!(server.recorded().custom_params.is_empty());
+    assert!(plugin.delete_param("XTouchCheek").await.is_err());
+}
+
+#[tokio::test]
+async fn scripts_responses() {
+    let server = Server::start().await.unwrap();
+    server.set_model("model-1", vec![hotkey("Dance", "id-dance")]);
+    server.respond_with(|message_type, data| match message_type {
+        "HotkeyTriggerRequest" if data["hotkeyID"] == "id-dance" => {
+            Some(Err("hotkey on cooldown".to_owned()))
+        }
+        "ParameterCreationRequest" => Some(Ok(json!({ "parameterName": "Renamed" }))),
+        _ => None,
+    });
+
+    let mut plugin = server.plugin_client().token("mock-token");
+    let error = plugin.trigger_hotkey("Dance").await.unwrap_err();
+    assert!(format!("{:#}", error).contains("hotkey on cooldown"));
+    assert_eq!(error.downcast_ref::<Source>(), Some(&Source::VTubeStudio));
+
+    // Answered by the script, so the mock didn't create it
+    plugin
+        .create_param(&ParamDefinition::new("XTouchCheek"))
+        .await
+        .unwrap();
+    assert!(server.recorded().custom_params.is_empty());
+}
+
+#[cfg(feature = "scripting")]
+#[tokio::test]
+async fn scripts_set_builtin_and_custom_params() {
+    use xtouchmini::script::ScriptEngine;
+
+    let server = Server::start().await.unwrap();
+    let mut vtube = server.client();
+    let mut plugin = server.plugin_client().token("mock-token");
+    plugin
+        .create_param(&ParamDefinition::new("XTouchCheek"))
+        .await
+        .unwrap();
+
+    let path = std::env::temp_dir().join(format!("xtouchmini-params-{}.rhai", std::process::id()));
+    let source = r#"
+        fn on_event(event) {
+            vtube_set_param("MouthX", 0.5);
+            vtube_set_param("XTouchCheek", vtube_param("XTouchCheek") + 0.25);
+        }
+    "#;
+    std::fs::write(&path, source).unwrap();
+    let mut script = ScriptEngine::new(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let (mut controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+    tokio::spawn(worker);
+    let event = Event::KnobTurned {
+        knob: Knob::Knob1,
+        delta: 1,
+    };
+
+    for _ in 0..2 {
+        script
+            .handle(&event, &mut controller, Some(&mut vtube), Some(&mut plugin))
+            .await
+            .unwrap();
+    }
+
+    server.wait_for_messages(2, TIMEOUT).await.unwrap();
+    assert_eq!(server.param(Param::MouthX), Some(0.5));
+    assert_eq!(
+        server.param(ParamId::Custom("XTouchCheek".to_owned())),
+        Some(0.5)
+    );
+    // Only built-in params go through the tracking connection
+    assert_eq!(server.recorded().messages.len(), 2);
+}
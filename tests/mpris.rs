@@ -0,0 +1,101 @@
+#![cfg(feature = "mpris")]
+
+mod common;
+
+use common::SessionBus;
+use std::sync::{Arc, Mutex};
+use xtouchmini::mpris::{Action, Mpris, Target};
+use xtouchmini::{Button, ButtonLedState, Controller, Event};
+
+/// A player that only has a playback status, and records the methods called on it.
+#[derive(Clone, Default)]
+struct StubPlayer {
+    status: Arc<Mutex<String>>,
+    calls: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl StubPlayer {
+    fn set_status(&self, status: &str) {
+        *self.status.lock().unwrap() = status.to_owned();
+    }
+
+    fn calls(&self) -> Vec<&'static str> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl StubPlayer {
+    fn play_pause(&self) {
+        self.calls.lock().unwrap().push("PlayPause");
+    }
+
+    fn next(&self) {
+        self.calls.lock().unwrap().push("Next");
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[tokio::test]
+async fn follows_playback_status_and_calls_actions() {
+    let bus = match SessionBus::start().await {
+        Some(bus) => bus,
+        None => {
+            eprintln!("Skipping, as dbus-daemon isn't installed");
+            return;
+        }
+    };
+
+    let player = StubPlayer::default();
+    player.set_status("Paused");
+    let _player_connection = bus
+        .builder()
+        .name("org.mpris.MediaPlayer2.stub")
+        .unwrap()
+        .serve_at("/org/mpris/MediaPlayer2", player.clone())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let (mut controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+    tokio::spawn(worker);
+
+    let mpris = Mpris::with_connection(bus.connect().await)
+        .target(Target::player("stub"))
+        .button(Button::Button1, Action::PlayPause)
+        .button(Button::Button2, Action::Next);
+
+    mpris.sync(&mut controller).await.unwrap();
+    assert_eq!(
+        *controller.state().button(Button::Button1),
+        ButtonLedState::Blink
+    );
+    assert_eq!(
+        *controller.state().button(Button::Button2),
+        ButtonLedState::Off
+    );
+
+    player.set_status("Playing");
+    mpris.sync(&mut controller).await.unwrap();
+    assert_eq!(
+        *controller.state().button(Button::Button1),
+        ButtonLedState::On
+    );
+
+    let press = Event::ButtonPressed {
+        button: Button::Button2,
+        is_down: true,
+    };
+    assert!(mpris.handles(&press));
+    assert!(!mpris.handles(&Event::ButtonPressed {
+        button: Button::Button3,
+        is_down: true,
+    }));
+    mpris.handle(&mut controller, &press).await.unwrap();
+    assert_eq!(player.calls(), vec!["Next"]);
+}
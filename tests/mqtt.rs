@@ -0,0 +1,196 @@
+#![cfg(feature = "mqtt")]
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use rumqttc::{
+    ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use xtouchmini::mqtt::Config;
+use xtouchmini::{Button, ButtonLedState, Command, ControllerState, Event};
+
+/// A broker that accepts one client, sends it `incoming` once it subscribes, and passes on
+/// everything it publishes. The client can connect before it's served.
+struct Broker {
+    listener: TcpListener,
+    incoming: Vec<Publish>,
+}
+
+impl Broker {
+    async fn bind(incoming: Vec<Publish>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self { listener, incoming }
+    }
+
+    fn port(&self) -> u16 {
+        self.listener.local_addr().unwrap().port()
+    }
+
+    fn serve(self) -> mpsc::UnboundedReceiver<Publish> {
+        let Self { listener, incoming } = self;
+        let (published_tx, published_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+
+            loop {
+                let packet = match rumqttc::read(&mut buf, 1 << 20) {
+                    Ok(packet) => packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        if stream.read_buf(&mut buf).await.unwrap() == 0 {
+                            return;
+                        }
+                        continue;
+                    }
+                    Err(error) => panic!("invalid MQTT packet: {:?}", error),
+                };
+
+                let mut out = BytesMut::new();
+                match packet {
+                    Packet::Connect(_) => {
+                        ConnAck::new(ConnectReturnCode::Success, false)
+                            .write(&mut out)
+                            .unwrap();
+                    }
+                    Packet::Subscribe(subscribe) => {
+                        let codes = subscribe
+                            .filters
+                            .iter()
+                            .map(|filter| SubscribeReasonCode::Success(filter.qos))
+                            .collect();
+                        SubAck::new(subscribe.pkid, codes).write(&mut out).unwrap();
+                        for publish in &incoming {
+                            publish.write(&mut out).unwrap();
+                        }
+                    }
+                    Packet::Publish(publish) => {
+                        if publish.qos == QoS::AtLeastOnce {
+                            PubAck::new(publish.pkid).write(&mut out).unwrap();
+                        }
+                        let _ = published_tx.send(publish);
+                    }
+                    Packet::PingReq => {
+                        PingResp.write(&mut out).unwrap();
+                    }
+                    _ => {}
+                }
+                stream.write_all(&out).await.unwrap();
+            }
+        });
+
+        published_rx
+    }
+}
+
+/// Waits for the next message on `topic`, skipping others.
+async fn next_on(published: &mut mpsc::UnboundedReceiver<Publish>, topic: &str) -> Publish {
+    let wait = async {
+        while let Some(publish) = published.recv().await {
+            if publish.topic == topic {
+                return publish;
+            }
+        }
+        panic!("the broker stopped before {} was published", topic);
+    };
+
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .unwrap_or_else(|_| panic!("{} wasn't published", topic))
+}
+
+/// Waits for the retained state that's republished once the client is online.
+async fn next_after_online(
+    published: &mut mpsc::UnboundedReceiver<Publish>,
+    topic: &str,
+) -> Publish {
+    loop {
+        let status = next_on(published, "xtouchmini/test/status").await;
+        if &status.payload[..] == b"online" {
+            return next_on(published, topic).await;
+        }
+    }
+}
+
+fn press(button: Button, is_down: bool) -> Event {
+    Event::ButtonPressed { button, is_down }
+}
+
+#[tokio::test]
+async fn publishes_retained_state_and_discovery() {
+    let broker = Broker::bind(vec![]).await;
+    let (mqtt, _commands, connection) = Config::new("127.0.0.1", broker.port(), "test").connect();
+    let mut published = broker.serve();
+
+    mqtt.publish(&press(Button::Button1, true)).await.unwrap();
+    mqtt.sync(&ControllerState::default()).await.unwrap();
+    tokio::spawn(connection);
+
+    let discovery = next_on(
+        &mut published,
+        "homeassistant/switch/xtouchmini_test/button_2_led/config",
+    )
+    .await;
+    assert!(discovery.retain);
+    let config: Value = serde_json::from_slice(&discovery.payload).unwrap();
+    assert_eq!(config["command_topic"], "xtouchmini/test/button/2/led/set");
+    assert_eq!(config["state_topic"], "xtouchmini/test/button/2/led");
+    assert_eq!(config["availability_topic"], "xtouchmini/test/status");
+    assert_eq!(config["device"]["identifiers"][0], "xtouchmini_test");
+
+    let button = next_after_online(&mut published, "xtouchmini/test/button/1").await;
+    assert!(button.retain);
+    assert_eq!(&button.payload[..], b"ON");
+}
+
+#[tokio::test]
+async fn receives_led_commands() {
+    let led = Publish::new("xtouchmini/test/button/2/led/set", QoS::AtMostOnce, "blink");
+    let broker = Broker::bind(vec![led]).await;
+    let (_mqtt, mut commands, connection) = Config::new("127.0.0.1", broker.port(), "test")
+        .discovery_prefix(None::<String>)
+        .connect();
+    let _published = broker.serve();
+    tokio::spawn(connection);
+
+    let command = tokio::time::timeout(Duration::from_secs(5), commands.next())
+        .await
+        .expect("no LED command was received");
+    assert_eq!(
+        command,
+        Some(Command::SetButtonLedState {
+            button: Button::Button2,
+            state: ButtonLedState::Blink,
+        })
+    );
+}
+
+#[tokio::test]
+async fn keeps_latest_state_while_disconnected() {
+    let broker = Broker::bind(vec![]).await;
+    let (mqtt, _commands, connection) = Config::new("127.0.0.1", broker.port(), "test")
+        .discovery_prefix(None::<String>)
+        .connect();
+
+    // Far more than the request queue holds, with nothing draining it
+    let presses = async {
+        for i in 0..=1000 {
+            mqtt.publish(&press(Button::Button1, i % 2 == 0))
+                .await
+                .unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(1), presses)
+        .await
+        .expect("publishing blocked without a broker");
+
+    let mut published = broker.serve();
+    tokio::spawn(connection);
+
+    let button = next_after_online(&mut published, "xtouchmini/test/button/1").await;
+    assert_eq!(&button.payload[..], b"ON");
+}
@@ -0,0 +1,162 @@
+#![cfg(feature = "notify")]
+
+mod common;
+
+use anyhow::{bail, Result};
+use common::SessionBus;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use xtouchmini::notify::{Config, Notifier};
+use xtouchmini::router::{HasController, Route};
+use xtouchmini::{Button, Controller, Event, Router};
+use zbus::zvariant::OwnedValue;
+
+const THROTTLE: Duration = Duration::from_millis(200);
+
+/// A notification service that records `(replaces_id, summary)` for each notification.
+#[derive(Clone, Default)]
+struct StubNotifications {
+    shown: Arc<Mutex<Vec<(u32, String)>>>,
+}
+
+impl StubNotifications {
+    fn shown(&self) -> Vec<(u32, String)> {
+        self.shown.lock().unwrap().clone()
+    }
+}
+
+#[zbus::interface(name = "org.freedesktop.Notifications")]
+impl StubNotifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        _app_name: String,
+        replaces_id: u32,
+        _app_icon: String,
+        summary: String,
+        _body: String,
+        _actions: Vec<String>,
+        _hints: HashMap<String, OwnedValue>,
+        _expire_timeout: i32,
+    ) -> u32 {
+        self.shown.lock().unwrap().push((replaces_id, summary));
+        42
+    }
+}
+
+/// Starts a private bus with the stub service, and a notifier connected to it.
+async fn notifier() -> Option<(SessionBus, StubNotifications, zbus::Connection, Notifier)> {
+    let bus = SessionBus::start().await?;
+
+    let service = StubNotifications::default();
+    let service_connection = bus
+        .builder()
+        .name("org.freedesktop.Notifications")
+        .unwrap()
+        .serve_at("/org/freedesktop/Notifications", service.clone())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let (notifier, sender) = Config::new()
+        .throttle(THROTTLE)
+        .with_connection(bus.connect().await);
+    tokio::spawn(sender);
+
+    Some((bus, service, service_connection, notifier))
+}
+
+struct Context {
+    controller: Controller,
+}
+
+impl HasController for Context {
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+
+    fn controller_mut(&mut self) -> &mut Controller {
+        &mut self.controller
+    }
+}
+
+fn press(button: Button) -> Event {
+    Event::ButtonPressed {
+        button,
+        is_down: true,
+    }
+}
+
+async fn fails(_context: &mut Context, _event: &Event) -> Result<()> {
+    bail!("binding failed")
+}
+
+async fn works(_context: &mut Context, _event: &Event) -> Result<()> {
+    Ok(())
+}
+
+#[tokio::test]
+async fn coalesces_to_the_latest_notification() {
+    let (_bus, service, _connection, notifier) = match notifier().await {
+        Some(notifier) => notifier,
+        None => {
+            eprintln!("Skipping, as dbus-daemon isn't installed");
+            return;
+        }
+    };
+
+    notifier.show("one");
+    tokio::time::sleep(THROTTLE / 2).await;
+
+    // All within the throttle after the first one
+    notifier.show("two");
+    notifier.show("three");
+    notifier.show("four");
+    tokio::time::sleep(THROTTLE * 2).await;
+
+    assert_eq!(
+        service.shown(),
+        vec![(0, "one".to_owned()), (42, "four".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn only_announces_bindings_that_worked() {
+    let (_bus, service, _connection, notifier) = match notifier().await {
+        Some(notifier) => notifier,
+        None => {
+            eprintln!("Skipping, as dbus-daemon isn't installed");
+            return;
+        }
+    };
+
+    let (controller, worker) = Controller::with_output(|_| Ok(())).unwrap();
+    tokio::spawn(worker);
+    let mut context = Context { controller };
+
+    let router = Router::new()
+        .middleware(
+            notifier
+                .binding(Route::button(Button::Button1), "Broken")
+                .binding(Route::button(Button::Button2), "Dance"),
+        )
+        .route(Route::button(Button::Button1), fails)
+        .route(Route::button(Button::Button2), works);
+
+    assert!(router
+        .dispatch(&mut context, &press(Button::Button1))
+        .await
+        .is_err());
+    router
+        .dispatch(&mut context, &press(Button::Button2))
+        .await
+        .unwrap();
+    tokio::time::sleep(THROTTLE).await;
+
+    assert_eq!(
+        service.shown(),
+        vec![(0, "Default layer: Dance".to_owned())]
+    );
+}
@@ -0,0 +1,169 @@
+#![cfg(feature = "obs")]
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use xtouchmini::obs::{Client, Event};
+use xtouchmini::status::Source;
+
+const SALT: &str = "salt";
+const CHALLENGE: &str = "challenge";
+
+fn response(request: &Value, result: bool, data: Value) -> String {
+    json!({
+        "op": 7,
+        "d": {
+            "requestType": request["requestType"],
+            "requestId": request["requestId"],
+            "requestStatus": { "result": result, "code": if result { 100 } else { 600 } },
+            "responseData": data,
+        }
+    })
+    .to_string()
+}
+
+/// Accepts one connection, identifies it, and passes each request to `handle`, which returns
+/// the frames to send back. Returning `None` closes the connection.
+async fn mock_obs<F>(password: Option<&'static str>, mut handle: F) -> String
+where
+    F: FnMut(&Value) -> Option<Vec<String>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+        let mut hello = json!({ "obsWebSocketVersion": "5.0.0", "rpcVersion": 1 });
+        if password.is_some() {
+            hello["authentication"] = json!({ "challenge": CHALLENGE, "salt": SALT });
+        }
+        let hello = json!({ "op": 0, "d": hello }).to_string();
+        ws.send(Message::Text(hello)).await.unwrap();
+
+        let identify: Value = match ws.next().await {
+            Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected Identify, got {:?}", other),
+        };
+        assert_eq!(identify["op"], 1);
+        if let Some(password) = password {
+            assert_eq!(identify["d"]["authentication"], auth_string(password));
+        }
+        let identified = json!({ "op": 2, "d": { "negotiatedRpcVersion": 1 } }).to_string();
+        ws.send(Message::Text(identified)).await.unwrap();
+
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let frame: Value = serde_json::from_str(&text).unwrap();
+            match handle(&frame["d"]) {
+                Some(replies) => {
+                    for reply in replies {
+                        ws.send(Message::Text(reply)).await.unwrap();
+                    }
+                }
+                None => break,
+            }
+        }
+    });
+
+    url
+}
+
+fn auth_string(password: &str) -> String {
+    let secret = base64::encode(Sha256::digest(format!("{}{}", password, SALT).as_bytes()));
+    base64::encode(Sha256::digest(
+        format!("{}{}", secret, CHALLENGE).as_bytes(),
+    ))
+}
+
+#[tokio::test]
+async fn authenticates_and_sends_requests() {
+    let url = mock_obs(Some("hunter2"), |request| {
+        Some(vec![match request["requestType"].as_str().unwrap() {
+            "GetCurrentProgramScene" => {
+                response(request, true, json!({ "currentProgramSceneName": "Live" }))
+            }
+            _ => response(request, false, Value::Null),
+        }])
+    })
+    .await;
+
+    let client = Client::connect(&url, Some("hunter2")).await.unwrap();
+    assert_eq!(client.current_program_scene().await.unwrap(), "Live");
+
+    let error = client.set_input_muted("Mic", true).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("status 600"));
+}
+
+#[tokio::test]
+async fn skips_malformed_frames() {
+    let url = mock_obs(None, |request| {
+        let event = json!({
+            "op": 5,
+            "d": {
+                "eventType": "StreamStateChanged",
+                "eventData": { "outputActive": true },
+            }
+        });
+
+        Some(vec![
+            "not json".to_owned(),
+            event.to_string(),
+            response(request, true, json!({ "outputActive": true })),
+        ])
+    })
+    .await;
+
+    let client = Client::connect(&url, None).await.unwrap();
+    let mut events = client.subscribe();
+
+    assert!(client.is_streaming().await.unwrap());
+    assert_eq!(
+        events.recv().await.unwrap(),
+        Event::StreamStateChanged { active: true }
+    );
+}
+
+#[tokio::test]
+async fn fails_fast_once_disconnected() {
+    let url = mock_obs(None, |_| None).await;
+    let client = Client::connect(&url, None).await.unwrap();
+
+    let requests = async {
+        assert!(client.is_recording().await.is_err());
+        assert!(client.is_recording().await.is_err());
+    };
+
+    tokio::time::timeout(Duration::from_secs(1), requests)
+        .await
+        .expect("requests hung after the connection closed");
+}
+
+#[tokio::test]
+async fn times_out_unanswered_requests() {
+    let mut answered = false;
+    let url = mock_obs(None, move |request| {
+        // Ignore the first request, then answer the next one
+        let replies = if answered {
+            vec![response(request, true, json!({ "outputActive": false }))]
+        } else {
+            vec![]
+        };
+        answered = true;
+        Some(replies)
+    })
+    .await;
+
+    let client = Client::connect(&url, None)
+        .await
+        .unwrap()
+        .request_timeout(Duration::from_millis(100));
+
+    let error = client.is_recording().await.unwrap_err();
+    assert!(format!("{:#}", error).contains("timed out"));
+    assert_eq!(error.downcast_ref::<Source>(), Some(&Source::Obs));
+    assert!(!client.is_recording().await.unwrap());
+}
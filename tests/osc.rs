@@ -0,0 +1,91 @@
+#![cfg(feature = "osc")]
+
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use xtouchmini::osc::Osc;
+use xtouchmini::{Button, ButtonLedState, Command, Event};
+
+async fn loopback() -> (Osc, UdpSocket) {
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let osc = Osc::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap()
+        .target(peer.local_addr().unwrap());
+
+    (osc, peer)
+}
+
+fn message(addr: &str, arg: OscType) -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: addr.to_owned(),
+        args: vec![arg],
+    })
+}
+
+#[tokio::test]
+async fn publishes_events() {
+    let (osc, peer) = loopback().await;
+
+    let event = Event::ButtonPressed {
+        button: Button::Button5,
+        is_down: true,
+    };
+    osc.publish(&event).await.unwrap();
+
+    let mut buf = vec![0; 1024];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+        .await
+        .expect("no OSC message was sent")
+        .unwrap();
+    assert_eq!(from, osc.local_addr().unwrap());
+
+    match rosc::decoder::decode(&buf[..len]).unwrap() {
+        OscPacket::Message(message) => {
+            assert_eq!(message.addr, "/xtouch/button/5");
+            assert_eq!(message.args, vec![OscType::Int(1)]);
+        }
+        packet => panic!("expected a message, got {:?}", packet),
+    }
+}
+
+#[tokio::test]
+async fn skips_invalid_messages_in_bundles() {
+    let (osc, peer) = loopback().await;
+
+    let bundle = OscPacket::Bundle(OscBundle {
+        timetag: OscTime {
+            seconds: 0,
+            fractional: 1,
+        },
+        content: vec![
+            message("/xtouch/button/99/led", OscType::Int(1)),
+            message("/xtouch/button/2/led", OscType::String("blink".into())),
+            message("/other/button/3/led", OscType::Int(1)),
+            message("/xtouch/layer/b/led", OscType::Int(1)),
+        ],
+    });
+    let bytes = rosc::encoder::encode(&bundle).unwrap();
+    peer.send_to(&bytes, osc.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let commands = tokio::time::timeout(Duration::from_secs(1), osc.recv())
+        .await
+        .expect("no OSC packet was received")
+        .unwrap();
+
+    assert_eq!(
+        commands,
+        vec![
+            Command::SetButtonLedState {
+                button: Button::Button2,
+                state: ButtonLedState::Blink,
+            },
+            Command::SetButtonLedState {
+                button: Button::LayerB,
+                state: ButtonLedState::On,
+            },
+        ]
+    );
+}
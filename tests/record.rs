@@ -0,0 +1,135 @@
+use futures::stream::{self, StreamExt};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use xtouchmini::record::{Recorder, Replay};
+use xtouchmini::{Event, EventStream, FaderValue, Knob};
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/recording.jsonl"
+);
+
+/// A writer that can still be read after the recorder that owns it is gone.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn replays_fixture() {
+    let events = Replay::open(FIXTURE)
+        .unwrap()
+        .instant()
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(events.len(), 4);
+    assert_eq!(
+        events[0].as_ref().unwrap(),
+        &Event::KnobTurned {
+            knob: Knob::Knob1,
+            delta: 3,
+        }
+    );
+    // Parsing fails the same way it did live
+    assert!(events[1].is_err());
+    assert_eq!(
+        events[2].as_ref().unwrap(),
+        &Event::FaderMoved {
+            value: FaderValue(64),
+        }
+    );
+    // Entries without a MIDI message are replayed from the event
+    assert_eq!(
+        events[3].as_ref().unwrap(),
+        &Event::KnobTurned {
+            knob: Knob::Knob8,
+            delta: -2,
+        }
+    );
+
+    let messages = Replay::open(FIXTURE)
+        .unwrap()
+        .instant()
+        .into_midi_stream()
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(
+        messages,
+        vec![
+            vec![176, 16, 3],
+            vec![240, 0, 247],
+            vec![232, 0, 64],
+            Event::KnobTurned {
+                knob: Knob::Knob8,
+                delta: -2,
+            }
+            .to_bytes()
+            .to_vec(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn records_messages_as_received() {
+    let fixture = Replay::open(FIXTURE).unwrap();
+    let received = fixture
+        .entries()
+        .iter()
+        .filter_map(|entry| entry.bytes.clone())
+        .collect::<Vec<_>>();
+
+    let buffer = SharedBuffer::default();
+    let input = EventStream::from_midi(stream::iter(received.clone()));
+    let events = Recorder::new(buffer.clone())
+        .wrap_input(input)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(events.len(), received.len());
+
+    let recording = Replay::from_reader(&buffer.0.lock().unwrap()[..]).unwrap();
+    let recorded = recording.entries();
+    assert_eq!(recorded.len(), received.len());
+
+    for (entry, expected) in recorded.iter().zip(fixture.entries()) {
+        assert_eq!(entry.bytes, expected.bytes);
+        assert_eq!(entry.event, expected.event);
+    }
+}
+
+#[tokio::test]
+async fn replays_out_of_range_messages_as_errors() {
+    let recording = concat!(
+        "{\"time_ms\":1520,\"bytes\":[176,1,70]}\n",
+        "{\"time_ms\":1530,\"bytes\":[176,0,1]}\n",
+        "{\"time_ms\":1540,\"bytes\":[144,32,127]}\n",
+    );
+
+    let events = Replay::from_reader(recording.as_bytes())
+        .unwrap()
+        .instant()
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(events.len(), 3);
+    assert!(events[0].is_err());
+    assert!(events[1].is_err());
+    assert_eq!(
+        events[2].as_ref().unwrap(),
+        &Event::KnobPressed {
+            knob: Knob::Knob1,
+            is_down: true,
+        }
+    );
+}